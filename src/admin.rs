@@ -0,0 +1,631 @@
+// jkcoxson
+
+use log::{info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+use warp::{filters::BoxedFilter, http::StatusCode, reply::Response, Filter, Rejection, Reply};
+
+use crate::{
+    backend::{Backend, DeserializedClient, PreviousIp},
+    error::ErrorCode,
+    integrity::IntegrityReport,
+    jobs::{Job, JobKind, JobState},
+    usage::{self, ExportFormat, ExportQuery, UsageEvent},
+    v2::{reply, ApiError, CensusResponse, ErrorResponse, JobsResponse, UnregisterResponse},
+    SHORTCUT_VERSION,
+};
+
+/// A registered device as seen by an administrator.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminClient {
+    pub ip: String,
+    pub udid: String,
+    /// Seconds since the epoch
+    pub last_seen: u64,
+    /// The IPs the device was registered from before, oldest first
+    pub ip_history: Vec<PreviousIp>,
+    /// Whether the device has been issued a token, older registrations have none
+    pub has_token: bool,
+    pub mounting: bool,
+    /// Why the last mount failed, if it did
+    pub mount_error: Option<String>,
+    pub pending_launch: Option<String>,
+    /// How many of the device's jobs are queued or running
+    pub active_jobs: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AdminClientsResponse {
+    pub clients: Vec<AdminClient>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AdminClientResponse {
+    pub client: AdminClient,
+    /// Every job kept for the device, newest first
+    pub jobs: Vec<Job>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UsageExportResponse {
+    /// Oldest first
+    pub events: Vec<UsageEvent>,
+}
+
+/// A device that has gone unseen for longer than the retention period.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpiredClient {
+    pub ip: String,
+    pub udid: String,
+    /// Seconds since the epoch
+    pub last_seen: u64,
+}
+
+impl From<DeserializedClient> for ExpiredClient {
+    fn from(client: DeserializedClient) -> Self {
+        ExpiredClient {
+            ip: client.ip,
+            udid: client.udid,
+            last_seen: client.last_seen,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpiryResponse {
+    /// Whether the devices were left registered
+    pub dry_run: bool,
+    /// 0 when devices are kept forever
+    pub retention_days: u64,
+    pub expired: Vec<ExpiredClient>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ClearedCodesResponse {
+    /// How many pair codes were dropped
+    pub cleared: usize,
+}
+
+/// Builds every route under `/admin`.
+/// Without a token in the config every route answers with `forbidden`.
+pub fn routes(backend: Arc<Mutex<Backend>>, token: Option<String>) -> BoxedFilter<(Response,)> {
+    let token = Arc::new(token);
+
+    let clients_backend = backend.clone();
+    let clients_route = warp::path!("admin" / "clients")
+        .and(warp::get())
+        .and(authorize(token.clone()))
+        .and_then(move |auth| list_clients(auth, clients_backend.clone()));
+
+    let client_backend = backend.clone();
+    let client_route = warp::path!("admin" / "clients" / String)
+        .and(warp::get())
+        .and(authorize(token.clone()))
+        .and_then(move |udid, auth| get_client(udid, auth, client_backend.clone()));
+
+    let unregister_backend = backend.clone();
+    let unregister_route = warp::path!("admin" / "clients" / String)
+        .and(warp::delete())
+        .and(authorize(token.clone()))
+        .and_then(move |udid, auth| unregister(udid, auth, unregister_backend.clone()));
+
+    let jobs_backend = backend.clone();
+    let jobs_route = warp::path!("admin" / "jobs")
+        .and(warp::get())
+        .and(authorize(token.clone()))
+        .and_then(move |auth| list_jobs(auth, jobs_backend.clone()));
+
+    let codes_backend = backend.clone();
+    let codes_route = warp::path!("admin" / "pair_codes")
+        .and(warp::delete())
+        .and(authorize(token.clone()))
+        .and_then(move |auth| clear_pair_codes(auth, codes_backend.clone()));
+
+    let counter_backend = backend.clone();
+    let counter_route = warp::path!("admin" / "counter" / "reset")
+        .and(warp::post())
+        .and(authorize(token.clone()))
+        .and_then(move |auth| reset_counter(auth, counter_backend.clone()));
+
+    let usage_backend = backend.clone();
+    let usage_route = warp::path!("admin" / "usage")
+        .and(warp::get())
+        .and(authorize(token.clone()))
+        .and(warp::query::<ExportQuery>())
+        .and_then(move |auth, query| export_usage(auth, query, usage_backend.clone()));
+
+    let preview_expiry_backend = backend.clone();
+    let preview_expiry_route = warp::path!("admin" / "expiry")
+        .and(warp::get())
+        .and(authorize(token.clone()))
+        .and_then(move |auth| preview_expiry(auth, preview_expiry_backend.clone()));
+
+    let expiry_backend = backend.clone();
+    let expiry_route = warp::path!("admin" / "expiry")
+        .and(warp::post())
+        .and(authorize(token.clone()))
+        .and_then(move |auth| run_expiry(auth, expiry_backend.clone()));
+
+    let integrity_backend = backend.clone();
+    let integrity_route = warp::path!("admin" / "integrity")
+        .and(warp::get())
+        .and(authorize(token.clone()))
+        .and_then(move |auth| check_integrity(auth, integrity_backend.clone()));
+
+    let repair_route = warp::path!("admin" / "integrity")
+        .and(warp::post())
+        .and(authorize(token))
+        .and_then(move |auth| repair_integrity(auth, backend.clone()));
+
+    clients_route
+        .or(client_route)
+        .unify()
+        .or(unregister_route)
+        .unify()
+        .or(jobs_route)
+        .unify()
+        .or(codes_route)
+        .unify()
+        .or(counter_route)
+        .unify()
+        .or(usage_route)
+        .unify()
+        .or(preview_expiry_route)
+        .unify()
+        .or(expiry_route)
+        .unify()
+        .or(integrity_route)
+        .unify()
+        .or(repair_route)
+        .unify()
+        .boxed()
+}
+
+/// Checks the bearer token a request was sent with against the configured one.
+fn authorize(
+    token: Arc<Option<String>>,
+) -> impl Filter<Extract = (Result<(), ApiError>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").map(move |header: Option<String>| {
+        let expected =
+            match token.as_ref() {
+                Some(expected) => expected,
+                None => return Err(ApiError::new(
+                    ErrorCode::Forbidden,
+                    "The admin API is disabled, set a token in the [admin] section of the config",
+                )),
+            };
+        let given = header
+            .as_deref()
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        // Compare digests so the time taken doesn't depend on how much of the token matched
+        if Sha256::digest(given.as_bytes()) == Sha256::digest(expected.as_bytes()) {
+            Ok(())
+        } else {
+            warn!("Rejected an admin request with a bad token");
+            Err(ApiError::new(
+                ErrorCode::Unauthorized,
+                "Invalid admin token",
+            ))
+        }
+    })
+}
+
+/// Describes a registered device without touching what the status route reports.
+fn describe(backend: &Backend, udid: &str) -> Option<AdminClient> {
+    let client = backend
+        .deserialized_clients
+        .iter()
+        .find(|c| c.udid == udid)?;
+    let jobs = match backend.shared.jobs.lock() {
+        Ok(jobs) => jobs,
+        Err(_) => {
+            warn!("Mutex poisoned!!");
+            return None;
+        }
+    };
+    let (mounting, mount_error) = match jobs.latest(udid, JobKind::Mount).map(|j| j.state) {
+        Some(JobState::Queued) | Some(JobState::Running) => (true, None),
+        Some(JobState::Failed { message, .. }) => (false, Some(message)),
+        _ => (false, None),
+    };
+    Some(AdminClient {
+        ip: client.ip.clone(),
+        udid: client.udid.clone(),
+        last_seen: client.last_seen,
+        ip_history: client.ip_history.clone(),
+        has_token: client.token_hash.is_some(),
+        mounting,
+        mount_error,
+        pending_launch: jobs.pending_launch(udid),
+        active_jobs: jobs.by_udid(udid).iter().filter(|j| j.is_active()).count(),
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/clients",
+    operation_id = "admin_list_clients",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Every registered device", body = AdminClientsResponse),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn list_clients(
+    auth: Result<(), ApiError>,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    if let Err(e) = auth {
+        return reply::<AdminClientsResponse>(Err(e));
+    }
+    let lock = backend.lock().await;
+    let clients = lock
+        .deserialized_clients
+        .iter()
+        .filter_map(|c| describe(&lock, &c.udid))
+        .collect();
+    reply(Ok((StatusCode::OK, AdminClientsResponse { clients })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/clients/{udid}",
+    operation_id = "admin_get_client",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("udid" = String, Path, description = "The device's UDID")),
+    responses(
+        (status = 200, description = "The device and its jobs", body = AdminClientResponse),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn get_client(
+    udid: String,
+    auth: Result<(), ApiError>,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    if let Err(e) = auth {
+        return reply::<AdminClientResponse>(Err(e));
+    }
+    let lock = backend.lock().await;
+    let client = match describe(&lock, &udid) {
+        Some(client) => client,
+        None => {
+            return reply::<AdminClientResponse>(Err(ApiError::new(
+                ErrorCode::NotRegistered,
+                "No device is registered with that UDID",
+            )))
+        }
+    };
+    let jobs = match lock.shared.jobs.lock() {
+        Ok(jobs) => jobs.by_udid(&udid),
+        Err(_) => vec![],
+    };
+    reply(Ok((StatusCode::OK, AdminClientResponse { client, jobs })))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/clients/{udid}",
+    operation_id = "admin_unregister",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("udid" = String, Path, description = "The device's UDID")),
+    responses(
+        (status = 200, description = "The device that was removed", body = UnregisterResponse),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn unregister(
+    udid: String,
+    auth: Result<(), ApiError>,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    if let Err(e) = auth {
+        return reply::<UnregisterResponse>(Err(e));
+    }
+    let mut lock = backend.lock().await;
+    if let Err(e) = lock.unregister_client(&udid) {
+        return reply::<UnregisterResponse>(Err(e.into()));
+    }
+    if let Ok(mut heart) = lock.shared.heart.lock() {
+        heart.kill(udid.as_str());
+    }
+    info!("An admin unregistered {}", udid);
+    reply(Ok((StatusCode::OK, UnregisterResponse { udid })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/jobs",
+    operation_id = "admin_list_jobs",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Every job being kept, newest first", body = JobsResponse),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn list_jobs(
+    auth: Result<(), ApiError>,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    if let Err(e) = auth {
+        return reply::<JobsResponse>(Err(e));
+    }
+    let jobs = backend.lock().await.shared.jobs.clone();
+    let jobs = match jobs.lock() {
+        Ok(jobs) => jobs.all(),
+        Err(_) => vec![],
+    };
+    reply(Ok((StatusCode::OK, JobsResponse { jobs })))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/pair_codes",
+    operation_id = "admin_clear_pair_codes",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (
+            status = 200,
+            description = "How many pair codes were dropped",
+            body = ClearedCodesResponse
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn clear_pair_codes(
+    auth: Result<(), ApiError>,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    if let Err(e) = auth {
+        return reply::<ClearedCodesResponse>(Err(e));
+    }
+    let cleared = backend.lock().await.clear_codes();
+    info!("An admin cleared {} pair codes", cleared);
+    reply(Ok((StatusCode::OK, ClearedCodesResponse { cleared })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/counter/reset",
+    operation_id = "admin_reset_counter",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (
+            status = 200,
+            description = "The counters as they were before the reset",
+            body = CensusResponse
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn reset_counter(
+    auth: Result<(), ApiError>,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    if let Err(e) = auth {
+        return reply::<CensusResponse>(Err(e));
+    }
+    let mut lock = backend.lock().await;
+    let counter = lock.reset_counter();
+    let clients = lock.deserialized_clients.len();
+    let usage = lock.shared.usage.clone();
+    drop(lock);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    info!("An admin reset the usage counters");
+    reply(Ok((
+        StatusCode::OK,
+        CensusResponse {
+            launched: counter.launched,
+            attached: counter.attached,
+            fetched: counter.fetched,
+            netmuxd: counter.netmuxd,
+            uptime: now - counter.uptime.as_secs(),
+            clients,
+            version: SHORTCUT_VERSION.to_string(),
+            usage: usage::summarize(&usage),
+        },
+    )))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/usage",
+    operation_id = "admin_export_usage",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(ExportQuery),
+    responses(
+        (
+            status = 200,
+            description = "The usage history, sent as a text/csv attachment when format is csv. \
+                           Events older than usage_retention_days are only kept as totals",
+            body = UsageExportResponse
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn export_usage(
+    auth: Result<(), ApiError>,
+    query: ExportQuery,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    if let Err(e) = auth {
+        return reply::<UsageExportResponse>(Err(e));
+    }
+    let history = backend.lock().await.shared.usage.clone();
+    let events = match history.lock() {
+        Ok(history) => history.between(query.since, query.until),
+        Err(_) => {
+            warn!("Mutex poisoned!!");
+            return reply::<UsageExportResponse>(Err(ApiError::new(
+                ErrorCode::Internal,
+                "Usage history is unavailable",
+            )));
+        }
+    };
+    match query.format {
+        ExportFormat::Json => reply(Ok((StatusCode::OK, UsageExportResponse { events }))),
+        ExportFormat::Csv => Ok(warp::reply::with_header(
+            warp::reply::with_header(usage::to_csv(&events), "content-type", "text/csv"),
+            "content-disposition",
+            "attachment; filename=\"usage.csv\"",
+        )
+        .into_response()),
+    }
+}
+
+/// Expires stale devices, or only reports which would be expired.
+async fn expire(dry_run: bool, backend: Arc<Mutex<Backend>>) -> Result<Response, Rejection> {
+    let mut lock = backend.lock().await;
+    let expired = match lock.expire_clients(dry_run) {
+        Ok(expired) => expired,
+        Err(e) => return reply::<ExpiryResponse>(Err(e.into())),
+    };
+    if !dry_run {
+        info!("An admin expired {} devices", expired.len());
+    }
+    reply(Ok((
+        StatusCode::OK,
+        ExpiryResponse {
+            dry_run,
+            retention_days: lock.retention_days,
+            expired: expired.into_iter().map(ExpiredClient::from).collect(),
+        },
+    )))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/expiry",
+    operation_id = "admin_preview_expiry",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (
+            status = 200,
+            description = "The devices the next maintenance run would expire, left registered",
+            body = ExpiryResponse
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn preview_expiry(
+    auth: Result<(), ApiError>,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    if let Err(e) = auth {
+        return reply::<ExpiryResponse>(Err(e));
+    }
+    expire(true, backend).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/expiry",
+    operation_id = "admin_run_expiry",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (
+            status = 200,
+            description = "The devices that were unregistered for going unseen",
+            body = ExpiryResponse
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn run_expiry(
+    auth: Result<(), ApiError>,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    if let Err(e) = auth {
+        return reply::<ExpiryResponse>(Err(e));
+    }
+    expire(false, backend).await
+}
+
+/// Reconciles the database with the pairing records, or only reports where they disagree.
+async fn integrity(repair: bool, backend: Arc<Mutex<Backend>>) -> Result<Response, Rejection> {
+    let mut lock = backend.lock().await;
+    let report = match lock.check_integrity(repair) {
+        Ok(report) => report,
+        Err(e) => return reply::<IntegrityReport>(Err(e.into())),
+    };
+    if repair {
+        info!(
+            "An admin repaired the pairing records, removing {} orphans and {} duplicates",
+            report.removed_records.len(),
+            report.duplicate_udids.len()
+        );
+    }
+    reply(Ok((StatusCode::OK, report)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/integrity",
+    operation_id = "admin_check_integrity",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (
+            status = 200,
+            description = "Where the database and pairing records disagree, nothing is changed",
+            body = IntegrityReport
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn check_integrity(
+    auth: Result<(), ApiError>,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    if let Err(e) = auth {
+        return reply::<IntegrityReport>(Err(e));
+    }
+    integrity(false, backend).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/integrity",
+    operation_id = "admin_repair_integrity",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (
+            status = 200,
+            description = "What was found, orphans and duplicates have been removed",
+            body = IntegrityReport
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn repair_integrity(
+    auth: Result<(), ApiError>,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    if let Err(e) = auth {
+        return reply::<IntegrityReport>(Err(e));
+    }
+    integrity(true, backend).await
+}
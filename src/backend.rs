@@ -1,40 +1,54 @@
 // jkcoxson
 
 use ip_in_subnet::iface_in_subnet;
-use log::warn;
+use log::{info, warn};
 use rand::Rng;
 use rusty_libimobiledevice::idevice::Device;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::net::IpAddr;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use utoipa::{IntoParams, ToSchema};
 
-use crate::client::Client;
+use crate::client::{Client, Shared};
 use crate::config::Config;
+use crate::error::Error;
 use crate::heartbeat::Heart;
+use crate::integrity::{self, IntegrityReport};
+use crate::jobs::Jobs;
+use crate::metrics::Metrics;
+use crate::pair_codes::PairCodes;
+use crate::pairing_store::PairingStore;
+use crate::progress::Progress;
+use crate::storage::{self, Storage};
+use crate::uploads::Uploads;
+use crate::usage::Usage;
+
+/// The header shortcuts send their device token in.
+pub const TOKEN_HEADER: &str = "x-jitstreamer-token";
+/// The header that picks a device when several are registered from one IP.
+pub const UDID_HEADER: &str = "x-jitstreamer-udid";
+
+const DAY: u64 = 24 * 60 * 60;
+/// How many previous IPs are kept for each device.
+const IP_HISTORY_LEN: usize = 10;
 
-#[derive(Serialize, Deserialize)]
 pub struct Backend {
     pub deserialized_clients: Vec<DeserializedClient>,
     pub allowed_subnet: String,
-    database_path: String,
-    plist_storage: String,
-    pub dmg_path: String,
+    storage: Box<dyn Storage>,
+    pub shared: Shared,
     pub netmuxd_address: Option<String>,
-
-    #[serde(skip)]
-    pub pair_potential: Vec<PairPotential>,
-
-    #[serde(skip)]
-    pub heart: Arc<Mutex<Heart>>,
-
-    #[serde(skip)]
+    /// Whether devices without a token can be identified by their IP
+    pub ip_fallback: bool,
+    pub pair_codes: PairCodes,
     pub counter: Counter,
-
-    #[serde(skip)]
-    pub mounts: Arc<Mutex<HashMap<String, String>>>,
+    pub uploads: Uploads,
+    /// Days a device can go unseen before it is unregistered, 0 keeps devices forever
+    pub retention_days: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -46,67 +60,73 @@ pub struct Counter {
     pub netmuxd: usize,
 }
 
-#[derive(Debug)]
-pub struct PairPotential {
-    pub ip: String,
-    pub code: u16,
+/// How a request says which device it is for.
+#[derive(Default, Debug)]
+pub struct DeviceId {
+    pub token: Option<String>,
+    /// Picks a device when several are registered from the same IP
+    pub udid: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UdidQuery {
+    /// The device to use when several are registered from the same IP,
+    /// can also be sent in the x-jitstreamer-udid header
+    pub udid: Option<String>,
+}
+
+/// A successful registration.
+pub struct Registration {
+    pub udid: String,
+    /// Shown to the device once, it identifies itself with this from now on
+    pub token: String,
+    /// The IP the device was registered from before, if it moved
+    pub moved_from: Option<String>,
 }
 
 impl Backend {
-    /// Loads the database JSON file into memory.
-    pub fn load(config: &Config) -> Backend {
-        let mut file = match std::fs::File::open(config.paths.database_path.clone()) {
-            Ok(file) => file,
-            Err(_) => {
-                println!("Failed to open database file, using an empty database");
-                return Backend {
-                    deserialized_clients: vec![],
-                    allowed_subnet: config.extra.allowed_subnet.clone(),
-                    database_path: config.paths.database_path.clone(),
-                    plist_storage: config.paths.plist_storage.clone(),
-                    dmg_path: config.paths.dmg_path.clone(),
-                    netmuxd_address: config.extra.netmuxd_address.clone(),
-                    pair_potential: vec![],
-                    heart: Arc::new(Mutex::new(Heart::new())),
-                    counter: Counter {
-                        launched: 0,
-                        fetched: 0,
-                        attached: 0,
-                        netmuxd: 0,
-                        uptime: SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
-                    },
-                    mounts: Arc::new(Mutex::new(HashMap::new())),
-                };
-            }
-        };
-        let mut contents = String::new();
-        std::io::Read::read_to_string(&mut file, &mut contents).unwrap();
-        let clients: Vec<DeserializedClient> = serde_json::from_str(&contents).unwrap();
-        Backend {
+    /// Loads the registered clients from the configured storage into memory.
+    pub fn load(config: &Config) -> Result<Backend, Error> {
+        let mut storage = storage::open(config)?;
+        let clients = storage.load()?;
+        let records = PairingStore::new(config)?;
+        let udids: Vec<String> = clients.iter().map(|c| c.udid.clone()).collect();
+        records.import_plaintext(&udids)?;
+        Ok(Backend {
             deserialized_clients: clients,
             allowed_subnet: config.extra.allowed_subnet.clone(),
-            database_path: config.paths.database_path.clone(),
-            plist_storage: config.paths.plist_storage.clone(),
-            dmg_path: config.paths.dmg_path.clone(),
-            netmuxd_address: config.extra.netmuxd_address.clone(),
-            pair_potential: vec![],
-            heart: Arc::new(Mutex::new(Heart::new())),
-            counter: Counter {
-                launched: 0,
-                fetched: 0,
-                attached: 0,
-                netmuxd: 0,
-                uptime: SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            storage,
+            shared: Shared {
+                records: Arc::new(records),
+                dmg_path: config.paths.dmg_path.clone(),
+                heart: Arc::new(Mutex::new(Heart::new())),
+                jobs: Arc::new(Mutex::new(Jobs::new())),
+                progress: Arc::new(Mutex::new(Progress::new())),
+                metrics: Arc::new(Metrics::new()),
+                usage: Arc::new(Mutex::new(Usage::load(
+                    &config.paths.usage_path,
+                    config.maintenance.usage_retention_days,
+                ))),
+                seen: Arc::new(Mutex::new(HashMap::new())),
             },
-            mounts: Arc::new(Mutex::new(HashMap::new())),
-        }
+            netmuxd_address: config.extra.netmuxd_address.clone(),
+            ip_fallback: config.auth.ip_fallback,
+            pair_codes: PairCodes::new(config),
+            counter: Counter::default(),
+            uploads: Uploads::new(config),
+            retention_days: config.maintenance.retention_days,
+        })
     }
 
-    /// Saves the database to disk.
-    fn save(&self) {
-        let contents = serde_json::to_string_pretty(&self.deserialized_clients).unwrap();
-        let mut file = std::fs::File::create(&self.database_path).unwrap();
-        std::io::Write::write_all(&mut file, contents.as_bytes()).unwrap();
+    /// Saves the clients to storage.
+    fn save(&mut self) -> Result<(), Error> {
+        self.apply_seen();
+        let res = self.storage.save(&self.deserialized_clients);
+        if let Err(e) = &res {
+            warn!("Unable to save the database: {}", e);
+        }
+        res
     }
 
     pub fn check_ip(&self, ip: IpAddr) -> bool {
@@ -123,97 +143,307 @@ impl Backend {
         }
     }
 
-    pub fn register_client(&mut self, ip: String, udid: String) -> Result<(), ()> {
-        // Check if the client is already registered.
-        if self.get_by_ip(&ip).is_some() {
-            return Err(());
+    /// Adds a client to the database and returns the token it should identify itself with.
+    /// A device that registers again is given a new token, and moved if its IP changed.
+    /// Only call this once lockdown has accepted the device's pairing record, re-registering
+    /// is how a device that lost its token, or never had one, gets a new one.
+    pub fn register_client(&mut self, ip: String, udid: String) -> Result<Registration, Error> {
+        let now = now();
+        let token = new_token();
+        // Other devices can share its IP, they are told apart by UDID.
+        let index = self
+            .deserialized_clients
+            .iter()
+            .position(|c| c.udid == udid);
+        let previous = match index {
+            Some(i) => {
+                let client = &mut self.deserialized_clients[i];
+                let previous = client.clone();
+                if client.ip != ip {
+                    client.move_to(ip, now);
+                }
+                client.last_seen = now;
+                client.token_hash = Some(hash_token(&token));
+                Some(previous)
+            }
+            None => {
+                // Add the client to the database.
+                self.deserialized_clients.push(DeserializedClient {
+                    ip,
+                    udid: udid.clone(),
+                    last_seen: now,
+                    token_hash: Some(hash_token(&token)),
+                    ip_history: vec![],
+                    seen_tracked: true,
+                });
+                None
+            }
+        };
+        if let Err(e) = self.save() {
+            // Put the device back how it was saved, so memory doesn't drift from storage
+            match (index, previous) {
+                (Some(i), Some(previous)) => self.deserialized_clients[i] = previous,
+                _ => {
+                    self.deserialized_clients.pop();
+                }
+            }
+            return Err(e);
         }
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
-        // Add the client to the database.
-        self.deserialized_clients.push(DeserializedClient {
-            ip,
+        let moved_from = match (previous, index) {
+            (Some(previous), Some(i)) => {
+                let client = &self.deserialized_clients[i];
+                if previous.ip == client.ip {
+                    info!("{} registered again, issued a new token", client.udid);
+                    None
+                } else {
+                    info!(
+                        "{} moved from {} to {}",
+                        client.udid, previous.ip, client.ip
+                    );
+                    Some(previous.ip)
+                }
+            }
+            _ => None,
+        };
+        Ok(Registration {
             udid,
-            last_seen: since_the_epoch.as_secs(),
-        });
-        self.save();
-        Ok(())
+            token,
+            moved_from,
+        })
     }
 
-    pub fn unregister_client(&mut self, ip: String) -> Result<(), ()> {
-        if let Some(client) = self.get_by_ip(&ip) {
+    pub fn unregister_client(&mut self, udid: &str) -> Result<(), Error> {
+        if self.get_by_udid(udid).is_some() {
             // Delete pairing file
-            let _ = std::fs::remove_file(format!("/var/lib/lockdown/{}.plist", client.udid));
+            if let Err(e) = self.shared.records.remove(udid) {
+                warn!("{}", e);
+            }
 
             // Remove from database
-            let mut i = 0;
-            while i < self.deserialized_clients.len() {
-                if self.deserialized_clients[i].ip == ip {
-                    self.deserialized_clients.remove(i);
+            self.deserialized_clients.retain(|c| c.udid != udid);
+            self.save()?;
+            Ok(())
+        } else {
+            Err(Error::NotRegistered)
+        }
+    }
+
+    /// Moves the waiting sightings onto the clients, returning whether any changed.
+    fn apply_seen(&mut self) -> bool {
+        let seen = match self.shared.seen.lock() {
+            Ok(mut seen) => std::mem::take(&mut *seen),
+            Err(_) => {
+                warn!("Mutex poisoned!!");
+                return false;
+            }
+        };
+        let mut changed = false;
+        for client in self.deserialized_clients.iter_mut() {
+            if let Some(&last_seen) = seen.get(&client.udid) {
+                if last_seen > client.last_seen {
+                    client.last_seen = last_seen;
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+
+    /// Saves when devices were last seen, if any did something since the last save.
+    pub fn save_seen(&mut self) -> Result<(), Error> {
+        if self.apply_seen() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Finds the devices that haven't been seen within the retention period.
+    /// Unless this is a dry run they are unregistered and their pairing files removed.
+    pub fn expire_clients(&mut self, dry_run: bool) -> Result<Vec<DeserializedClient>, Error> {
+        if self.retention_days == 0 {
+            return Ok(vec![]);
+        }
+        self.apply_seen();
+        let cutoff = now().saturating_sub(self.retention_days * DAY);
+        let (expired, kept): (Vec<_>, Vec<_>) = self
+            .deserialized_clients
+            .iter()
+            .cloned()
+            .partition(|c| c.last_seen < cutoff);
+        if dry_run || expired.is_empty() {
+            return Ok(expired);
+        }
+
+        for client in &expired {
+            if let Err(e) = self.remove_pairing_file(&client.udid) {
+                warn!("{}", e);
+            }
+            if let Ok(mut heart) = self.shared.heart.lock() {
+                heart.kill(client.udid.as_str());
+            }
+        }
+        self.deserialized_clients = kept;
+        self.save()?;
+        Ok(expired)
+    }
+
+    /// Compares the registered devices with the stored pairing records.
+    /// A repair removes orphaned records JitStreamer encrypted, see PairingStore::is_removable,
+    /// and drops all but the most recently seen registration of a device, whose other tokens
+    /// stop working.
+    /// Devices missing a record and invalid records are only reported.
+    pub fn check_integrity(&mut self, repair: bool) -> Result<IntegrityReport, Error> {
+        let mut report = integrity::check(&self.deserialized_clients, &self.shared.records)?;
+        if !repair {
+            return Ok(report);
+        }
+
+        for udid in &report.orphaned_records {
+            if self
+                .shared
+                .records
+                .is_removable(udid, integrity::ORPHAN_GRACE)
+            {
+                self.shared.records.remove(udid)?;
+                report.removed_records.push(udid.clone());
+            }
+        }
+        if !report.duplicate_udids.is_empty() {
+            let mut newest: HashMap<&str, usize> = HashMap::new();
+            for (i, client) in self.deserialized_clients.iter().enumerate() {
+                match newest.get(client.udid.as_str()) {
+                    Some(&j) if self.deserialized_clients[j].last_seen >= client.last_seen => {}
+                    _ => {
+                        newest.insert(client.udid.as_str(), i);
+                    }
                 }
+            }
+            let keep: HashSet<usize> = newest.into_values().collect();
+            let previous = self.deserialized_clients.clone();
+            let mut i = 0;
+            self.deserialized_clients.retain(|_| {
                 i += 1;
+                keep.contains(&(i - 1))
+            });
+            if let Err(e) = self.save() {
+                self.deserialized_clients = previous;
+                return Err(e);
             }
-            self.save();
-            Ok(())
-        } else {
-            Err(())
         }
+        report.repaired = true;
+        Ok(report)
     }
 
-    pub fn get_by_ip(&mut self, ip: &str) -> Option<Client> {
+    /// Replaces a registered device's token, returning the new one.
+    /// The old token stops working straight away.
+    pub fn issue_token(&mut self, udid: &str) -> Result<String, Error> {
+        let client = match self
+            .deserialized_clients
+            .iter_mut()
+            .find(|c| c.udid == udid)
+        {
+            Some(c) => c,
+            None => return Err(Error::NotRegistered),
+        };
+        let token = new_token();
+        client.token_hash = Some(hash_token(&token));
+        self.save()?;
+        Ok(token)
+    }
+
+    /// Works out which registered device is making a request.
+    /// A token always wins, the IP the request came from is only used without one.
+    /// When several devices share that IP, the request has to pick one by UDID.
+    pub fn identify(
+        &mut self,
+        addr: Option<SocketAddr>,
+        device: &DeviceId,
+    ) -> Result<Client, Error> {
+        let addr = match addr {
+            Some(addr) => addr,
+            None => {
+                warn!("No address provided");
+                return Err(Error::NoAddress);
+            }
+        };
+        if !self.check_ip(addr.ip()) {
+            warn!("Address not allowed");
+            return Err(Error::IpNotAllowed);
+        }
+        if let Some(token) = &device.token {
+            return match self.get_by_token(token) {
+                Some(client) if device.udid.as_ref().is_some_and(|u| *u != client.udid) => {
+                    warn!(
+                        "Request from {} chose a device its token isn't for",
+                        addr.ip()
+                    );
+                    Err(Error::DeviceMismatch)
+                }
+                Some(client) => Ok(client),
+                None => {
+                    warn!("Request from {} sent an unknown token", addr.ip());
+                    Err(Error::InvalidToken)
+                }
+            };
+        }
+        if !self.ip_fallback {
+            return Err(Error::TokenRequired);
+        }
+        let mut clients = self.get_by_ip(&addr.ip().to_string());
+        if let Some(udid) = &device.udid {
+            clients.retain(|c| &c.udid == udid);
+        }
+        match clients.len() {
+            0 => {
+                warn!("No client found with the given IP");
+                Err(Error::NotRegistered)
+            }
+            1 => Ok(clients.remove(0)),
+            n => {
+                warn!("{} devices share {}, none was chosen", n, addr.ip());
+                Err(Error::AmbiguousDevice(n))
+            }
+        }
+    }
+
+    pub fn get_by_token(&self, token: &str) -> Option<Client> {
+        let hash = hash_token(token);
         let res = self
             .deserialized_clients
             .iter()
-            .find(|client| client.ip == ip);
-        match res {
-            Some(c) => Some(c.to_client(
-                &format!("{}/{}.plist", self.plist_storage, c.udid),
-                &self.dmg_path,
-                self.heart.clone(),
-                self.mounts.clone(),
-            )),
-            None => None,
-        }
+            .find(|c| c.token_hash.as_deref() == Some(hash.as_str()));
+        res.map(|c| c.to_client(&self.shared))
+    }
+
+    /// Every device registered from an IP.
+    pub fn get_by_ip(&self, ip: &str) -> Vec<Client> {
+        self.deserialized_clients
+            .iter()
+            .filter(|client| client.ip == ip)
+            .map(|c| c.to_client(&self.shared))
+            .collect()
     }
 
-    pub fn _get_by_udid(&self, udid: &str) -> Option<Client> {
+    pub fn get_by_udid(&self, udid: &str) -> Option<Client> {
         let res = self.deserialized_clients.iter().find(|c| c.udid == udid);
-        res.map(|c| {
-            c.to_client(
-                &format!("{}/{}.plist", self.plist_storage, c.udid),
-                &self.dmg_path,
-                self.heart.clone(),
-                self.mounts.clone(),
-            )
-        })
+        res.map(|c| c.to_client(&self.shared))
     }
 
-    pub fn write_pairing_file(&self, plist: String, udid: &String) -> Result<(), ()> {
-        let path = format!("{}/{}.plist", &self.plist_storage, &udid);
-        let mut file = std::fs::File::create(&path).unwrap();
-        match std::io::Write::write_all(&mut file, plist.as_bytes()) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(()),
-        }
+    pub fn read_pairing_file(&self, udid: &str) -> Result<Vec<u8>, Error> {
+        self.shared.records.read(udid)
     }
 
-    pub fn _remove_pairing_file(&self, udid: &String) -> Result<(), ()> {
-        let path = format!("{}/{}.plist", &self.plist_storage, &udid);
-        match std::fs::remove_file(&path) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(()),
-        }
+    pub fn remove_pairing_file(&self, udid: &String) -> Result<(), Error> {
+        self.shared.records.remove(udid)
     }
 
-    pub async fn test_new_client(ip: &String, udid: &String) -> Result<(), ()> {
+    pub async fn test_new_client(ip: &String, udid: &String) -> Result<(), Error> {
         // Determine if device is in the muxer
         let ip = match IpAddr::from_str(ip) {
             Ok(ip) => ip,
             Err(e) => {
                 warn!("Error parsing ip {}: {}", ip, e);
-                return Err(());
+                return Err(Error::InvalidIp(e));
             }
         };
         let to_test = Device::new(udid, Some(ip), 0);
@@ -222,7 +452,7 @@ impl Backend {
             Ok(_) => Ok(()),
             Err(e) => {
                 warn!("Error creating lockdownd client: {:?}", e);
-                Err(())
+                Err(Error::PairingTest(e))
             }
         };
 
@@ -243,36 +473,33 @@ impl Backend {
         false
     }
 
-    pub fn potential_pair(&mut self, ip: String) -> u16 {
-        let mut rng = rand::thread_rng();
-        let code: u16 = rng.gen_range(10000..65535);
-
-        let p = PairPotential { ip, code };
-        self.pair_potential.push(p);
-        code
+    /// Drops every outstanding pair code, returning how many there were.
+    pub fn clear_codes(&mut self) -> usize {
+        self.pair_codes.clear()
     }
 
-    pub fn check_code(&mut self, code: u16) -> Option<String> {
-        let mut i = 0;
-        while i < self.pair_potential.len() {
-            if self.pair_potential[i].code == code {
-                let ip = self.pair_potential[i].ip.clone();
-                return Some(ip);
-            }
-            i += 1;
-        }
-        None
+    /// Starts the usage counters again from zero, returning what they were.
+    pub fn reset_counter(&mut self) -> Counter {
+        std::mem::take(&mut self.counter)
     }
+}
 
-    pub fn remove_code(&mut self, code: u16) {
-        let mut i = 0;
-        while i < self.pair_potential.len() {
-            if self.pair_potential[i].code == code {
-                self.pair_potential.remove(i);
-            }
-            i += 1;
-        }
-    }
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Generates a random device token.
+fn new_token() -> String {
+    let mut rng = rand::thread_rng();
+    hex::encode(rng.gen::<[u8; 32]>())
+}
+
+/// Tokens are random, so a plain SHA-256 is enough to keep them safe at rest.
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
 }
 
 impl Default for Counter {
@@ -287,32 +514,54 @@ impl Default for Counter {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 /// Representation of an iDevice's information.
 pub struct DeserializedClient {
     /// The iDevice's IP on the VLAN.
     pub ip: String,
     /// The iDevice's UDID used to identify it.
     pub udid: String,
-    /// If the device hasn't been seen in 28 days, it will be removed.
+    /// When the device last did something, in seconds since the epoch.
+    /// Devices unseen for longer than the retention period are removed.
     pub last_seen: u64,
+    /// The SHA-256 of the device's token, devices registered before tokens have none.
+    #[serde(default)]
+    pub token_hash: Option<String>,
+    /// The IPs the device was registered from before, oldest first
+    #[serde(default)]
+    pub ip_history: Vec<PreviousIp>,
+    /// Whether last_seen is kept up to date. Before devices could expire it was only set
+    /// at registration, so older records start counting from when they are first loaded.
+    #[serde(default)]
+    pub seen_tracked: bool,
+}
+
+/// An IP a device used to be registered from.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct PreviousIp {
+    pub ip: String,
+    /// When the device moved off this IP, in seconds since the epoch
+    pub moved_at: u64,
 }
 
 impl DeserializedClient {
-    pub fn to_client(
-        &self,
-        plist_path: &String,
-        dmg_path: &String,
-        heart: Arc<Mutex<Heart>>,
-        mounts: Arc<Mutex<HashMap<String, String>>>,
-    ) -> Client {
+    /// Moves the device to a new IP, remembering the old one.
+    fn move_to(&mut self, ip: String, now: u64) {
+        let old = std::mem::replace(&mut self.ip, ip);
+        self.ip_history.push(PreviousIp {
+            ip: old,
+            moved_at: now,
+        });
+        if self.ip_history.len() > IP_HISTORY_LEN {
+            self.ip_history.remove(0);
+        }
+    }
+
+    pub fn to_client(&self, shared: &Shared) -> Client {
         Client {
             ip: self.ip.clone(),
             udid: self.udid.clone(),
-            pairing_file: plist_path.to_string(),
-            dmg_path: dmg_path.to_string(),
-            heart,
-            mounts,
+            shared: shared.clone(),
         }
     }
 }
@@ -0,0 +1,81 @@
+// jkcoxson
+
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+/// Enable JIT on iOS devices over the network.
+#[derive(Parser, Debug)]
+#[command(name = "jit_streamer", version)]
+pub struct Cli {
+    /// The config file, defaults to config.toml in the data directory
+    #[arg(long, global = true, value_name = "PATH", env = "JITSTREAMER_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Where relative paths in the config are resolved from,
+    /// defaults to the working directory
+    #[arg(long, global = true, value_name = "DIR", env = "JITSTREAMER_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    // The flags older versions took instead of commands, kept so scripts don't break
+    #[arg(long, hide = true)]
+    check_config: bool,
+    #[arg(long, hide = true)]
+    rotate_key: bool,
+    #[arg(long, hide = true)]
+    check_integrity: bool,
+    #[arg(long, hide = true, requires = "check_integrity")]
+    repair: bool,
+}
+
+impl Cli {
+    /// The command to run, the server unless another was asked for.
+    /// The old flags still work, with a warning to move to the commands.
+    pub fn command(&self) -> Command {
+        let (flag, replacement, command) = if self.check_config {
+            ("--check-config", "check-config", Command::CheckConfig)
+        } else if self.rotate_key {
+            ("--rotate-key", "rotate-key", Command::RotateKey)
+        } else if self.check_integrity && self.repair {
+            let command = Command::CheckIntegrity { repair: true };
+            ("--check-integrity", "check-integrity --repair", command)
+        } else if self.check_integrity {
+            let command = Command::CheckIntegrity { repair: false };
+            ("--check-integrity", "check-integrity", command)
+        } else {
+            return self.command.clone().unwrap_or(Command::Serve);
+        };
+        eprintln!(
+            "{} is deprecated and will be removed, use `jit_streamer {}` instead",
+            flag, replacement
+        );
+        command
+    }
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Run the server, the default without a command
+    Serve,
+    /// Write the default config without starting the server
+    Init {
+        /// Replace a config that already exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Check the config and exit, non-zero if there is anything wrong with it
+    CheckConfig,
+    /// Print the default config
+    PrintDefaultConfig,
+    /// Print the config the server would use, with environment overrides applied
+    /// and secrets redacted
+    PrintConfig,
+    /// Encrypt every pairing record under a new key in the key file, with the server stopped
+    RotateKey,
+    /// Compare the registered devices with the stored pairing records
+    CheckIntegrity {
+        /// Remove orphaned records JitStreamer encrypted, and duplicate registrations
+        #[arg(long)]
+        repair: bool,
+    },
+}
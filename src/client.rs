@@ -2,81 +2,129 @@
 
 use log::{info, warn};
 use plist_plus::Plist;
-use rusty_libimobiledevice::{idevice::Device, services::instproxy::InstProxyClient};
+use rusty_libimobiledevice::{
+    idevice::Device,
+    services::{
+        afc::{AfcClient, AfcFileMode},
+        instproxy::InstProxyClient,
+    },
+};
 use std::{
     collections::HashMap,
+    io::{Read, Write},
     net::IpAddr,
+    path::Path,
     str::FromStr,
     sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
+    error::Error,
     heartbeat::Heart,
-    messages::{DETACH, LOOKUP_APPS, MOUNTING, START_DEBUG_SERVER, START_INSTPROXY},
+    jobs::Jobs,
+    metrics::{Metrics, Operation},
+    pairing_store::PairingStore,
+    progress::{self, Phase, Progress},
+    usage::{EventKind, Usage},
 };
 
+/// The directory on the device that instproxy installs packages from.
+const STAGING_DIRECTORY: &str = "PublicStaging";
+/// How many bytes to send over AFC per write.
+const AFC_CHUNK_SIZE: usize = 1024 * 1024;
+/// How many bytes to download between progress reports.
+const PROGRESS_INTERVAL: u64 = 1024 * 1024;
+
+/// What every client shares with the backend, cloned into each one.
+#[derive(Clone)]
+pub struct Shared {
+    /// Pairing records, encrypted at rest when a key is configured
+    pub records: Arc<PairingStore>,
+    pub dmg_path: String,
+    pub heart: Arc<Mutex<Heart>>,
+    pub jobs: Arc<Mutex<Jobs>>,
+    pub progress: Arc<Mutex<Progress>>,
+    pub metrics: Arc<Metrics>,
+    pub usage: Arc<Mutex<Usage>>,
+    /// When devices last did something, waiting to be saved to the database
+    pub seen: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+#[derive(Clone)]
 pub struct Client {
     pub ip: String,
     pub udid: String,
-    pub pairing_file: String,
-    pub dmg_path: String,
-    pub heart: Arc<Mutex<Heart>>,
-    pub mounts: Arc<Mutex<HashMap<String, String>>>,
+    pub shared: Shared,
 }
 
 impl Client {
-    #[allow(dead_code)]
-    pub fn new(
-        ip: String,
-        udid: String,
-        pairing_file: String,
-        dmg_path: String,
-        heart: Arc<Mutex<Heart>>,
-        mounts: Arc<Mutex<HashMap<String, String>>>,
-    ) -> Client {
-        Client {
-            ip,
-            udid,
-            pairing_file,
-            dmg_path,
-            heart,
-            mounts,
+    fn report_progress(&self, phase: Phase, bytes: u64, total: Option<u64>, message: &str) {
+        progress::report(
+            &self.shared.progress,
+            &self.udid,
+            phase,
+            bytes,
+            total,
+            message,
+        );
+    }
+
+    /// Adds something this device did to the usage history.
+    /// Anything that worked also counts as the device being seen, so it isn't expired.
+    pub fn record_usage(&self, kind: EventKind, bundle_id: Option<String>, success: bool) {
+        if let Ok(mut usage) = self.shared.usage.lock() {
+            usage.record(kind, &self.udid, bundle_id, success);
+        }
+        if success {
+            self.mark_seen();
+        }
+    }
+
+    /// Notes that the device just did something, the backend saves it with the database.
+    pub fn mark_seen(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if let Ok(mut seen) = self.shared.seen.lock() {
+            seen.insert(self.udid.clone(), now);
         }
     }
 
     /// Connects to a given device and runs preflight operations.
-    pub fn connect(&self) -> Result<Device, String> {
+    pub fn connect(&self) -> Result<Device, Error> {
+        let timer = self.shared.metrics.time(Operation::Connect);
         // Determine if device is in the muxer
         let ip = match IpAddr::from_str(&self.ip) {
             Ok(ip) => ip,
             Err(e) => {
                 warn!("Error parsing ip: {}", e);
-                return Err("Unable to parse ip".to_string());
+                return Err(Error::InvalidIp(e));
             }
         };
+        // The muxer reads the pairing record from disk, it may only be kept encrypted
+        self.shared.records.materialize(&self.udid)?;
         let device = Device::new(self.udid.clone(), Some(ip), 0);
         info!("Starting heartbeat {}", self.udid);
 
         // Start heartbeat
-        (*self.heart.lock().unwrap()).start(&device);
+        (*self.shared.heart.lock().unwrap()).start(&device);
 
+        timer.succeed();
         Ok(device)
     }
 
-    pub fn get_apps(&self) -> Result<Plist, String> {
-        let device = match self.connect() {
-            Ok(device) => device,
-            Err(_) => {
-                return Err("Unable to connect to device".to_string());
-            }
-        };
+    pub fn get_apps(&self) -> Result<Plist, Error> {
+        let device = self.connect()?;
 
+        let timer = self.shared.metrics.time(Operation::InstproxyLookup);
         let instproxy_client = match device.new_instproxy_client("jitstreamer") {
             Ok(instproxy) => instproxy,
             Err(e) => {
                 warn!("Error starting instproxy: {:?}", e);
-                (*self.heart.lock().unwrap()).kill(device.get_udid());
-                return Err(format!("{} {:?}", START_INSTPROXY, e));
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::StartInstproxy(e));
             }
         };
         let client_opts = InstProxyClient::create_return_attributes(
@@ -87,30 +135,30 @@ impl Client {
             Ok(apps) => apps,
             Err(e) => {
                 warn!("Error looking up apps: {:?}", e);
-                (*self.heart.lock().unwrap()).kill(device.get_udid());
-                return Err(format!("{} {:?}", LOOKUP_APPS, e));
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::LookupApps(e));
             }
         };
+        timer.succeed();
 
-        (*self.heart.lock().unwrap()).kill(device.get_udid());
+        (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
 
         Ok(lookup_results)
     }
 
-    pub fn debug_app(&self, app: String) -> Result<(), String> {
-        let device = match self.connect() {
-            Ok(device) => device,
-            Err(_) => {
-                return Err("Unable to connect to device".to_string());
-            }
-        };
+    /// Launches an app with a debugger attached.
+    /// If the developer disk image needs mounting first and a job is given to `defer`, the launch
+    /// is queued under that job and runs automatically once the mount finishes.
+    pub fn debug_app(&self, app: String, defer: Option<&str>) -> Result<(), Error> {
+        let device = self.connect()?;
 
+        let timer = self.shared.metrics.time(Operation::InstproxyLookup);
         let instproxy_client = match device.new_instproxy_client("idevicedebug") {
             Ok(instproxy) => instproxy,
             Err(e) => {
                 warn!("Error starting instproxy: {:?}", e);
-                (*self.heart.lock().unwrap()).kill(device.get_udid());
-                return Err(format!("{} {:?}", START_INSTPROXY, e));
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::StartInstproxy(e));
             }
         };
         let client_opts = InstProxyClient::create_return_attributes(
@@ -121,18 +169,19 @@ impl Client {
             Ok(apps) => apps,
             Err(e) => {
                 warn!("Error looking up apps: {:?}", e);
-                (*self.heart.lock().unwrap()).kill(device.get_udid());
-                return Err(format!("{} {:?}", LOOKUP_APPS, e));
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::LookupApps(e));
             }
         };
+        timer.succeed();
         let lookup_results = lookup_results.dict_get_item(&app).unwrap();
 
         let working_directory = match lookup_results.dict_get_item("Container") {
             Ok(p) => p,
             Err(_) => {
                 warn!("App not found");
-                (*self.heart.lock().unwrap()).kill(device.get_udid());
-                return Err("App not found".to_string());
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::AppNotFound);
             }
         };
 
@@ -140,31 +189,32 @@ impl Client {
             Ok(p) => p,
             Err(_) => {
                 warn!("App not found");
-                (*self.heart.lock().unwrap()).kill(device.get_udid());
-                return Err("App not found".to_string());
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::AppNotFound);
             }
         };
         info!("Working directory: {}", working_directory);
 
-        let bundle_path = match instproxy_client.get_path_for_bundle_identifier(app) {
+        let bundle_path = match instproxy_client.get_path_for_bundle_identifier(app.clone()) {
             Ok(p) => p,
             Err(e) => {
                 warn!("Error getting path for bundle identifier: {:?}", e);
-                (*self.heart.lock().unwrap()).kill(device.get_udid());
-                return Err("Unable to get path for bundle identifier".to_string());
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::BundlePath(e));
             }
         };
 
         // Attempt to create a debug server 3 times before giving up
+        let timer = self.shared.metrics.time(Operation::DebugserverLaunch);
         let mut debug_server = None;
+        let mut debug_server_error = None;
         for _ in 1..4 {
-            let ds = match device.new_debug_server("jitstreamer") {
-                Ok(d) => Some(d),
-                Err(_) => None,
-            };
-            if ds.is_some() {
-                debug_server = ds;
-                break;
+            match device.new_debug_server("jitstreamer") {
+                Ok(d) => {
+                    debug_server = Some(d);
+                    break;
+                }
+                Err(e) => debug_server_error = Some(e),
             }
         }
 
@@ -178,14 +228,15 @@ impl Client {
                 }
                 Err(e) => {
                     warn!("Error starting mobile_image_mounter: {:?}", e);
-                    return Err("Unable to start mobile_image_mounter".to_string());
+                    (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                    return Err(Error::StartImageMounter(e));
                 }
             };
 
             let path = match self.get_dmg_path() {
                 Ok(p) => p,
                 Err(e) => {
-                    (*self.heart.lock().unwrap()).kill(device.get_udid());
+                    (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
                     return Err(e);
                 }
             };
@@ -194,8 +245,8 @@ impl Client {
                 Ok(images) => images,
                 Err(e) => {
                     warn!("Error looking up images: {:?}", e);
-                    (*self.heart.lock().unwrap()).kill(device.get_udid());
-                    return Err("Unable to look up images".to_string());
+                    (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                    return Err(Error::LookupImages(e));
                 }
             };
 
@@ -204,45 +255,50 @@ impl Client {
                     Ok(n) => {
                         if n > 0 {
                             warn!("Image already mounted, failed to start debug server");
-                            (*self.heart.lock().unwrap()).kill(device.get_udid());
-                            return Err(START_DEBUG_SERVER.to_string());
+                            (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                            return Err(Error::StartDebugServer(debug_server_error));
                         }
                     }
                     Err(_) => {
-                        (*self.heart.lock().unwrap()).kill(device.get_udid());
-                        return Err("Image plist in wrong format".to_string());
+                        (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                        return Err(Error::ImagePlist);
                     }
                 },
                 Err(_) => {
-                    (*self.heart.lock().unwrap()).kill(device.get_udid());
-                    return Err("Image plist in wrong format".to_string());
+                    (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                    return Err(Error::ImagePlist);
                 }
             }
 
-            let device = device.clone();
-            let heart = self.heart.clone();
-            let mounts = self.mounts.clone();
-            tokio::task::spawn_blocking(move || {
-                let mut i = 5;
-                loop {
-                    match Client::upload_dev_dmg(&device, &path, mounts.clone()) {
-                        Ok(_) => {
-                            (*heart.lock().unwrap()).kill(device.get_udid());
-                            break;
-                        }
-                        Err(e) => {
-                            warn!("Error uploading dmg: {:?}", e);
-                            i -= 1;
-                            if i == 0 {
-                                (*heart.lock().unwrap()).kill(device.get_udid());
-                                break;
-                            }
-                        }
-                    }
+            if let Some(job) = defer {
+                if let Ok(mut jobs) = self.shared.jobs.lock() {
+                    jobs.defer_launch(&self.udid, &app, job);
                 }
-            });
+            }
+
+            // A mount already running launches the deferred app when it finishes
+            let mounting = match self.shared.jobs.lock() {
+                Ok(jobs) => jobs.is_mounting(&self.udid),
+                Err(_) => false,
+            };
+            if !mounting {
+                let device = device.clone();
+                let client = self.clone();
+                tokio::task::spawn_blocking(move || {
+                    let res = client.upload_dev_dmg(&device, &path, 5);
+                    (*client.shared.heart.lock().unwrap()).kill(device.get_udid());
+                    match res {
+                        // Another launch started a mount first, that one runs the launch
+                        Err(Error::Mounting) => {}
+                        res => client.run_pending_launch(res.is_ok()),
+                    }
+                });
+            }
 
-            return Err(MOUNTING.to_string());
+            if defer.is_some() {
+                return Err(Error::MountingDeferred);
+            }
+            return Err(Error::Mounting);
         }
         let debug_server = debug_server.unwrap();
 
@@ -252,8 +308,11 @@ impl Client {
             }
             Err(e) => {
                 warn!("Error setting max packet size: {:?}", e);
-                (*self.heart.lock().unwrap()).kill(device.get_udid());
-                return Err("Unable to set max packet size".to_string());
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::DebugCommand {
+                    step: "set max packet size",
+                    cause: e,
+                });
             }
         }
 
@@ -263,8 +322,11 @@ impl Client {
             }
             Err(e) => {
                 warn!("Error setting working directory: {:?}", e);
-                (*self.heart.lock().unwrap()).kill(device.get_udid());
-                return Err("Unable to set working directory".to_string());
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::DebugCommand {
+                    step: "set working directory",
+                    cause: e,
+                });
             }
         }
 
@@ -274,8 +336,11 @@ impl Client {
             }
             Err(e) => {
                 warn!("Error setting argv: {:?}", e);
-                (*self.heart.lock().unwrap()).kill(device.get_udid());
-                return Err("Unable to set argv".to_string());
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::DebugCommand {
+                    step: "set argv",
+                    cause: e,
+                });
             }
         }
 
@@ -283,8 +348,11 @@ impl Client {
             Ok(res) => info!("Got launch response: {:?}", res),
             Err(e) => {
                 warn!("Error checking if app launched: {:?}", e);
-                (*self.heart.lock().unwrap()).kill(device.get_udid());
-                return Err("Unable to check if app launched".to_string());
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::DebugCommand {
+                    step: "check if app launched",
+                    cause: e,
+                });
             }
         }
 
@@ -292,44 +360,70 @@ impl Client {
             Ok(res) => info!("Detaching: {:?}", res),
             Err(e) => {
                 warn!("Error detaching: {:?}", e);
-                (*self.heart.lock().unwrap()).kill(device.get_udid());
-                return Err(DETACH.to_string());
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::Detach(e));
             }
         }
+        timer.succeed();
 
-        (*self.heart.lock().unwrap()).kill(device.get_udid());
+        (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
 
         Ok(())
     }
 
-    pub fn attach_debugger(
-        &self,
-        pid: u16,
-        mounts: Arc<Mutex<HashMap<String, String>>>,
-    ) -> Result<(), String> {
+    /// Runs a launch that was queued while the developer disk image was mounting.
+    fn run_pending_launch(&self, mounted: bool) {
+        let pending = match self.shared.jobs.lock() {
+            Ok(mut jobs) => jobs.take_pending_launch(&self.udid),
+            Err(_) => None,
+        };
+        let (app, job) = match pending {
+            Some(p) => p,
+            None => return,
+        };
+        if !mounted {
+            warn!("Dropping deferred launch of {}, the mount failed", app);
+            if let Ok(mut jobs) = self.shared.jobs.lock() {
+                jobs.fail(&job, &Error::MountFailed);
+            }
+            return;
+        }
+
+        info!("Launching deferred app {}", app);
+        if let Ok(mut jobs) = self.shared.jobs.lock() {
+            jobs.start(&job);
+        }
+        let res = self.debug_app(app.clone(), None);
+        self.record_usage(EventKind::Launch, Some(app), res.is_ok());
+        if let Ok(mut jobs) = self.shared.jobs.lock() {
+            jobs.finish(&job, &res);
+        }
+    }
+
+    pub fn attach_debugger(&self, pid: u16) -> Result<(), Error> {
         let device = self.connect()?;
         let debug_server = match device.new_debug_server("jitstreamer") {
             Ok(d) => d,
             Err(_) => {
                 let path = match self.get_dmg_path() {
                     Ok(p) => p,
-                    Err(_) => {
-                        (*self.heart.lock().unwrap()).kill(device.get_udid());
-                        return Err("Unable to get dmg path, the server was set up incorrectly!"
-                            .to_string());
+                    Err(e) => {
+                        (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                        return Err(e);
                     }
                 };
-                match Client::upload_dev_dmg(&device, &path, mounts) {
+                match self.upload_dev_dmg(&device, &path, 1) {
                     Ok(_) => match device.new_debug_server("jitstreamer") {
                         Ok(d) => d,
-                        Err(_) => {
-                            (*self.heart.lock().unwrap()).kill(device.get_udid());
-                            return Err("Unable to get debug server".to_string());
+                        Err(e) => {
+                            (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                            return Err(Error::StartDebugServer(Some(e)));
                         }
                     },
                     Err(e) => {
                         warn!("Error uploading dmg: {:?}", e);
-                        return Err("Unable to upload dmg".to_string());
+                        (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                        return Err(e);
                     }
                 }
             }
@@ -348,8 +442,11 @@ impl Client {
             Ok(res) => info!("Successfully attached: {:?}", res),
             Err(e) => {
                 warn!("Error attaching: {:?}", e);
-                (*self.heart.lock().unwrap()).kill(device.get_udid());
-                return Err("Unable to attach".to_string());
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::DebugCommand {
+                    step: "attach",
+                    cause: e,
+                });
             }
         }
 
@@ -357,31 +454,118 @@ impl Client {
             Ok(res) => info!("Detaching: {:?}", res),
             Err(e) => {
                 warn!("Error detaching: {:?}", e);
-                (*self.heart.lock().unwrap()).kill(device.get_udid());
-                return Err("Unable to detach".to_string());
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::Detach(e));
             }
         }
 
-        (*self.heart.lock().unwrap()).kill(device.get_udid());
+        (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
 
         Ok(())
     }
 
-    pub fn install_app(&self, _ipa: Vec<u8>) -> Result<(), String> {
+    pub fn install_app(&self, ipa_path: &Path) -> Result<(), Error> {
+        let mut ipa = match std::fs::File::open(ipa_path) {
+            Ok(f) => f,
+            Err(e) => {
+                warn!("Error opening IPA {}: {}", ipa_path.display(), e);
+                return Err(Error::Io {
+                    context: "Unable to read the uploaded app",
+                    cause: e,
+                });
+            }
+        };
+
         let device = self.connect()?;
 
-        let _inst = device.new_instproxy_client("jitstreamer")?;
+        // Upload the IPA to the staging directory over AFC
+        let afc_client = match AfcClient::start_service(&device, "jitstreamer") {
+            Ok(afc) => afc,
+            Err(e) => {
+                warn!("Error starting AFC: {:?}", e);
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::StartAfc(e));
+            }
+        };
 
-        todo!();
-    }
+        // The directory usually exists already, so failures here are not fatal
+        if let Err(e) = afc_client.make_directory(STAGING_DIRECTORY) {
+            info!("Unable to create {}: {:?}", STAGING_DIRECTORY, e);
+        }
 
-    pub fn get_ios_version(&self) -> Result<String, String> {
-        let device = match self.connect() {
-            Ok(device) => device,
-            Err(_) => {
-                return Err("Unable to connect to device".to_string());
+        let staging_path = format!("{}/{}.ipa", STAGING_DIRECTORY, self.udid);
+        info!("Uploading IPA to {}", staging_path);
+        let handle = match afc_client.file_open(staging_path.clone(), AfcFileMode::WriteOnly) {
+            Ok(h) => h,
+            Err(e) => {
+                warn!("Error opening staging file: {:?}", e);
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::UploadIpa(e));
             }
         };
+        let mut chunk = vec![0; AFC_CHUNK_SIZE];
+        loop {
+            let n = match ipa.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    warn!("Error reading IPA: {}", e);
+                    let _ = afc_client.file_close(handle);
+                    (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                    return Err(Error::Io {
+                        context: "Unable to read the uploaded app",
+                        cause: e,
+                    });
+                }
+            };
+            if let Err(e) = afc_client.file_write(handle, chunk[..n].to_vec()) {
+                warn!("Error writing staging file: {:?}", e);
+                let _ = afc_client.file_close(handle);
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::UploadIpa(e));
+            }
+        }
+        if let Err(e) = afc_client.file_close(handle) {
+            warn!("Error closing staging file: {:?}", e);
+            (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+            return Err(Error::UploadIpa(e));
+        }
+        info!("Successfully uploaded IPA");
+
+        let instproxy_client = match device.new_instproxy_client("jitstreamer") {
+            Ok(instproxy) => instproxy,
+            Err(e) => {
+                warn!("Error starting instproxy: {:?}", e);
+                let _ = afc_client.remove_path(staging_path);
+                (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+                return Err(Error::StartInstproxy(e));
+            }
+        };
+
+        // Upgrade installs the app if it isn't present, and replaces it otherwise
+        let res = match instproxy_client.upgrade(staging_path.clone(), None) {
+            Ok(_) => {
+                info!("Successfully installed app");
+                Ok(())
+            }
+            Err(e) => {
+                warn!("Error installing app: {:?}", e);
+                Err(Error::InstallApp(e))
+            }
+        };
+
+        // Clean up the staged IPA so it doesn't eat the device's storage
+        if let Err(e) = afc_client.remove_path(staging_path) {
+            warn!("Unable to remove staged IPA: {:?}", e);
+        }
+
+        (*self.shared.heart.lock().unwrap()).kill(device.get_udid());
+
+        res
+    }
+
+    pub fn get_ios_version(&self) -> Result<String, Error> {
+        let device = self.connect()?;
 
         let lockdown_client = match device.new_lockdownd_client("ideviceimagemounter") {
             Ok(lckd) => {
@@ -390,7 +574,7 @@ impl Client {
             }
             Err(e) => {
                 warn!("Error starting lockdown service: {:?}", e);
-                return Err("Unable to start lockdown".to_string());
+                return Err(Error::Lockdownd(e));
             }
         };
 
@@ -398,7 +582,7 @@ impl Client {
             Ok(ios_version) => ios_version.get_string_val().unwrap(),
             Err(e) => {
                 warn!("Error getting iOS version: {:?}", e);
-                return Err("Unable to get iOS version".to_string());
+                return Err(Error::IosVersion(e));
             }
         };
 
@@ -407,16 +591,32 @@ impl Client {
         Ok(ios_version)
     }
 
-    pub fn get_dmg_path(&self) -> Result<String, String> {
+    pub fn get_dmg_path(&self) -> Result<String, Error> {
         let ios_version = self.get_ios_version()?;
 
         // Check if directory exists
-        let path = std::path::Path::new(&self.dmg_path).join(format!("{}.dmg", &ios_version));
+        let path =
+            std::path::Path::new(&self.shared.dmg_path).join(format!("{}.dmg", &ios_version));
         info!("Checking if {} exists", path.display());
         if path.exists() {
             return Ok(String::from(path.to_string_lossy()));
         }
 
+        let timer = self.shared.metrics.time(Operation::DdiDownload);
+        match self.download_dmg(&ios_version) {
+            Ok(path) => {
+                timer.succeed();
+                Ok(path)
+            }
+            Err(e) => {
+                self.report_progress(Phase::Failed, 0, None, &e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    /// Downloads and unzips the developer disk image for an iOS version.
+    fn download_dmg(&self, ios_version: &str) -> Result<String, Error> {
         let mut ios_dmg_url = None;
         let dmg_libraries =
             ["https://raw.githubusercontent.com/jkcoxson/JitStreamer/master/versions.json"];
@@ -428,77 +628,107 @@ impl Client {
             info!("Downloading iOS dictionary...");
             let response = match reqwest::blocking::get(lib) {
                 Ok(response) => response,
-                Err(_) => {
-                    return Err("Error downloading versions.json".to_string());
+                Err(e) => {
+                    return Err(Error::Download {
+                        what: "versions.json",
+                        cause: e,
+                    });
                 }
             };
             let contents = match response.text() {
                 Ok(contents) => contents,
-                Err(_) => {
-                    return Err("Error reading versions.json".to_string());
+                Err(e) => {
+                    return Err(Error::Download {
+                        what: "versions.json",
+                        cause: e,
+                    });
                 }
             };
             // Parse versions.json
             let versions: serde_json::Value = serde_json::from_str(&contents).unwrap();
             // Get DMG url
             ios_dmg_url = versions
-                .get(ios_version.clone())
+                .get(ios_version)
                 .map(|x| x.as_str().unwrap().to_string());
         }
 
         if ios_dmg_url.is_none() {
-            return Err(format!(
-                "Libraries did not contain a DMG for iOS {}",
-                ios_version
-            ));
+            return Err(Error::NoDmg(ios_version.to_string()));
         }
 
         // Download DMG zip
         info!("Downloading iOS {} DMG...", ios_version);
-        let resp = match reqwest::blocking::get(ios_dmg_url.unwrap()) {
+        let mut resp = match reqwest::blocking::get(ios_dmg_url.unwrap()) {
             Ok(resp) => resp,
-            Err(_) => {
-                return Err("Error downloading DMG".to_string());
-            }
-        };
-        let mut out = match std::fs::File::create("dmg.zip") {
-            Ok(out) => out,
-            Err(_) => {
-                return Err("Error creating temp DMG.zip".to_string());
+            Err(e) => {
+                return Err(Error::Download {
+                    what: "DMG",
+                    cause: e,
+                });
             }
         };
-        let mut content = std::io::Cursor::new(match resp.bytes() {
-            Ok(content) => content,
-            Err(_) => {
-                return Err("Error reading DMG".to_string());
-            }
-        });
-        match std::io::copy(&mut content, &mut out) {
-            Ok(_) => (),
-            Err(_) => {
-                return Err("Error downloading DMG".to_string());
+        let mut out =
+            std::fs::File::create("dmg.zip").map_err(Error::io("Error creating temp DMG.zip"))?;
+        let total = resp.content_length();
+        let mut downloaded = 0;
+        let mut last_report = 0;
+        let mut buf = vec![0; 64 * 1024];
+        self.report_progress(Phase::Download, 0, total, "");
+        loop {
+            let n = match resp.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    return Err(Error::Io {
+                        context: "Error reading DMG",
+                        cause: e,
+                    });
+                }
+            };
+            out.write_all(&buf[..n])
+                .map_err(Error::io("Error writing DMG.zip"))?;
+            downloaded += n as u64;
+            if downloaded - last_report >= PROGRESS_INTERVAL {
+                self.report_progress(Phase::Download, downloaded, total, "");
+                last_report = downloaded;
             }
-        };
+        }
+        self.report_progress(Phase::Download, downloaded, Some(downloaded), "");
+        drop(out);
         // Create tmp path
-        let tmp_path = format!("{}/tmp", &self.dmg_path);
+        let tmp_path = format!("{}/tmp", &self.shared.dmg_path);
         info!("tmp path {}", tmp_path);
-        std::fs::create_dir_all(&tmp_path).unwrap();
+        std::fs::create_dir_all(&tmp_path).map_err(Error::io("Error creating tmp path"))?;
         // Unzip zip
-        let mut dmg_zip = match zip::ZipArchive::new(std::fs::File::open("dmg.zip").unwrap()) {
-            Ok(dmg_zip) => dmg_zip,
-            Err(_) => {
-                return Err("Error opening DMG.zip".to_string());
-            }
-        };
-        match dmg_zip.extract(&tmp_path) {
-            Ok(_) => {}
-            Err(e) => return Err(format!("Failed to unzip DMG: {:?}", e)),
+        let zip_file =
+            std::fs::File::open("dmg.zip").map_err(Error::io("Error opening DMG.zip"))?;
+        let mut dmg_zip = zip::ZipArchive::new(zip_file)?;
+        let total: u64 = (0..dmg_zip.len())
+            .filter_map(|i| dmg_zip.by_index(i).ok().map(|f| f.size()))
+            .sum();
+        let mut extracted = 0;
+        self.report_progress(Phase::Unzip, 0, Some(total), "");
+        for i in 0..dmg_zip.len() {
+            let mut file = dmg_zip.by_index(i)?;
+            let out_path = match file.enclosed_name() {
+                Some(p) => std::path::Path::new(&tmp_path).join(p),
+                None => continue,
+            };
+            if file.is_dir() {
+                std::fs::create_dir_all(&out_path).map_err(Error::io("Failed to unzip DMG"))?;
+                continue;
+            }
+            if let Some(parent) = out_path.parent() {
+                std::fs::create_dir_all(parent).map_err(Error::io("Failed to unzip DMG"))?;
+            }
+            let mut out =
+                std::fs::File::create(&out_path).map_err(Error::io("Failed to unzip DMG"))?;
+            std::io::copy(&mut file, &mut out).map_err(Error::io("Failed to unzip DMG"))?;
+            extracted += file.size();
+            self.report_progress(Phase::Unzip, extracted, Some(total), "");
         }
         // Remove zip
-        match std::fs::remove_file("dmg.zip") {
-            Ok(_) => (),
-            Err(_) => return Err("Failed to remove DMG.zip".to_string()),
-        }
+        std::fs::remove_file("dmg.zip").map_err(Error::io("Failed to remove DMG.zip"))?;
         // Get folder name in tmp
         let mut dmg_path = std::path::PathBuf::new();
         for entry in std::fs::read_dir(&tmp_path).unwrap() {
@@ -512,11 +742,15 @@ impl Client {
         }
         // Move DMG to JIT Shipper directory
         let ios_dmg = dmg_path.join("DeveloperDiskImage.dmg");
-        std::fs::rename(ios_dmg, format!("{}/{}.dmg", &self.dmg_path, ios_version)).unwrap();
+        std::fs::rename(
+            ios_dmg,
+            format!("{}/{}.dmg", &self.shared.dmg_path, ios_version),
+        )
+        .unwrap();
         let ios_sig = dmg_path.join("DeveloperDiskImage.dmg.signature");
         std::fs::rename(
             ios_sig,
-            format!("{}/{}.dmg.signature", &self.dmg_path, ios_version),
+            format!("{}/{}.dmg.signature", &self.shared.dmg_path, ios_version),
         )
         .unwrap();
 
@@ -528,18 +762,55 @@ impl Client {
         );
 
         // Return DMG path
-        Ok(format!("{}/{}.dmg", &self.dmg_path, ios_version))
+        Ok(format!("{}/{}.dmg", &self.shared.dmg_path, ios_version))
     }
 
+    /// Mounts the developer disk image, trying up to `attempts` times under a single mount job
+    /// so the status route can report on it.
+    /// Returns Error::Mounting without doing anything if the device is already mounting.
     pub fn upload_dev_dmg(
+        &self,
         device: &Device,
         dmg_path: &String,
-        mounts: Arc<Mutex<HashMap<String, String>>>,
-    ) -> Result<(), String> {
-        // Add the device to mounts
-        if let Ok(mut mounts) = mounts.lock() {
-            mounts.insert(device.get_udid(), "".to_string());
+        attempts: usize,
+    ) -> Result<(), Error> {
+        let job = match self.shared.jobs.lock() {
+            Ok(mut jobs) => match jobs.start_mount(&device.get_udid(), dmg_path) {
+                Some(id) => Some(id),
+                None => return Err(Error::Mounting),
+            },
+            Err(_) => None,
+        };
+
+        let mut res = Err(Error::MountFailed);
+        for attempt in 1..=attempts {
+            let timer = self.shared.metrics.time(Operation::Mount);
+            res = Client::mount_dmg(device, dmg_path, &self.shared.progress);
+            match &res {
+                Ok(_) => {
+                    timer.succeed();
+                    break;
+                }
+                Err(e) => warn!("Mount attempt {} of {} failed: {:?}", attempt, attempts, e),
+            }
+        }
+        match &res {
+            Ok(_) => self.report_progress(Phase::Done, 0, None, ""),
+            Err(e) => self.report_progress(Phase::Failed, 0, None, &e.to_string()),
+        }
+        self.record_usage(EventKind::Mount, None, res.is_ok());
+        if let (Some(id), Ok(mut jobs)) = (&job, self.shared.jobs.lock()) {
+            jobs.finish(id, &res);
         }
+        res
+    }
+
+    fn mount_dmg(
+        device: &Device,
+        dmg_path: &String,
+        progress: &Arc<Mutex<Progress>>,
+    ) -> Result<(), Error> {
+        let udid = device.get_udid();
 
         let mim = match device.new_mobile_image_mounter("jitstreamer") {
             Ok(mim) => {
@@ -548,18 +819,14 @@ impl Client {
             }
             Err(e) => {
                 warn!("Error starting mobile_image_mounter: {:?}", e);
-                if let Ok(mut mounts) = mounts.lock() {
-                    mounts.insert(
-                        device.get_udid(),
-                        format!("Error starting mobile image mounter: {:?}", e),
-                    );
-                }
-                return Err("Unable to start mobile_image_mounter".to_string());
+                return Err(Error::StartImageMounter(e));
             }
         };
 
         info!("Uploading DMG from: {}", dmg_path);
         info!("signature: {}", format!("{}.signature", dmg_path.clone()));
+        // The image is uploaded in one call, so there are no bytes to count
+        progress::report(progress, &udid, Phase::Upload, 0, None, "");
         match mim.upload_image(
             dmg_path.clone(),
             "Developer",
@@ -570,12 +837,10 @@ impl Client {
             }
             Err(e) => {
                 warn!("Error uploading image: {:?}", e);
-                if let Ok(mut mounts) = mounts.lock() {
-                    mounts.insert(device.get_udid(), format!("Error uploading image: {:?}", e));
-                }
-                return Err("Unable to upload developer disk image".to_string());
+                return Err(Error::UploadImage(e));
             }
         }
+        progress::report(progress, &udid, Phase::Mount, 0, None, "");
         match mim.mount_image(
             dmg_path.clone(),
             "Developer",
@@ -586,16 +851,9 @@ impl Client {
             }
             Err(e) => {
                 warn!("Error mounting image: {:?}", e);
-                if let Ok(mut mounts) = mounts.lock() {
-                    mounts.insert(device.get_udid(), format!("Error mounting image: {:?}", e));
-                }
-                return Err("Unable to mount developer disk image".to_string());
+                return Err(Error::MountImage(e));
             }
         }
-        // Remove device from mounts
-        if let Ok(mut mounts) = mounts.lock() {
-            let _ = mounts.remove(&device.get_udid());
-        }
         Ok(())
     }
 }
@@ -1,7 +1,13 @@
 // jkcoxson
 
-const DEFAULT: &str = r#"# JitStreamer Config File
-# Revision: A
+pub const DEFAULT: &str = r#"# JitStreamer Config File
+# Revision: I
+
+# Any key can be overridden with an environment variable named after its section and key,
+# like JITSTREAMER_WEB_SERVER__PORT=8081 or JITSTREAMER_EXTRA__NETMUXD_ADDRESS=127.0.0.1:27015
+# Environment variables win over this file, and --data-dir wins over both.
+# Every key has the default shown here, so keys can be left out. Without this file the
+# defaults and environment variables are used on their own.
 
 [paths]
 # The path to host static content when a route is not matched
@@ -20,6 +26,17 @@ plist_storage = "plist_storage"
 # These files are used for mounting the iOS device, and are different depending on the version of iOS.
 dmg_path = "dmg_files"
 
+# The path to the usage history, which keeps the census across restarts
+usage_path = "usage.jsonl"
+
+[storage]
+# Where registered devices are kept, either "json" or "sqlite"
+backend = "json"
+
+# The path to the SQLite database, used when the backend is "sqlite"
+# When it is first created, the devices in database_path are imported into it
+sqlite_path = "database.sqlite"
+
 [web_server]
 # The port to run JitStreamer on
 port = 8080
@@ -44,30 +61,128 @@ allowed_subnet = "0.0.0.0/0"
 
 # The address that can be used to access netmuxd (uncomment to use)
 # This is a temporary option for use in SideStore
+# Devices that register from a new IP are moved in netmuxd with RemoveDevice and AddDevice,
+# a netmuxd that doesn't answer RemoveDevice keeps the old IP, which is logged
 # netmuxd_address = "127.0.0.1:27015"
+
+[install]
+# The path to store IPA uploads in while they are being received
+upload_path = "uploads"
+
+# The largest IPA that can be uploaded, in megabytes
+max_ipa_size = 4096
+
+# How long an unfinished upload session is kept before it is discarded, in hours
+session_lifetime = 24
+
+[auth]
+# Devices are given a token when they register, which shortcuts send in the
+# x-jitstreamer-token header. Devices that don't send one are looked up by the IP
+# they connect from. Turn this off once every shortcut sends its token.
+ip_fallback = true
+
+[admin]
+# The token for the admin API, sent as "Authorization: Bearer <token>"
+# The admin API is disabled until this is set (uncomment to use)
+# token = "change me to something long and random"
+
+[encryption]
+# Pairing records hold the key that unlocks each device, set a key to encrypt them at rest.
+# Either 64 hex characters, or a file that is created with a random key if it doesn't exist.
+# Without a key they are saved in plist_storage as plain plists. (uncomment to use)
+# Stop the server and run `jit_streamer rotate-key` to encrypt every record under
+# a new key in key_file.
+# key_file = "pairing.key"
+
+# Where encrypted pairing records are kept. plist_storage then only holds decrypted copies
+# for the muxer while devices are used, so put it on a tmpfs like /run/jitstreamer/lockdown
+# The copies can only be read by the user JitStreamer runs as, so run the muxer as that
+# user or as root.
+record_path = "pairing_records"
+
+# How long a decrypted copy is left in plist_storage, in minutes
+copy_lifetime = 5
+
+[pairing]
+# How long a pair code from /potential can be used for, in minutes
+code_lifetime = 10
+
+# How many failed follow ups a code gets before it is dropped, and how many
+# an IP gets within a code's lifetime before it is turned away
+max_attempts = 5
+
+[maintenance]
+# Devices that haven't done anything for this many days are unregistered
+# and their pairing files removed. Set to 0 to keep devices forever.
+retention_days = 28
+
+# How often to look for expired devices and save when devices were last seen, in minutes
+interval = 60
+
+# Usage events older than this many days are added to running totals and dropped from
+# the usage history, so it doesn't grow forever. The census looks back four weeks, so
+# this has to be at least 28. Set to 0 to keep every event.
+usage_retention_days = 90
                       
 "#;
 
-use std::{fs::File, io::Write};
+use ip_in_subnet::iface_in_subnet;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
-use serde::{Deserialize, Serialize};
+use crate::usage;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Starts every environment variable that overrides a config key.
+const ENV_PREFIX: &str = "JITSTREAMER_";
+/// Keys left out when the config is printed.
+const SECRETS: &[(&str, &str)] = &[("admin", "token"), ("encryption", "key")];
+
+/// Every section and key falls back to its default, which match DEFAULT.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct Config {
     pub paths: Paths,
     pub web_server: WebServer,
     pub extra: Extra,
+    pub install: Install,
+    pub auth: Auth,
+    pub admin: Admin,
+    pub storage: Storage,
+    pub encryption: Encryption,
+    pub pairing: Pairing,
+    pub maintenance: Maintenance,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 pub struct Paths {
     pub static_path: String,
     pub database_path: String,
     pub plist_storage: String,
     pub dmg_path: String,
+    pub usage_path: String,
+}
+
+impl Default for Paths {
+    fn default() -> Self {
+        Paths {
+            static_path: "static".to_string(),
+            database_path: "database.json".to_string(),
+            plist_storage: "plist_storage".to_string(),
+            dmg_path: "dmg_files".to_string(),
+            usage_path: "usage.jsonl".to_string(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 pub struct WebServer {
     pub port: u16,
     pub ssl_port: Option<u16>,
@@ -76,36 +191,633 @@ pub struct WebServer {
     pub ssl_key: Option<String>,
 }
 
+impl Default for WebServer {
+    fn default() -> Self {
+        WebServer {
+            port: 8080,
+            ssl_port: None,
+            host: "0.0.0.0".to_string(),
+            ssl_cert: None,
+            ssl_key: None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 pub struct Extra {
     pub allowed_subnet: String,
     pub netmuxd_address: Option<String>,
 }
 
+impl Default for Extra {
+    fn default() -> Self {
+        Extra {
+            allowed_subnet: "0.0.0.0/0".to_string(),
+            netmuxd_address: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Install {
+    pub upload_path: String,
+    /// The maximum IPA size in megabytes
+    pub max_ipa_size: u64,
+    /// Hours before an unfinished upload session is discarded
+    pub session_lifetime: u64,
+}
+
+impl Default for Install {
+    fn default() -> Self {
+        Install {
+            upload_path: "uploads".to_string(),
+            max_ipa_size: 4096,
+            session_lifetime: 24,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Auth {
+    /// Whether devices without a token can be identified by their IP
+    pub ip_fallback: bool,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Auth { ip_fallback: true }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Admin {
+    /// The bearer token for the admin API, the API is disabled without one
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    Json,
+    Sqlite,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Storage {
+    pub backend: StorageBackend,
+    pub sqlite_path: String,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage {
+            backend: StorageBackend::Json,
+            sqlite_path: "database.sqlite".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Encryption {
+    /// The pairing record key as hex
+    pub key: Option<String>,
+    /// A file holding the pairing record key as hex
+    pub key_file: Option<String>,
+    pub record_path: String,
+    /// Minutes a decrypted copy is left in plist_storage
+    pub copy_lifetime: u64,
+}
+
+impl Default for Encryption {
+    fn default() -> Self {
+        Encryption {
+            key: None,
+            key_file: None,
+            record_path: "pairing_records".to_string(),
+            copy_lifetime: 5,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Pairing {
+    /// Minutes a pair code can be used for
+    pub code_lifetime: u64,
+    /// Failed follow ups allowed per code, and per IP
+    pub max_attempts: usize,
+}
+
+impl Default for Pairing {
+    fn default() -> Self {
+        Pairing {
+            code_lifetime: 10,
+            max_attempts: 5,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Maintenance {
+    /// Days a device can go unseen before it is unregistered, 0 keeps devices forever
+    pub retention_days: u64,
+    /// Minutes between maintenance runs
+    pub interval: u64,
+    /// Days usage events are kept before they are rolled up, 0 keeps every event
+    pub usage_retention_days: u64,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Maintenance {
+            retention_days: 28,
+            interval: 60,
+            usage_retention_days: 90,
+        }
+    }
+}
+
 impl Config {
-    pub fn load() -> Config {
-        let config_path = "config.toml";
-        match std::fs::read_to_string(config_path) {
-            Ok(contents) => {
-                let config: Config = match toml::from_str(&contents) {
-                    Ok(c) => c,
-                    Err(e) => panic!("Error parsing config: {}", e),
-                };
-                config
+    /// Builds the config from the defaults, then the file if there is one, then the
+    /// environment, with relative paths resolved from `data_dir` if there is one.
+    /// Every problem found is returned together, so they can all be fixed at once.
+    pub fn load(path: &Path, data_dir: Option<&Path>) -> Result<Config, Vec<ConfigProblem>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            // An empty file is all defaults
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                eprintln!(
+                    "{} doesn't exist, using the defaults and environment",
+                    path.display()
+                );
+                String::new()
             }
             Err(e) => {
-                println!("Could not read config file: {}", e);
-                match e.kind() {
-                    std::io::ErrorKind::NotFound => {
-                        println!("Creating default config file");
-                        let default = DEFAULT.to_string();
-                        let mut file = File::create(config_path).unwrap();
-                        file.write_all(default.as_bytes()).unwrap();
-                        Config::load()
+                return Err(vec![ConfigProblem {
+                    line: None,
+                    var: None,
+                    key: String::new(),
+                    message: format!("can't be read ({})", e),
+                }]);
+            }
+        };
+        let mut config: Config = match toml::from_str(&contents) {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(vec![ConfigProblem {
+                    line: e.span().map(|span| line_at(&contents, span.start)),
+                    var: None,
+                    key: String::new(),
+                    message: e.message().to_string(),
+                }])
+            }
+        };
+        let vars: HashMap<String, String> = std::env::vars()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        let overridden = config.apply_env(&vars)?;
+        if let Some(data_dir) = data_dir {
+            config.resolve_paths(data_dir);
+        }
+        let problems = config.validate(&contents, &overridden);
+        match problems.is_empty() {
+            true => Ok(config),
+            false => Err(problems),
+        }
+    }
+
+    /// Loads the config, or prints everything wrong with it and exits.
+    pub fn load_or_exit(path: &Path, data_dir: Option<&Path>) -> Config {
+        match Config::load(path, data_dir) {
+            Ok(config) => config,
+            Err(problems) => {
+                print_problems(path, &problems);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /// Overrides keys with the environment variables named after them,
+    /// returning the keys that were overridden and the variable each came from.
+    fn apply_env(
+        &mut self,
+        vars: &HashMap<String, String>,
+    ) -> Result<BTreeMap<String, String>, Vec<ConfigProblem>> {
+        let mut o = Overrides {
+            vars,
+            applied: BTreeMap::new(),
+            problems: vec![],
+        };
+        o.set("paths", "static_path", &mut self.paths.static_path);
+        o.set("paths", "database_path", &mut self.paths.database_path);
+        o.set("paths", "plist_storage", &mut self.paths.plist_storage);
+        o.set("paths", "dmg_path", &mut self.paths.dmg_path);
+        o.set("paths", "usage_path", &mut self.paths.usage_path);
+        o.set("storage", "backend", &mut self.storage.backend);
+        o.set("storage", "sqlite_path", &mut self.storage.sqlite_path);
+        o.set("web_server", "port", &mut self.web_server.port);
+        o.set("web_server", "ssl_port", &mut self.web_server.ssl_port);
+        o.set("web_server", "host", &mut self.web_server.host);
+        o.set("web_server", "ssl_cert", &mut self.web_server.ssl_cert);
+        o.set("web_server", "ssl_key", &mut self.web_server.ssl_key);
+        o.set("extra", "allowed_subnet", &mut self.extra.allowed_subnet);
+        o.set("extra", "netmuxd_address", &mut self.extra.netmuxd_address);
+        o.set("install", "upload_path", &mut self.install.upload_path);
+        o.set("install", "max_ipa_size", &mut self.install.max_ipa_size);
+        o.set(
+            "install",
+            "session_lifetime",
+            &mut self.install.session_lifetime,
+        );
+        o.set("auth", "ip_fallback", &mut self.auth.ip_fallback);
+        o.set("admin", "token", &mut self.admin.token);
+        o.set("encryption", "key", &mut self.encryption.key);
+        o.set("encryption", "key_file", &mut self.encryption.key_file);
+        o.set(
+            "encryption",
+            "record_path",
+            &mut self.encryption.record_path,
+        );
+        o.set(
+            "encryption",
+            "copy_lifetime",
+            &mut self.encryption.copy_lifetime,
+        );
+        o.set("pairing", "code_lifetime", &mut self.pairing.code_lifetime);
+        o.set("pairing", "max_attempts", &mut self.pairing.max_attempts);
+        o.set(
+            "maintenance",
+            "retention_days",
+            &mut self.maintenance.retention_days,
+        );
+        o.set("maintenance", "interval", &mut self.maintenance.interval);
+        o.set(
+            "maintenance",
+            "usage_retention_days",
+            &mut self.maintenance.usage_retention_days,
+        );
+
+        // Catch typos, which would otherwise be ignored without a word
+        for name in vars.keys() {
+            if name.contains("__") && !o.applied.values().any(|var| var == name) {
+                o.problems.push(ConfigProblem {
+                    line: None,
+                    var: Some(name.clone()),
+                    key: String::new(),
+                    message: "isn't named after a config key".to_string(),
+                });
+            }
+        }
+        match o.problems.is_empty() {
+            true => Ok(o.applied),
+            false => Err(o.problems),
+        }
+    }
+
+    /// The config as TOML with its secrets replaced, to check what the server will use.
+    pub fn redacted(&self) -> String {
+        let mut value = toml::Value::try_from(self).unwrap();
+        for (section, key) in SECRETS {
+            if let Some(secret) = value.get_mut(section).and_then(|s| s.get_mut(key)) {
+                *secret = toml::Value::String("<redacted>".to_string());
+            }
+        }
+        toml::to_string(&value).unwrap()
+    }
+
+    /// Makes every relative path in the config relative to `base` instead of the working
+    /// directory.
+    fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |path: &mut String| {
+            if Path::new(path.as_str()).is_relative() {
+                *path = base.join(path.as_str()).to_string_lossy().into_owned();
+            }
+        };
+        resolve(&mut self.paths.static_path);
+        resolve(&mut self.paths.database_path);
+        resolve(&mut self.paths.plist_storage);
+        resolve(&mut self.paths.dmg_path);
+        resolve(&mut self.paths.usage_path);
+        resolve(&mut self.storage.sqlite_path);
+        resolve(&mut self.install.upload_path);
+        resolve(&mut self.encryption.record_path);
+        for path in [
+            &mut self.web_server.ssl_cert,
+            &mut self.web_server.ssl_key,
+            &mut self.encryption.key_file,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            resolve(path);
+        }
+    }
+
+    /// Looks for settings that would only fail once the server is running.
+    fn validate(
+        &self,
+        contents: &str,
+        overridden: &BTreeMap<String, String>,
+    ) -> Vec<ConfigProblem> {
+        let mut v = Validator {
+            contents,
+            overridden,
+            problems: vec![],
+        };
+
+        let addr = format!("{}:{}", self.web_server.host, self.web_server.port);
+        if addr.parse::<SocketAddr>().is_err() {
+            v.problem("web_server", "host", "isn't an IP address to listen on");
+        }
+        if let Some(ssl_port) = self.web_server.ssl_port {
+            if ssl_port == self.web_server.port {
+                v.problem("web_server", "ssl_port", "is the same as port");
+            }
+            for (key, path) in [
+                ("ssl_cert", &self.web_server.ssl_cert),
+                ("ssl_key", &self.web_server.ssl_key),
+            ] {
+                match path {
+                    Some(path) => {
+                        if let Err(e) = File::open(path) {
+                            v.problem("web_server", key, format!("can't be read ({})", e));
+                        }
                     }
-                    _ => panic!("This is a fatal error."),
+                    None => v.problem("web_server", key, "must be set to use ssl_port"),
                 }
             }
         }
+
+        let probe = match self.extra.allowed_subnet.contains(':') {
+            true => "::1",
+            false => "127.0.0.1",
+        };
+        if iface_in_subnet(probe, &self.extra.allowed_subnet).is_err() {
+            v.problem("extra", "allowed_subnet", "isn't a subnet like 10.7.0.0/16");
+        }
+        if let Some(address) = &self.extra.netmuxd_address {
+            // Anything that isn't an IP and port is used as a Unix socket path
+            if address.parse::<SocketAddr>().is_err() {
+                if address.contains(':') {
+                    v.problem(
+                        "extra",
+                        "netmuxd_address",
+                        "isn't an IP and port like 127.0.0.1:27015 or a socket path",
+                    );
+                } else if let Err(e) = check_parent(address) {
+                    v.problem("extra", "netmuxd_address", e);
+                }
+            }
+        }
+
+        v.dir("paths", "plist_storage", &self.paths.plist_storage);
+        v.dir("paths", "dmg_path", &self.paths.dmg_path);
+        v.file("paths", "usage_path", &self.paths.usage_path);
+        match self.storage.backend {
+            StorageBackend::Json => v.file("paths", "database_path", &self.paths.database_path),
+            StorageBackend::Sqlite => v.file("storage", "sqlite_path", &self.storage.sqlite_path),
+        }
+        v.dir("install", "upload_path", &self.install.upload_path);
+        if self.install.max_ipa_size == 0 {
+            v.problem("install", "max_ipa_size", "must be more than 0");
+        }
+
+        let encryption = &self.encryption;
+        match (&encryption.key, &encryption.key_file) {
+            (Some(_), Some(_)) => v.problem("encryption", "key", "can't be set with key_file"),
+            (Some(key), None) => {
+                if hex::decode(key.trim()).map_or(true, |key| key.len() != 32) {
+                    v.problem("encryption", "key", "must be 64 hex characters");
+                }
+            }
+            (None, Some(key_file)) => {
+                if let Err(e) = check_parent(key_file) {
+                    v.problem("encryption", "key_file", e);
+                }
+            }
+            (None, None) => {}
+        }
+        if encryption.key.is_some() || encryption.key_file.is_some() {
+            v.dir("encryption", "record_path", &encryption.record_path);
+        }
+
+        if self.pairing.code_lifetime == 0 {
+            v.problem("pairing", "code_lifetime", "must be more than 0");
+        }
+        if self.pairing.max_attempts == 0 {
+            v.problem("pairing", "max_attempts", "must be more than 0");
+        }
+        let usage_days = self.maintenance.usage_retention_days;
+        if usage_days != 0 && usage_days < usage::MIN_RETENTION_DAYS {
+            v.problem(
+                "maintenance",
+                "usage_retention_days",
+                format!(
+                    "must be 0 or at least {}, the census looks back four weeks",
+                    usage::MIN_RETENTION_DAYS
+                ),
+            );
+        }
+
+        v.problems
     }
 }
+
+/// Something wrong with the config, found before the server starts.
+#[derive(Debug)]
+pub struct ConfigProblem {
+    /// The line the setting is on, if it is in the file
+    pub line: Option<usize>,
+    /// The environment variable the setting came from, if it didn't come from the file
+    pub var: Option<String>,
+    /// Like `web_server.ssl_cert`, empty when the problem is with the whole file
+    pub key: String,
+    pub message: String,
+}
+
+/// Prints every problem as `path:line: key message`, like a compiler would.
+pub fn print_problems(path: &Path, problems: &[ConfigProblem]) {
+    eprintln!("Found {} problems with the config:", problems.len());
+    for problem in problems {
+        let location = match (&problem.var, problem.line) {
+            (Some(var), _) => var.clone(),
+            (None, Some(line)) => format!("{}:{}", path.display(), line),
+            (None, None) => path.display().to_string(),
+        };
+        match problem.key.is_empty() {
+            true => eprintln!("  {}: {}", location, problem.message),
+            false => eprintln!("  {}: {} {}", location, problem.key, problem.message),
+        }
+    }
+}
+
+/// Writes the default config, creating its directory if needed.
+/// An existing config is only replaced with `force`.
+pub fn write_default(path: &Path, force: bool) -> std::io::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).truncate(true);
+    match force {
+        true => options.create(true),
+        false => options.create_new(true),
+    };
+    let mut file = options.open(path)?;
+    file.write_all(DEFAULT.as_bytes())
+}
+
+/// The environment variable that overrides a key, like `JITSTREAMER_WEB_SERVER__PORT`.
+pub fn env_var(section: &str, key: &str) -> String {
+    format!(
+        "{}{}__{}",
+        ENV_PREFIX,
+        section.to_uppercase(),
+        key.to_uppercase()
+    )
+}
+
+/// Environment variables have no types, so a value is read as a string first,
+/// then as a TOML value for numbers and booleans.
+fn parse_env<T: DeserializeOwned>(raw: &str) -> Result<T, toml::de::Error> {
+    let as_string = toml::Value::String(raw.to_string()).try_into();
+    if as_string.is_ok() {
+        return as_string;
+    }
+    match toml::from_str::<toml::Table>(&format!("value = {}", raw)) {
+        Ok(mut table) => table.remove("value").unwrap().try_into(),
+        Err(_) => as_string,
+    }
+}
+
+/// Where the config is read from, `config.toml` in the data directory unless one is given.
+pub fn config_path(config: Option<PathBuf>, data_dir: Option<&Path>) -> PathBuf {
+    match (config, data_dir) {
+        (Some(config), _) => config,
+        (None, Some(data_dir)) => data_dir.join("config.toml"),
+        (None, None) => PathBuf::from("config.toml"),
+    }
+}
+
+struct Validator<'a> {
+    contents: &'a str,
+    overridden: &'a BTreeMap<String, String>,
+    problems: Vec<ConfigProblem>,
+}
+
+impl Validator<'_> {
+    fn problem(&mut self, section: &str, key: &str, message: impl Into<String>) {
+        let name = format!("{}.{}", section, key);
+        let var = self.overridden.get(&name).cloned();
+        let line = match var {
+            Some(_) => None,
+            None => line_of(self.contents, section, key),
+        };
+        self.problems.push(ConfigProblem {
+            line,
+            var,
+            key: name,
+            message: message.into(),
+        });
+    }
+
+    /// Checks a directory can be written to, creating it if it doesn't exist yet.
+    fn dir(&mut self, section: &str, key: &str, path: &str) {
+        if let Err(e) = check_dir(Path::new(path)) {
+            self.problem(section, key, e);
+        }
+    }
+
+    /// Checks a file can be written to, or created if it doesn't exist yet.
+    fn file(&mut self, section: &str, key: &str, path: &str) {
+        let res = match Path::new(path).exists() {
+            true => OpenOptions::new()
+                .append(true)
+                .open(path)
+                .map(|_| ())
+                .map_err(|e| format!("isn't writable ({})", e)),
+            false => check_parent(path),
+        };
+        if let Err(e) = res {
+            self.problem(section, key, e);
+        }
+    }
+}
+
+struct Overrides<'a> {
+    vars: &'a HashMap<String, String>,
+    /// The keys overridden so far, mapped to the variable each came from
+    applied: BTreeMap<String, String>,
+    problems: Vec<ConfigProblem>,
+}
+
+impl Overrides<'_> {
+    fn set<T: DeserializeOwned>(&mut self, section: &str, key: &str, field: &mut T) {
+        let var = env_var(section, key);
+        let raw = match self.vars.get(&var) {
+            Some(raw) => raw,
+            None => return,
+        };
+        let key = format!("{}.{}", section, key);
+        match parse_env(raw) {
+            Ok(value) => *field = value,
+            Err(e) => self.problems.push(ConfigProblem {
+                line: None,
+                var: Some(var.clone()),
+                key: key.clone(),
+                message: format!("isn't valid ({})", e.message()),
+            }),
+        }
+        self.applied.insert(key, var);
+    }
+}
+
+fn check_dir(path: &Path) -> Result<(), String> {
+    std::fs::create_dir_all(path).map_err(|e| format!("can't be created ({})", e))?;
+    let probe = path.join(".jitstreamer-write-test");
+    std::fs::write(&probe, b"").map_err(|e| format!("isn't writable ({})", e))?;
+    let _ = std::fs::remove_file(probe);
+    Ok(())
+}
+
+/// Checks the directory a file will be created in can be written to.
+fn check_parent(path: &str) -> Result<(), String> {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => check_dir(parent),
+        _ => check_dir(Path::new(".")),
+    }
+}
+
+/// Finds the line a setting is on, so problems can point at it.
+fn line_of(contents: &str, section: &str, key: &str) -> Option<usize> {
+    let mut current = "";
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            current = name.trim();
+        } else if current == section {
+            match line.split_once('=') {
+                Some((name, _)) if name.trim() == key => return Some(i + 1),
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+/// The line a byte offset into the file falls on.
+fn line_at(contents: &str, offset: usize) -> usize {
+    contents
+        .get(..offset)
+        .unwrap_or(contents)
+        .matches('\n')
+        .count()
+        + 1
+}
@@ -0,0 +1,283 @@
+// jkcoxson
+
+use rusty_libimobiledevice::error::{
+    AfcError, DebugServerError, InstProxyError, LockdowndError, MobileImageMounterError,
+};
+use serde::Serialize;
+use std::net::AddrParseError;
+use thiserror::Error;
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+
+use crate::messages::{
+    DETACH, INSTALL_APP, LOOKUP_APPS, MOUNTING, MOUNTING_DEFERRED, PAIRING_TEST, START_AFC,
+    START_DEBUG_SERVER, START_INSTPROXY, UPLOAD_IPA,
+};
+use crate::pairing_record::PairingRecordError;
+
+/// Everything that can go wrong talking to a device or managing clients.
+/// The display text is what gets shown to the user, the causes are kept for the logs.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Unable to get IP address")]
+    NoAddress,
+    #[error("Address not allowed, connect to the VLAN")]
+    IpNotAllowed,
+    #[error("Invalid device token, register your device again")]
+    InvalidToken,
+    #[error("This server requires a device token, update your shortcut")]
+    TokenRequired,
+    #[error("Unable to parse ip")]
+    InvalidIp(#[from] AddrParseError),
+    #[error(
+        "{0} devices are registered from this IP, choose one with the \
+         x-jitstreamer-udid header or the udid query parameter"
+    )]
+    AmbiguousDevice(usize),
+    #[error("The token belongs to a different device than the one chosen")]
+    DeviceMismatch,
+    #[error("Your device is not registered with JitStreamer")]
+    NotRegistered,
+    #[error("Invalid code")]
+    InvalidCode,
+    #[error("Too many failed pairing attempts, wait a few minutes and try again")]
+    TooManyAttempts,
+    #[error("Unable to save pairing file: {0}")]
+    PairingFile(#[source] std::io::Error),
+    #[error("Invalid pairing file, {0}")]
+    InvalidPairingRecord(#[from] PairingRecordError),
+    #[error("Invalid pairing record key, {0}")]
+    PairingKey(String),
+    #[error("Unable to decrypt the pairing record for {0}, is the key right?")]
+    PairingDecrypt(String),
+    #[error("This device is already being paired, try again in a moment")]
+    PairingInProgress,
+    #[error("{}", PAIRING_TEST)]
+    PairingTest(LockdowndError),
+
+    #[error("Unable to start lockdown")]
+    Lockdownd(LockdowndError),
+    #[error("Unable to get iOS version")]
+    IosVersion(LockdowndError),
+
+    #[error("{} {:?}", START_INSTPROXY, .0)]
+    StartInstproxy(InstProxyError),
+    #[error("{} {:?}", LOOKUP_APPS, .0)]
+    LookupApps(InstProxyError),
+    #[error("App not found")]
+    AppNotFound,
+    #[error("Unable to get path for bundle identifier")]
+    BundlePath(InstProxyError),
+
+    #[error("{}", START_DEBUG_SERVER)]
+    StartDebugServer(Option<DebugServerError>),
+    /// A debugserver command failed, `step` finishes the sentence "Unable to ..."
+    #[error("Unable to {step}")]
+    DebugCommand {
+        step: &'static str,
+        cause: DebugServerError,
+    },
+    #[error("{}", DETACH)]
+    Detach(DebugServerError),
+
+    #[error("{}", MOUNTING)]
+    Mounting,
+    #[error("{}", MOUNTING_DEFERRED)]
+    MountingDeferred,
+    #[error("The developer disk image failed to mount")]
+    MountFailed,
+    #[error("Replaced by a newer launch request")]
+    LaunchReplaced,
+    #[error("Unable to start mobile_image_mounter")]
+    StartImageMounter(MobileImageMounterError),
+    #[error("Unable to look up images")]
+    LookupImages(MobileImageMounterError),
+    #[error("Image plist in wrong format")]
+    ImagePlist,
+    #[error("Unable to upload developer disk image: {:?}", .0)]
+    UploadImage(MobileImageMounterError),
+    #[error("Unable to mount developer disk image: {:?}", .0)]
+    MountImage(MobileImageMounterError),
+    #[error("Libraries did not contain a DMG for iOS {0}")]
+    NoDmg(String),
+    #[error("Error downloading {what}: {cause}")]
+    Download {
+        what: &'static str,
+        #[source]
+        cause: reqwest::Error,
+    },
+    #[error("Failed to unzip DMG: {0}")]
+    Unzip(#[from] zip::result::ZipError),
+
+    #[error("{} {:?}", START_AFC, .0)]
+    StartAfc(AfcError),
+    #[error("{} {:?}", UPLOAD_IPA, .0)]
+    UploadIpa(AfcError),
+    #[error("{} {:?}", INSTALL_APP, .0)]
+    InstallApp(InstProxyError),
+
+    #[error("The x-content-sha256 header is required")]
+    HashRequired,
+    #[error("Invalid SHA-256 hash")]
+    InvalidHash,
+    #[error("The uploaded app does not match the provided hash")]
+    HashMismatch,
+    #[error("The app is too large, the limit is {0} MB")]
+    UploadTooLarge(u64),
+    #[error("Upload session not found")]
+    UploadNotFound,
+    #[error("Upload session is already receiving data")]
+    UploadBusy,
+    #[error("Upload offset does not match the data received so far")]
+    UploadOffset,
+    #[error("The upload is not complete yet")]
+    UploadIncomplete,
+    #[error("Upload was interrupted")]
+    UploadInterrupted,
+
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("The database is at schema version {0}, which is newer than this JitStreamer")]
+    DatabaseVersion(usize),
+    #[error("Unable to parse the database: {0}")]
+    DatabaseFormat(#[from] serde_json::Error),
+    #[error(
+        "{path} is damaged ({cause}), it may have been half written. \
+         Restore it from a backup, or move it aside to start with an empty database"
+    )]
+    DatabaseDamaged {
+        path: String,
+        #[source]
+        cause: serde_json::Error,
+    },
+    #[error("{0} is locked, is another JitStreamer using the same database?")]
+    DatabaseLocked(String),
+
+    #[error("{context}: {cause}")]
+    Io {
+        context: &'static str,
+        #[source]
+        cause: std::io::Error,
+    },
+    #[error("The task stopped unexpectedly")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+impl Error {
+    /// Wraps an I/O error with what we were trying to do at the time.
+    pub fn io(context: &'static str) -> impl FnOnce(std::io::Error) -> Error {
+        move |cause| Error::Io { context, cause }
+    }
+
+    /// The machine readable code reported by the API for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::NoAddress => ErrorCode::NoAddress,
+            Error::IpNotAllowed => ErrorCode::IpNotAllowed,
+            Error::InvalidToken | Error::TokenRequired | Error::DeviceMismatch => {
+                ErrorCode::Unauthorized
+            }
+            Error::AmbiguousDevice(_) => ErrorCode::AmbiguousDevice,
+            Error::NotRegistered => ErrorCode::NotRegistered,
+            Error::InvalidCode => ErrorCode::InvalidCode,
+            Error::InvalidPairingRecord(_) => ErrorCode::InvalidPairingRecord,
+            Error::TooManyAttempts | Error::PairingInProgress => ErrorCode::TooManyAttempts,
+            Error::InvalidIp(_)
+            | Error::PairingTest(_)
+            | Error::Lockdownd(_)
+            | Error::StartInstproxy(_)
+            | Error::StartDebugServer(_)
+            | Error::StartImageMounter(_)
+            | Error::StartAfc(_) => ErrorCode::DeviceUnreachable,
+            Error::AppNotFound => ErrorCode::AppNotFound,
+            Error::Mounting | Error::MountingDeferred => ErrorCode::Mounting,
+            Error::NoDmg(_) | Error::Download { .. } | Error::Unzip(_) => {
+                ErrorCode::DiskImageUnavailable
+            }
+            Error::HashRequired
+            | Error::InvalidHash
+            | Error::HashMismatch
+            | Error::UploadOffset
+            | Error::UploadIncomplete
+            | Error::UploadInterrupted => ErrorCode::InvalidUpload,
+            Error::UploadTooLarge(_) => ErrorCode::UploadTooLarge,
+            Error::UploadNotFound => ErrorCode::UploadNotFound,
+            Error::UploadBusy => ErrorCode::UploadBusy,
+            Error::PairingFile(_)
+            | Error::Database(_)
+            | Error::DatabaseVersion(_)
+            | Error::DatabaseFormat(_)
+            | Error::DatabaseDamaged { .. }
+            | Error::DatabaseLocked(_)
+            | Error::PairingKey(_)
+            | Error::PairingDecrypt(_)
+            | Error::Io { .. }
+            | Error::Task(_) => ErrorCode::Internal,
+            Error::IosVersion(_)
+            | Error::LookupApps(_)
+            | Error::BundlePath(_)
+            | Error::DebugCommand { .. }
+            | Error::Detach(_)
+            | Error::MountFailed
+            | Error::LaunchReplaced
+            | Error::LookupImages(_)
+            | Error::ImagePlist
+            | Error::UploadImage(_)
+            | Error::MountImage(_)
+            | Error::UploadIpa(_)
+            | Error::InstallApp(_) => ErrorCode::OperationFailed,
+        }
+    }
+}
+
+/// Machine readable reasons a request failed.
+#[derive(Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    NoAddress,
+    IpNotAllowed,
+    Unauthorized,
+    NotRegistered,
+    AmbiguousDevice,
+    InvalidCode,
+    TooManyAttempts,
+    InvalidPairingRecord,
+    Forbidden,
+    InvalidUpload,
+    UploadTooLarge,
+    UploadNotFound,
+    UploadBusy,
+    Mounting,
+    DeviceUnreachable,
+    DiskImageUnavailable,
+    AppNotFound,
+    JobNotFound,
+    OperationFailed,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::NoAddress
+            | ErrorCode::AmbiguousDevice
+            | ErrorCode::InvalidPairingRecord
+            | ErrorCode::InvalidUpload => StatusCode::BAD_REQUEST,
+            ErrorCode::UploadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::IpNotAllowed | ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotRegistered
+            | ErrorCode::InvalidCode
+            | ErrorCode::AppNotFound
+            | ErrorCode::JobNotFound
+            | ErrorCode::UploadNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::UploadBusy => StatusCode::CONFLICT,
+            ErrorCode::Mounting | ErrorCode::DiskImageUnavailable => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ErrorCode::DeviceUnreachable | ErrorCode::OperationFailed => StatusCode::BAD_GATEWAY,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
@@ -55,6 +55,11 @@ impl Heart {
             });
         }
     }
+    /// How many devices have a heartbeat running.
+    pub fn count(&self) -> usize {
+        self.devices.len()
+    }
+
     pub fn kill(&mut self, udid: impl Into<String>) {
         let udid = udid.into();
         info!("Attempting to kill heartbeat for {}", udid);
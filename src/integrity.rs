@@ -0,0 +1,94 @@
+// jkcoxson
+
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+use utoipa::ToSchema;
+
+use crate::{
+    backend::DeserializedClient, error::Error, pairing_record::PairingRecord,
+    pairing_store::PairingStore,
+};
+
+/// How long a record has to be left alone before a repair will remove it.
+pub const ORPHAN_GRACE: Duration = Duration::from_secs(10 * 60);
+
+/// Where the database and the stored pairing records disagree.
+#[derive(Serialize, ToSchema, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrityReport {
+    /// Whether the problems that can be fixed were
+    pub repaired: bool,
+    /// Pairing records no registered device owns.
+    /// If plist_storage is shared with usbmuxd its own records show up here too.
+    pub orphaned_records: Vec<String>,
+    /// Orphaned records a repair removed. Only records JitStreamer encrypted that haven't
+    /// changed in ten minutes are removed, the rest are left for an admin to look at
+    pub removed_records: Vec<String>,
+    /// Registered devices without a pairing record, they have to pair again
+    pub missing_records: Vec<String>,
+    /// Devices registered more than once, a repair keeps the most recently seen
+    pub duplicate_udids: Vec<String>,
+    /// Pairing records that can't be used, they are left for an admin to look at
+    pub invalid_records: Vec<InvalidRecord>,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct InvalidRecord {
+    pub udid: String,
+    pub reason: String,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.orphaned_records.is_empty()
+            && self.missing_records.is_empty()
+            && self.duplicate_udids.is_empty()
+            && self.invalid_records.is_empty()
+    }
+}
+
+/// Compares the registered devices with the pairing records on disk, changing nothing.
+pub fn check(
+    clients: &[DeserializedClient],
+    records: &PairingStore,
+) -> Result<IntegrityReport, Error> {
+    let stored: BTreeSet<String> = records.list()?.into_iter().collect();
+    let mut registered: BTreeMap<&str, usize> = BTreeMap::new();
+    for client in clients {
+        *registered.entry(client.udid.as_str()).or_default() += 1;
+    }
+
+    let mut report = IntegrityReport::default();
+    for udid in &stored {
+        if !registered.contains_key(udid.as_str()) {
+            report.orphaned_records.push(udid.clone());
+        }
+    }
+    for (&udid, &count) in &registered {
+        if count > 1 {
+            report.duplicate_udids.push(udid.to_string());
+        }
+        if !stored.contains(udid) {
+            report.missing_records.push(udid.to_string());
+            continue;
+        }
+        let reason = match records.read(udid) {
+            Ok(bytes) => match PairingRecord::validate(&bytes, Some(udid)) {
+                Ok(record) if record.udid != udid => Some(format!("it belongs to {}", record.udid)),
+                Ok(_) => None,
+                Err(e) => Some(e.to_string()),
+            },
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(reason) = reason {
+            report.invalid_records.push(InvalidRecord {
+                udid: udid.to_string(),
+                reason,
+            });
+        }
+    }
+    Ok(report)
+}
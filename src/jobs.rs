@@ -0,0 +1,285 @@
+// jkcoxson
+
+use log::info;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use utoipa::{IntoParams, ToSchema};
+
+use crate::error::{Error, ErrorCode};
+
+/// How long finished jobs are kept around for polling, in seconds.
+const JOB_RETENTION: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    Mount,
+    Launch,
+    Attach,
+    Install,
+}
+
+#[derive(Serialize, ToSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed { message: String, code: ErrorCode },
+}
+
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct Job {
+    pub id: String,
+    pub udid: String,
+    pub kind: JobKind,
+    /// What the job is operating on, such as a bundle ID, PID or DMG path
+    pub detail: String,
+    #[serde(flatten)]
+    pub state: JobState,
+    pub created: u64,
+    pub started: Option<u64>,
+    pub finished: Option<u64>,
+    /// Whether a failure has already been shown to the user by the status route
+    #[serde(skip)]
+    pub reported: bool,
+}
+
+impl Job {
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, JobState::Queued | JobState::Running)
+    }
+}
+
+/// What the status route reports about a device's developer disk image mount.
+pub struct MountStatus {
+    pub mounting: bool,
+    /// Why the last mount failed, empty while a mount is still running
+    pub message: String,
+    pub pending_launch: Option<String>,
+}
+
+/// Query parameters accepted by routes that can run as a background job.
+#[derive(Deserialize, IntoParams, Default)]
+#[into_params(parameter_in = Query)]
+pub struct AsyncQuery {
+    /// Return a job ID straight away instead of waiting for the operation
+    #[serde(rename = "async", default)]
+    pub run_async: bool,
+}
+
+/// Query parameters accepted by the launch route.
+#[derive(Deserialize, IntoParams, Default)]
+#[into_params(parameter_in = Query)]
+pub struct LaunchQuery {
+    /// Return a job ID straight away instead of waiting for the operation
+    #[serde(rename = "async", default)]
+    pub run_async: bool,
+    /// Launch the app automatically if the developer disk image has to be mounted first.
+    /// The v2 API then answers 202 with the launch job to poll
+    #[serde(default)]
+    pub defer: bool,
+}
+
+/// Query parameters for listing jobs.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct JobListQuery {
+    pub udid: Option<String>,
+}
+
+/// Keeps track of long running device operations so they can be polled.
+#[derive(Default)]
+pub struct Jobs {
+    jobs: HashMap<String, Job>,
+    /// Launches waiting on a mount, keyed by UDID, holding the bundle ID and job ID
+    pending_launches: HashMap<String, (String, String)>,
+}
+
+impl Jobs {
+    pub fn new() -> Self {
+        Self {
+            jobs: HashMap::new(),
+            pending_launches: HashMap::new(),
+        }
+    }
+
+    /// Creates a queued job and returns its ID.
+    pub fn create(
+        &mut self,
+        udid: impl Into<String>,
+        kind: JobKind,
+        detail: impl Into<String>,
+    ) -> String {
+        self.prune();
+        let mut rng = rand::thread_rng();
+        let id = format!("{:032x}", rng.gen::<u128>());
+        let job = Job {
+            id: id.clone(),
+            udid: udid.into(),
+            kind,
+            detail: detail.into(),
+            state: JobState::Queued,
+            created: now(),
+            started: None,
+            finished: None,
+            reported: false,
+        };
+        info!("Created {:?} job {} for {}", job.kind, id, job.udid);
+        self.jobs.insert(id.clone(), job);
+        id
+    }
+
+    pub fn start(&mut self, id: &str) {
+        if let Some(job) = self.jobs.get_mut(id) {
+            job.state = JobState::Running;
+            job.started = Some(now());
+        }
+    }
+
+    pub fn succeed(&mut self, id: &str) {
+        if let Some(job) = self.jobs.get_mut(id) {
+            info!("Job {} succeeded", id);
+            job.state = JobState::Succeeded;
+            job.finished = Some(now());
+        }
+    }
+
+    pub fn fail(&mut self, id: &str, error: &Error) {
+        if let Some(job) = self.jobs.get_mut(id) {
+            info!("Job {} failed: {:?}", id, error);
+            job.state = JobState::Failed {
+                message: error.to_string(),
+                code: error.code(),
+            };
+            job.finished = Some(now());
+        }
+    }
+
+    /// Records the outcome of a job from an operation's result.
+    pub fn finish(&mut self, id: &str, res: &Result<(), Error>) {
+        match res {
+            Ok(_) => self.succeed(id),
+            Err(e) => self.fail(id, e),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.get(id).cloned()
+    }
+
+    /// Returns every job, newest first.
+    pub fn all(&self) -> Vec<Job> {
+        let mut jobs: Vec<Job> = self.jobs.values().cloned().collect();
+        jobs.sort_by(|a, b| b.created.cmp(&a.created));
+        jobs
+    }
+
+    /// Returns every job for a device, newest first.
+    pub fn by_udid(&self, udid: &str) -> Vec<Job> {
+        let mut jobs: Vec<Job> = self
+            .jobs
+            .values()
+            .filter(|j| j.udid == udid)
+            .cloned()
+            .collect();
+        jobs.sort_by(|a, b| b.created.cmp(&a.created));
+        jobs
+    }
+
+    /// Returns the most recent job of a kind for a device.
+    pub fn latest(&self, udid: &str, kind: JobKind) -> Option<Job> {
+        self.jobs
+            .values()
+            .filter(|j| j.udid == udid && j.kind == kind)
+            .max_by_key(|j| j.created)
+            .cloned()
+    }
+
+    /// Whether the device's developer disk image is being mounted.
+    pub fn is_mounting(&self, udid: &str) -> bool {
+        self.latest(udid, JobKind::Mount)
+            .is_some_and(|job| job.is_active())
+    }
+
+    /// Creates and starts a mount job for a device, returning its ID,
+    /// or None if the device is already mounting.
+    pub fn start_mount(&mut self, udid: &str, dmg_path: &str) -> Option<String> {
+        if self.is_mounting(udid) {
+            return None;
+        }
+        let id = self.create(udid, JobKind::Mount, dmg_path);
+        self.start(&id);
+        Some(id)
+    }
+
+    /// Queues a launch to run once the device's mount finishes, under the launch's own job.
+    /// The job goes back to queued until then.
+    /// A device only has one pending launch, so this replaces any earlier one.
+    pub fn defer_launch(&mut self, udid: &str, bundle_id: &str, id: &str) {
+        if let Some(job) = self.jobs.get_mut(id) {
+            job.state = JobState::Queued;
+            job.started = None;
+        }
+        if let Some((_, old)) = self
+            .pending_launches
+            .insert(udid.to_string(), (bundle_id.to_string(), id.to_string()))
+        {
+            if old != id {
+                self.fail(&old, &Error::LaunchReplaced);
+            }
+        }
+    }
+
+    /// Removes and returns the device's pending launch as a bundle ID and job ID.
+    pub fn take_pending_launch(&mut self, udid: &str) -> Option<(String, String)> {
+        self.pending_launches.remove(udid)
+    }
+
+    /// Returns the bundle ID waiting to be launched on a device, if any.
+    pub fn pending_launch(&self, udid: &str) -> Option<String> {
+        self.pending_launches.get(udid).map(|(b, _)| b.clone())
+    }
+
+    /// Reports whether a device is mounting. A failed mount is only reported once.
+    pub fn mount_status(&mut self, udid: &str) -> MountStatus {
+        let pending_launch = self.pending_launch(udid);
+        let (mounting, message) = match self.latest(udid, JobKind::Mount) {
+            Some(job) => match job.state {
+                JobState::Queued | JobState::Running => (true, String::new()),
+                JobState::Failed { message, .. } if !job.reported => {
+                    if let Some(job) = self.jobs.get_mut(&job.id) {
+                        job.reported = true;
+                    }
+                    (true, message)
+                }
+                _ => (false, String::new()),
+            },
+            None => (false, String::new()),
+        };
+        MountStatus {
+            mounting,
+            message,
+            pending_launch,
+        }
+    }
+
+    /// Forgets finished jobs older than the retention period.
+    fn prune(&mut self) {
+        let cutoff = now().saturating_sub(JOB_RETENTION);
+        self.jobs
+            .retain(|_, j| j.is_active() || j.finished.unwrap_or(j.created) >= cutoff);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -2,36 +2,137 @@
 
 pub const SHORTCUT_VERSION: &str = "0.2.0";
 
-use backend::Backend;
-use bytes::BufMut;
-use futures::TryStreamExt;
+use backend::{Backend, DeviceId, Registration, UdidQuery};
+use bytes::{Buf, BufMut};
+use clap::Parser;
+use cli::Command;
+use client::Client;
+use error::Error;
+use futures::{Stream, TryStreamExt};
+use jobs::{AsyncQuery, JobListQuery, LaunchQuery};
 use log::{info, warn};
-use plist_plus::Plist;
-use serde_json::Value;
-use std::{net::SocketAddr, str::FromStr, sync::Arc};
+use metrics::{Outcome, Request};
+use packets::{
+    CensusPacket, JobListPacket, JobStatusPacket, ListAppsPacket, MessagePacket,
+    PotentialPairPacket, StatusPacket, TokenPacket, UploadSessionPacket,
+};
+use pairing_record::PairingRecord;
+use progress::ProgressEvent;
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
 use tokio::{
-    io::AsyncWriteExt,
-    sync::{mpsc, Mutex},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
     time::timeout,
 };
+use uploads::{NewSessionQuery, UploadSession};
+use utoipa::OpenApi;
 use warp::{
     filters::BoxedFilter,
-    http::Uri,
+    http::{StatusCode, Uri},
     multipart::{FormData, Part},
     path::FullPath,
-    redirect, Filter, Rejection, Reply,
+    redirect,
+    reply::Response,
+    Filter, Rejection, Reply,
 };
 
+mod admin;
 mod backend;
+mod cli;
 mod client;
 mod config;
+mod error;
 mod heartbeat;
+mod integrity;
+mod jobs;
+mod maintenance;
 mod messages;
+mod metrics;
 mod netmuxd;
+mod openapi;
 mod packets;
+mod pair_codes;
+mod pairing_record;
+mod pairing_store;
+mod progress;
+mod storage;
+mod tasks;
+mod uploads;
+mod usage;
+mod v2;
 
 #[tokio::main]
 async fn main() {
+    let cli = cli::Cli::parse();
+    let data_dir = cli.data_dir.as_deref();
+    let config_path = config::config_path(cli.config.clone(), data_dir);
+
+    match cli.command() {
+        Command::Serve => {}
+        Command::Init { force } => match config::write_default(&config_path, force) {
+            Ok(_) => {
+                println!("Wrote the default config to {}", config_path.display());
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Unable to write {}: {}", config_path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        Command::PrintDefaultConfig => {
+            print!("{}", config::DEFAULT);
+            std::process::exit(0);
+        }
+        Command::PrintConfig => {
+            let config = config::Config::load_or_exit(&config_path, data_dir);
+            print!("{}", config.redacted());
+            std::process::exit(0);
+        }
+        // Check the config and exit, so it can be tested before a restart
+        Command::CheckConfig => match config::Config::load(&config_path, data_dir) {
+            Ok(_) => {
+                println!("The config is valid");
+                std::process::exit(0);
+            }
+            Err(problems) => {
+                config::print_problems(&config_path, &problems);
+                std::process::exit(1);
+            }
+        },
+        // Encrypt every pairing record under a new key, without starting the server
+        Command::RotateKey => {
+            env_logger::init();
+            let config = config::Config::load_or_exit(&config_path, data_dir);
+            match pairing_store::PairingStore::rotate_key(&config) {
+                Ok(count) => {
+                    println!("Encrypted {} pairing records under the new key", count);
+                    std::process::exit(0);
+                }
+                Err(e) => {
+                    log::error!("Unable to rotate the pairing record key: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        // Compare the database with the pairing records, fixing what it can with --repair
+        Command::CheckIntegrity { repair } => {
+            env_logger::init();
+            let config = config::Config::load_or_exit(&config_path, data_dir);
+            let report =
+                backend::Backend::load(&config).and_then(|mut b| b.check_integrity(repair));
+            match report {
+                Ok(report) => {
+                    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                    std::process::exit(0);
+                }
+                Err(e) => {
+                    log::error!("Unable to check the pairing records: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
     println!("Starting JitStreamer...");
 
     env_logger::init();
@@ -44,10 +145,28 @@ async fn main() {
         std::process::exit(1);
     }
 
-    let config = config::Config::load();
+    let config = config::Config::load_or_exit(&config_path, data_dir);
     let static_dir = config.paths.static_path.clone();
     let current_dir = std::env::current_dir().expect("failed to read current directory");
-    let backend = Arc::new(Mutex::new(backend::Backend::load(&config)));
+    let backend = match backend::Backend::load(&config) {
+        Ok(backend) => Arc::new(Mutex::new(backend)),
+        Err(e) => {
+            log::error!("Unable to load the database: {}", e);
+            std::process::exit(1);
+        }
+    };
+    match backend.lock().await.check_integrity(false) {
+        Ok(report) if !report.is_clean() => warn!(
+            "The pairing records don't match the database ({} orphaned, {} missing, \
+            {} duplicated, {} invalid), see /admin/integrity or run `jit_streamer check-integrity`",
+            report.orphaned_records.len(),
+            report.missing_records.len(),
+            report.duplicate_udids.len(),
+            report.invalid_records.len()
+        ),
+        Ok(_) => {}
+        Err(e) => warn!("Unable to check the pairing records: {}", e),
+    }
     let upload_backend = backend.clone();
     let potential_backend = backend.clone();
     let potential_follow_up_backend = backend.clone();
@@ -55,9 +174,22 @@ async fn main() {
     let list_apps_backend = backend.clone();
     let shortcuts_launch_backend = backend.clone();
     let shortcuts_unregister_backend = backend.clone();
+    let token_backend = backend.clone();
     let attach_backend = backend.clone();
     let census_backend = backend.clone();
+    let metrics_backend = backend.clone();
     let install_app_backend = backend.clone();
+    let install_session_create_backend = backend.clone();
+    let install_session_status_backend = backend.clone();
+    let install_session_upload_backend = backend.clone();
+    let install_session_finish_backend = backend.clone();
+    let job_backend = backend.clone();
+    let jobs_backend = backend.clone();
+    let progress_backend = backend.clone();
+    let v2_backend = backend.clone();
+    let admin_backend = backend.clone();
+    let maintenance_backend = backend.clone();
+    let pair_codes_backend = backend.clone();
 
     let cors = warp::cors().allow_any_origin();
 
@@ -65,14 +197,8 @@ async fn main() {
     let status_route = warp::path("status")
         .and(warp::get())
         .and(warp::filters::addr::remote())
-        .and_then(move |addr| status(addr, status_backend.clone()));
-
-    // Admin route
-    let admin_route = warp::path("admin").map(|| {
-        warp::redirect(Uri::from_static(
-            "https://www.youtube.com/watch?v=dQw4w9WgXcQ", // haha gottem
-        ))
-    });
+        .and(device_id())
+        .and_then(move |addr, device| status(addr, device, status_backend.clone()));
 
     // Upload route
     let upload_route = warp::path("upload")
@@ -93,8 +219,16 @@ async fn main() {
         .and(warp::post())
         .and(warp::body::content_length_limit(1024 * 1024 * 10))
         .and(warp::body::bytes())
-        .and_then(move |code: u16, bytes: bytes::Bytes| {
-            potential_follow_up(bytes, code, potential_follow_up_backend.clone())
+        .and(warp::query::<UdidQuery>())
+        .and(warp::filters::addr::remote())
+        .and_then(move |code: u16, bytes: bytes::Bytes, query, addr| {
+            potential_follow_up(
+                bytes,
+                code,
+                query,
+                addr,
+                potential_follow_up_backend.clone(),
+            )
         });
 
     // Version route
@@ -107,43 +241,160 @@ async fn main() {
         .and(warp::get())
         .and_then(move || census(census_backend.clone()));
 
+    // Prometheus metrics route
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .and_then(move || prometheus_metrics(metrics_backend.clone()));
+
+    // OpenAPI document route
+    let openapi_route = warp::path("openapi.json")
+        .and(warp::get())
+        .and_then(openapi_json);
+
     // Shortcuts route
     let list_apps_route = warp::path!("shortcuts" / "list_apps")
         .and(warp::get())
         .and(warp::filters::addr::remote())
-        .and_then(move |addr| list_apps(addr, list_apps_backend.clone()));
+        .and(device_id())
+        .and_then(move |addr, device| list_apps(addr, device, list_apps_backend.clone()));
 
     let shortcuts_launch_route = warp::path!("shortcuts" / "launch" / String)
         .and(warp::post())
         .and(warp::filters::addr::remote())
-        .and_then(move |query, addr| shortcuts_run(query, addr, shortcuts_launch_backend.clone()));
+        .and(device_id())
+        .and(warp::query::<LaunchQuery>())
+        .and_then(move |app, addr, device, query| {
+            shortcuts_run(app, addr, device, query, shortcuts_launch_backend.clone())
+        });
 
     let unregister_route = warp::path!("shortcuts" / "unregister")
         .and(warp::post())
         .and(warp::filters::addr::remote())
-        .and_then(move |addr| shortcuts_unregister(addr, shortcuts_unregister_backend.clone()));
+        .and(device_id())
+        .and_then(move |addr, device| {
+            shortcuts_unregister(addr, device, shortcuts_unregister_backend.clone())
+        });
+
+    let token_route = warp::path("token")
+        .and(warp::post())
+        .and(warp::filters::addr::remote())
+        .and(device_id())
+        .and_then(move |addr, device| rotate_token(addr, device, token_backend.clone()));
 
     let attach_route = warp::path!("attach" / u16)
         .and(warp::post())
         .and(warp::filters::addr::remote())
-        .and_then(move |code: u16, addr| attach_debugger(code, addr, attach_backend.clone()));
+        .and(device_id())
+        .and(warp::query::<AsyncQuery>())
+        .and_then(move |code: u16, addr, device, query| {
+            attach_debugger(code, addr, device, query, attach_backend.clone())
+        });
+
+    // Job routes
+    let job_route = warp::path!("jobs" / String)
+        .and(warp::get())
+        .and_then(move |id| get_job(id, job_backend.clone()));
+
+    let jobs_route = warp::path!("jobs")
+        .and(warp::get())
+        .and(warp::filters::addr::remote())
+        .and(device_id())
+        .and(warp::query::<JobListQuery>())
+        .and_then(move |addr, device, query| list_jobs(addr, device, query, jobs_backend.clone()));
+
+    // Progress route
+    let progress_route = warp::path!("progress" / String)
+        .and(warp::get())
+        .and(warp::filters::addr::remote())
+        .and(device_id())
+        .and_then(move |udid, addr, device| {
+            progress_stream(udid, addr, device, progress_backend.clone())
+        })
+        .with(warp::cors().allow_any_origin());
 
     let netmuxd_route = warp::path("netmuxd")
         .and(warp::post())
         .and(warp::filters::addr::remote())
-        .and_then(move |addr| netmuxd_connect(addr, backend.clone()));
+        .and(device_id())
+        .and_then(move |addr, device| netmuxd_connect(addr, device, backend.clone()));
 
     let install_app_route = warp::path!("install" / "app")
         .and(warp::post())
         .and(warp::filters::addr::remote())
-        .and(warp::body::content_length_limit(1024 * 1024 * 10))
-        .and(warp::body::bytes())
-        .and_then(move |addr, bytes: bytes::Bytes| {
-            install_app(addr, install_app_backend.clone(), bytes)
+        .and(device_id())
+        .and(warp::header::optional::<String>("x-content-sha256"))
+        .and(warp::query::<AsyncQuery>())
+        .and(warp::body::stream())
+        .and_then(move |addr, device, sha256, query, body| {
+            install_app(
+                addr,
+                device,
+                sha256,
+                query,
+                body,
+                install_app_backend.clone(),
+            )
+        });
+
+    let install_session_create_route = warp::path!("install" / "session")
+        .and(warp::post())
+        .and(warp::filters::addr::remote())
+        .and(device_id())
+        .and(warp::query::<NewSessionQuery>())
+        .and_then(move |addr, device, query| {
+            install_session_create(addr, device, query, install_session_create_backend.clone())
+        });
+
+    let install_session_status_route = warp::path!("install" / "session" / String)
+        .and(warp::get())
+        .and(warp::filters::addr::remote())
+        .and(device_id())
+        .and_then(move |id, addr, device| {
+            install_session_status(id, addr, device, install_session_status_backend.clone())
+        });
+
+    let install_session_upload_route = warp::path!("install" / "session" / String)
+        .and(warp::put())
+        .and(warp::filters::addr::remote())
+        .and(device_id())
+        .and(warp::header::<u64>("upload-offset"))
+        .and(warp::body::stream())
+        .and_then(move |id, addr, device, offset, body| {
+            install_session_upload(
+                id,
+                addr,
+                device,
+                offset,
+                body,
+                install_session_upload_backend.clone(),
+            )
+        });
+
+    let install_session_finish_route = warp::path!("install" / "session" / String / "finish")
+        .and(warp::post())
+        .and(warp::filters::addr::remote())
+        .and(device_id())
+        .and(warp::query::<AsyncQuery>())
+        .and_then(move |id, addr, device, query| {
+            install_session_finish(
+                id,
+                addr,
+                device,
+                query,
+                install_session_finish_backend.clone(),
+            )
         });
 
+    // The v2 and admin APIs are matched before the trailing slash redirect so POSTs go through,
+    // and so are the metrics so scrapers don't have to follow a redirect
+    let v2_routes = v2::routes(v2_backend);
+    let admin_routes = admin::routes(admin_backend, config.admin.token.clone());
+
     // Assemble routes for service
-    let routes = root_redirect()
+    let routes = v2_routes
+        .or(admin_routes)
+        .or(metrics_route)
+        .or(root_redirect())
         .or(warp::fs::dir(current_dir.join(static_dir)))
         .or(status_route)
         .or(upload_route)
@@ -154,12 +405,28 @@ async fn main() {
         .or(attach_route)
         .or(netmuxd_route)
         .or(install_app_route)
+        .or(install_session_create_route)
+        .or(install_session_status_route)
+        .or(install_session_upload_route)
+        .or(install_session_finish_route)
+        .or(job_route)
+        .or(jobs_route)
+        .or(progress_route)
         .or(version_route)
         .or(census_route)
+        .or(openapi_route)
         .or(unregister_route)
-        .or(admin_route);
+        .or(token_route);
     let ssl_routes = routes.clone();
 
+    tokio::spawn(maintenance::sweep_pair_codes(pair_codes_backend));
+    let records = backend.lock().await.shared.records.clone();
+    tokio::spawn(maintenance::sweep_pairing_copies(records));
+    tokio::spawn(maintenance::run(
+        maintenance_backend,
+        std::time::Duration::from_secs(config.maintenance.interval.max(1) * 60),
+    ));
+
     let addr: std::net::SocketAddr =
         format!("{}:{}", config.web_server.host, config.web_server.port)
             .parse()
@@ -186,6 +453,18 @@ async fn main() {
     warp::serve(routes).run(addr).await;
 }
 
+/// Extracts the device token and UDID selector a request was sent with, if any.
+/// The UDID can also be sent as a query parameter, for shortcuts that can't set headers.
+pub(crate) fn device_id() -> impl Filter<Extract = (DeviceId,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(backend::TOKEN_HEADER)
+        .and(warp::header::optional::<String>(backend::UDID_HEADER))
+        .and(warp::query::<UdidQuery>())
+        .map(|token, udid: Option<String>, query: UdidQuery| DeviceId {
+            token,
+            udid: udid.or(query.udid),
+        })
+}
+
 fn root_redirect() -> BoxedFilter<(impl Reply,)> {
     warp::path::full()
         .and_then(move |path: FullPath| async move {
@@ -204,25 +483,119 @@ fn root_redirect() -> BoxedFilter<(impl Reply,)> {
         .boxed()
 }
 
+#[utoipa::path(
+    get,
+    path = "/version/",
+    tag = "v1",
+    responses(
+        (
+            status = 200,
+            description = "The shortcut version this server expects",
+            body = String,
+            content_type = "text/plain"
+        )
+    )
+)]
 async fn version_route() -> Result<impl Reply, Rejection> {
     Ok(SHORTCUT_VERSION)
 }
 
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    tag = "v1",
+    responses(
+        (status = 200, description = "This document", content_type = "application/json")
+    )
+)]
+async fn openapi_json() -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&openapi::ApiDoc::openapi()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/census/",
+    tag = "v1",
+    responses(
+        (
+            status = 200,
+            description = "Usage counters since startup",
+            body = CensusPacket,
+            content_type = "text/plain"
+        )
+    )
+)]
 async fn census(backend: Arc<Mutex<Backend>>) -> Result<impl Reply, Rejection> {
     let lock = backend.lock().await;
+    let counter = lock.counter.clone();
+    let clients = lock.deserialized_clients.len();
+    let usage = lock.shared.usage.clone();
+    drop(lock);
     Ok(packets::census_response(
-        lock.counter.clone(),
-        lock.deserialized_clients.len(),
+        counter,
+        clients,
         SHORTCUT_VERSION.to_string(),
+        usage::summarize(&usage),
     ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "v1",
+    responses(
+        (
+            status = 200,
+            description = "Request outcomes, operation latencies and device gauges",
+            body = String,
+            content_type = "text/plain; version=0.0.4"
+        )
+    )
+)]
+async fn prometheus_metrics(backend: Arc<Mutex<Backend>>) -> Result<impl Reply, Rejection> {
+    let lock = backend.lock().await;
+    let heartbeats = match lock.shared.heart.lock() {
+        Ok(heart) => heart.count(),
+        Err(_) => {
+            warn!("Mutex poisoned!!");
+            0
+        }
+    };
+    let body = lock
+        .shared
+        .metrics
+        .render(lock.deserialized_clients.len(), heartbeats);
+    Ok(warp::reply::with_header(
+        body,
+        "content-type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/upload/",
+    tag = "v1",
+    request_body(
+        content = String,
+        content_type = "multipart/form-data",
+        description = "A form with the device's pairing file as an XML or binary plist, \
+                       or a .mobiledevicepairing file named after the device's UDID"
+    ),
+    responses(
+        (
+            status = 200,
+            description = "The device's token, or why it couldn't be registered",
+            body = TokenPacket,
+            content_type = "text/plain"
+        )
+    )
+)]
 async fn upload_file(
     form: FormData,
     address: Option<SocketAddr>,
     backend: Arc<Mutex<Backend>>,
 ) -> Result<impl Reply, Rejection> {
-    let lock = backend.lock().await;
     let parts: Vec<Part> = match form.try_collect().await {
         Ok(parts) => parts,
         Err(_) => return Ok(packets::upload_response(false, "Form error")),
@@ -230,6 +603,7 @@ async fn upload_file(
 
     for p in parts {
         if p.name() == "file" {
+            let filename = p.filename().map(|f| f.to_string());
             let value = match p
                 .stream()
                 .try_fold(Vec::new(), |mut vec, data| {
@@ -242,70 +616,37 @@ async fn upload_file(
                 Err(_) => return Ok(packets::upload_response(false, "File error")),
             };
 
-            // Get string from value
-            let value = match String::from_utf8(value) {
-                Ok(value) => value,
-                Err(_) => {
-                    return Ok(packets::upload_response(false, "Unable to read file"));
-                }
-            };
-            // Attempt to parse it as an Apple Plist
-            let plist: Plist = Plist::from_xml(value.clone()).unwrap();
-            let udid = match plist.dict_get_item("UDID") {
-                Ok(s) => match s.get_string_val() {
-                    Ok(s) => s,
-                    Err(_) => {
-                        return Ok(packets::upload_response(
-                            false,
-                            "Unable to read UDID from Plist",
-                        ));
-                    }
-                },
-                _ => {
-                    return Ok(packets::upload_response(false, "Invalid pairing file!"));
-                }
-            };
             let address = match address {
                 Some(address) => address,
                 None => {
                     return Ok(packets::upload_response(false, "No address provided"));
                 }
             };
-            let plist: Plist = Plist::from_xml(value).unwrap();
-            // Save the plist to the plist storage directory
-            match lock.write_pairing_file(plist.to_string(), &udid) {
-                Ok(_) => {}
-                Err(_) => {
-                    return Ok(packets::upload_response(
-                        false,
-                        "Unable to save pairing file",
-                    ));
-                }
-            }
-            drop(lock);
-            // Make sure that the client is valid before adding it to the backend
-            match backend::Backend::test_new_client(&address.ip().to_string(), &udid).await {
-                Ok(_) => {}
-                Err(_) => {
-                    return Ok(packets::upload_response(
-                        false,
-                        "Device did not respond to pairing test",
-                    ));
-                }
-            }
-            let mut lock = backend.lock().await;
-            match lock.register_client(address.ip().to_string(), udid.clone()) {
-                Ok(_) => {}
-                Err(_) => {
-                    return Ok(packets::upload_response(false, "Client already registered"));
-                }
-            }
-            return Ok(packets::upload_response(true, ""));
+            let udid = filename
+                .as_deref()
+                .and_then(pairing_record::udid_from_filename);
+            return match register_device(&value, udid, address.ip().to_string(), &backend).await {
+                Ok(registration) => Ok(packets::token_response(true, "", &registration.token)),
+                Err(e) => Ok(packets::upload_response(false, &e.to_string())),
+            };
         }
     }
     Ok(packets::upload_response(false, "No file found"))
 }
 
+#[utoipa::path(
+    get,
+    path = "/potential/",
+    tag = "v1",
+    responses(
+        (
+            status = 200,
+            description = "A code to pair this IP from another machine",
+            body = PotentialPairPacket,
+            content_type = "text/plain"
+        )
+    )
+)]
 async fn potential_pair(
     addr: Option<SocketAddr>,
     backend: Arc<Mutex<Backend>>,
@@ -326,336 +667,327 @@ async fn potential_pair(
         ));
     }
 
-    let code = backend.potential_pair(addr.unwrap().to_string());
+    let code = backend.pair_codes.issue(addr.unwrap().to_string());
     info!("A potential pair code was generated: {}", code);
     Ok(packets::potential_pair_response(true, "", code))
 }
 
+#[utoipa::path(
+    post,
+    path = "/potential_follow_up/{code}/",
+    tag = "v1",
+    params(
+        ("code" = u16, Path, description = "The code returned by /potential"),
+        (
+            "udid" = Option<String>,
+            Query,
+            description = "The device's UDID, for pairing files that don't include it"
+        )
+    ),
+    request_body(
+        content = String,
+        content_type = "application/xml",
+        description = "The device's pairing file as an XML or binary plist"
+    ),
+    responses(
+        (
+            status = 200,
+            description = "The device's token, or why it couldn't be registered",
+            body = TokenPacket,
+            content_type = "text/plain"
+        )
+    )
+)]
 async fn potential_follow_up(
     form: bytes::Bytes,
     code: u16,
+    query: UdidQuery,
+    addr: Option<SocketAddr>,
     backend: Arc<Mutex<Backend>>,
 ) -> Result<impl Reply, Rejection> {
-    // Parse form to a string
-    let value = match String::from_utf8(form.to_vec()) {
-        Ok(form) => form,
-        Err(_) => {
-            return Ok(packets::potential_pair_response(false, "Invalid UTF-8", 0));
-        }
-    };
-
-    let mut lock = backend.lock().await;
-    let ip = match lock.check_code(code) {
-        Some(ip) => ip,
+    let source = match addr {
+        Some(addr) => addr.ip().to_string(),
         None => {
-            return Ok(packets::potential_follow_up_response(false, "Invalid code"));
-        }
-    }
-    .split(':')
-    .next()
-    .unwrap()
-    .to_string();
-
-    // Attempt to parse it as an Apple Plist
-    let plist: Plist = Plist::from_xml(value.clone()).unwrap();
-    let udid = match plist.dict_get_item("UDID") {
-        Ok(s) => match s.get_string_val() {
-            Ok(s) => s,
-            Err(_) => {
-                return Ok(packets::upload_response(
-                    false,
-                    "Unable to read UDID from Plist",
-                ));
-            }
-        },
-        _ => {
             return Ok(packets::potential_follow_up_response(
                 false,
-                "Invalid pairing file!",
-            ));
+                "No address provided",
+            ))
         }
     };
-    let plist: Plist = Plist::from_xml(value).unwrap();
-    // Save the plist to the plist storage directory
-    match lock.write_pairing_file(plist.to_string(), &udid) {
-        Ok(_) => {}
-        Err(_) => {
-            return Ok(packets::upload_response(
-                false,
-                "Unable to save pairing file",
-            ));
+    // The code is taken while the pairing file is checked, so it can only be used once
+    let claimed = match backend.lock().await.pair_codes.claim(code, &source) {
+        Ok(claimed) => claimed,
+        Err(e) => return Ok(packets::potential_follow_up_response(false, &e.to_string())),
+    };
+    let ip = claimed.ip.split(':').next().unwrap().to_string();
+
+    match register_device(&form, query.udid.as_deref(), ip, &backend).await {
+        Ok(registration) => Ok(packets::token_response(true, "", &registration.token)),
+        Err(e) => {
+            backend.lock().await.pair_codes.release(claimed, &source);
+            Ok(packets::potential_follow_up_response(false, &e.to_string()))
         }
     }
-    drop(lock);
+}
+
+/// Registers a device from its pairing file.
+/// The record only replaces the saved one once lockdown accepts it, a record that fails
+/// the test is thrown away and whatever was there before is put back.
+pub(crate) async fn register_device(
+    plist: &[u8],
+    udid: Option<&str>,
+    ip: String,
+    backend: &Arc<Mutex<Backend>>,
+) -> Result<Registration, Error> {
+    // Check the pairing record over before anything is saved
+    let record = match PairingRecord::validate(plist, udid) {
+        Ok(record) => record,
+        Err(e) => {
+            warn!("Rejected a pairing file: {}", e);
+            return Err(Error::from(e));
+        }
+    };
+    let udid = record.udid;
+    let records = backend.lock().await.shared.records.clone();
+    records.begin_trial(&udid, record.xml.as_bytes())?;
     // Make sure that the client is valid before adding it to the backend
-    match backend::Backend::test_new_client(&ip, &udid).await {
-        Ok(_) => {}
-        Err(_) => {
-            return Ok(packets::upload_response(false, messages::PAIRING_TEST));
+    if let Err(e) = backend::Backend::test_new_client(&ip, &udid).await {
+        if let Err(e) = records.rollback_trial(&udid) {
+            warn!("{}", e);
         }
+        return Err(e);
+    }
+    if let Err(e) = records.commit_trial(&udid, record.xml.as_bytes()) {
+        warn!("{:?}", e);
+        return Err(e);
     }
     let mut lock = backend.lock().await;
-    match lock.register_client(ip, udid.clone()) {
-        Ok(_) => {}
-        Err(_) => {
-            return Ok(packets::upload_response(false, "Client already registered"));
-        }
+    let registration = lock.register_client(ip.clone(), udid.clone())?;
+    follow_move(&lock, &registration, &ip, &udid);
+    Ok(registration)
+}
+
+/// Points netmuxd at a device's new IP in the background, if it moved.
+fn follow_move(backend: &Backend, registration: &Registration, ip: &str, udid: &str) {
+    if registration.moved_from.is_none() {
+        return;
     }
-    lock.remove_code(code);
-    Ok(packets::upload_response(true, ""))
+    let netmuxd_address = match backend.netmuxd_address.clone() {
+        Some(address) => address,
+        None => return,
+    };
+    let ip = ip.to_string();
+    let udid = udid.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = move_in_netmuxd(ip, udid.clone(), netmuxd_address).await {
+            warn!("Unable to move {} in netmuxd: {}", udid, e);
+        }
+    });
 }
 
+#[utoipa::path(
+    get,
+    path = "/status/",
+    tag = "v1",
+    security((), ("device_token" = [])),
+    params(UdidQuery),
+    responses(
+        (
+            status = 200,
+            description = "Registration and mount state of the calling device",
+            body = StatusPacket,
+            content_type = "text/plain"
+        )
+    )
+)]
 async fn status(
     addr: Option<SocketAddr>,
+    device: DeviceId,
     backend: Arc<Mutex<Backend>>,
 ) -> Result<impl Reply, Rejection> {
     let mut backend = backend.lock().await;
-    if addr.is_none() {
-        return Ok(packets::status_packet(false, false, false, ""));
-    }
-    if !backend.check_ip(addr.unwrap().ip()) {
-        return Ok(packets::status_packet(false, false, false, ""));
-    }
-    match backend.get_by_ip(&addr.unwrap().ip().to_string()) {
-        Some(client) => {
+    match find_client(addr, device, &mut backend) {
+        Ok(client) => {
             // Check if the client is mounting
-            let mut mounts = match backend.mounts.lock() {
-                Ok(m) => m,
+            let status = match backend.shared.jobs.lock() {
+                Ok(mut j) => j.mount_status(&client.udid),
                 Err(_) => {
                     warn!("Mutex poisoned!!");
-                    return Ok(packets::status_packet(true, true, false, ""));
+                    return Ok(packets::status_packet(true, true, false, "", None));
                 }
             };
-
-            match mounts.get(&client.udid) {
-                Some(m) => {
-                    let m = m.to_string();
-                    if !m.is_empty() {
-                        // Remove it from the HashMap
-                        mounts.remove(&client.udid);
-                    }
-                    Ok(packets::status_packet(true, true, true, &m))
-                }
-                None => Ok(packets::status_packet(true, true, false, "")),
-            }
+            Ok(packets::status_packet(
+                true,
+                true,
+                status.mounting,
+                &status.message,
+                status.pending_launch,
+            ))
+        }
+        Err(Error::NoAddress) | Err(Error::IpNotAllowed) => {
+            Ok(packets::status_packet(false, false, false, "", None))
         }
-        None => Ok(packets::status_packet(true, false, false, "")),
+        Err(e @ Error::AmbiguousDevice(_)) => Ok(packets::status_packet(
+            true,
+            true,
+            false,
+            &e.to_string(),
+            None,
+        )),
+        Err(_) => Ok(packets::status_packet(true, false, false, "", None)),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/shortcuts/list_apps/",
+    tag = "v1",
+    security((), ("device_token" = [])),
+    params(UdidQuery),
+    responses(
+        (
+            status = 200,
+            description = "Apps installed on the calling device",
+            body = ListAppsPacket,
+            content_type = "text/plain"
+        )
+    )
+)]
 async fn list_apps(
     addr: Option<SocketAddr>,
+    device: DeviceId,
     backend: Arc<Mutex<Backend>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Device list requested");
     let mut lock = backend.lock().await;
-    if addr.is_none() {
-        warn!("No address provided");
-        return Ok(packets::list_apps_response(
-            false,
-            "Unable to get IP address",
-            serde_json::Value::Object(serde_json::Map::new()),
-            serde_json::Value::Object(serde_json::Map::new()),
-        ));
-    }
-    if !lock.check_ip(addr.unwrap().ip()) {
-        warn!("Address not allowed");
-        return Ok(packets::list_apps_response(
-            false,
-            "Address not allowed, connect to the VLAN",
-            serde_json::Value::Object(serde_json::Map::new()),
-            serde_json::Value::Object(serde_json::Map::new()),
-        ));
-    }
-    let client = match lock.get_by_ip(&addr.unwrap().ip().to_string()) {
-        Some(client) => client,
-        None => {
-            warn!("No client found with the given IP");
+    let client = match find_client(addr, device, &mut lock) {
+        Ok(client) => client,
+        Err(e) => {
             return Ok(packets::list_apps_response(
                 false,
-                "Your device is not registered with JitStreamer",
-                serde_json::Value::Object(serde_json::Map::new()),
-                serde_json::Value::Object(serde_json::Map::new()),
+                &e.to_string(),
+                BTreeMap::new(),
+                BTreeMap::new(),
             ));
         }
     };
     drop(lock);
 
-    let (tx, mut rx) = mpsc::channel(1);
-
-    tokio::task::spawn_blocking(move || {
-        let v = match client.get_apps() {
-            Ok(v) => v,
-            Err(e) => {
-                warn!("Unable to get apps");
-                tx.blocking_send(Err(packets::list_apps_response(
-                    false,
-                    &format!("Unable to get apps: {}", e),
-                    serde_json::Value::Object(serde_json::Map::new()),
-                    serde_json::Value::Object(serde_json::Map::new()),
-                )))
-                .unwrap();
-                return;
-            }
-        };
-        tx.blocking_send(Ok(v)).unwrap();
-    });
-
-    let v = match rx.recv().await.unwrap() {
-        Ok(v) => v,
-        Err(e) => return Ok(e),
+    let list = match tasks::get_apps(client).await {
+        Ok(list) => list,
+        Err(e) => {
+            return Ok(packets::list_apps_response(
+                false,
+                &format!("Unable to get apps: {}", e),
+                BTreeMap::new(),
+                BTreeMap::new(),
+            ))
+        }
     };
 
-    // Trim the list of apps
-    let mut preferred_apps = Value::Object(serde_json::Map::new());
-    let mut apps: Value = Value::Object(serde_json::Map::new());
-    let mut count = 0;
-    for i in v {
-        let i = i.plist;
-        let name = i
-            .clone()
-            .dict_get_item("CFBundleDisplayName")
-            .unwrap()
-            .get_string_val()
-            .unwrap();
-        let bundle_id = i
-            .clone()
-            .dict_get_item("CFBundleIdentifier")
-            .unwrap()
-            .get_string_val()
-            .unwrap();
-        if bundle_id.contains("com.apple") {
-            continue;
-        }
-        if backend::Backend::preferred_app(&name) {
-            preferred_apps[&name] = serde_json::Value::String(bundle_id);
-        } else {
-            apps[&name] = serde_json::Value::String(bundle_id);
-        }
-        count += 1;
-    }
-
     let mut lock = backend.lock().await;
-    lock.counter.fetched += count;
+    lock.counter.fetched += list.count;
 
-    let res = packets::list_apps_response(true, "", apps, preferred_apps);
+    let res = packets::list_apps_response(true, "", list.apps, list.preferred_apps);
     Ok(res)
 }
 
+#[utoipa::path(
+    post,
+    path = "/shortcuts/launch/{app}/",
+    tag = "v1",
+    security((), ("device_token" = [])),
+    params(
+        ("app" = String, Path, description = "The bundle ID to launch"),
+        LaunchQuery,
+        UdidQuery
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Whether the app launched, or a JobPacket if async was set",
+            body = MessagePacket,
+            content_type = "text/plain"
+        )
+    )
+)]
 async fn shortcuts_run(
     app: String,
     addr: Option<SocketAddr>,
+    device: DeviceId,
+    query: LaunchQuery,
     backend: Arc<Mutex<Backend>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Device has sent request to launch {}", app);
     let mut lock = backend.lock().await;
-    if addr.is_none() {
-        warn!("No address provided");
-        return Ok(packets::launch_response(false, "Unable to get IP address"));
-    }
-    if !lock.check_ip(addr.unwrap().ip()) {
-        warn!("Address not allowed");
-        return Ok(packets::launch_response(
-            false,
-            "Address not allowed, connect to the VLAN",
-        ));
-    }
-    let client = match lock.get_by_ip(&addr.unwrap().ip().to_string()) {
-        Some(client) => client,
-        None => {
-            warn!("No client found with the given IP");
-            return Ok(packets::launch_response(
-                false,
-                "No client found with the given IP, please register your device",
-            ));
-        }
+    let client = match find_client(addr, device, &mut lock) {
+        Ok(client) => client,
+        Err(e) => return Ok(packets::launch_response(false, &e.to_string())),
     };
     lock.counter.launched += 1;
     drop(lock);
 
-    let (tx, mut rx) = mpsc::channel(1);
+    let (job, rx) = tasks::launch(client, app, query.defer);
 
-    tokio::task::spawn_blocking(move || {
-        match client.debug_app(app.clone()) {
-            Ok(_) => {
-                tx.blocking_send(packets::launch_response(true, ""))
-                    .unwrap();
-            }
-            Err(e) => {
-                tx.blocking_send(packets::launch_response(false, &e))
-                    .unwrap();
-            }
-        };
-    });
-
-    Ok(rx.recv().await.unwrap())
+    if query.run_async {
+        return Ok(packets::job_response(true, "", &job));
+    }
+    match rx.await {
+        Ok(Ok(_)) => Ok(packets::launch_response(true, "")),
+        Ok(Err(e)) => Ok(packets::launch_response(false, &e.to_string())),
+        Err(_) => Ok(packets::launch_response(
+            false,
+            "The launch task stopped unexpectedly",
+        )),
+    }
 }
 
+#[utoipa::path(
+    post,
+    path = "/attach/{pid}/",
+    tag = "v1",
+    security((), ("device_token" = [])),
+    params(
+        ("pid" = u16, Path, description = "The process to attach to"),
+        AsyncQuery,
+        UdidQuery
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Whether the debugger attached, or a JobPacket if async was set",
+            body = MessagePacket,
+            content_type = "text/plain"
+        )
+    )
+)]
 async fn attach_debugger(
     pid: u16,
     addr: Option<SocketAddr>,
+    device: DeviceId,
+    query: AsyncQuery,
     backend: Arc<Mutex<Backend>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Device has sent request to attach to process {}", pid);
     let mut backend = backend.lock().await;
-    if addr.is_none() {
-        warn!("No address provided");
-        return Ok(packets::attach_response(false, "Unable to get IP address"));
-    }
-    if !backend.check_ip(addr.unwrap().ip()) {
-        warn!("Address not allowed");
-        return Ok(packets::attach_response(
-            false,
-            "Address not allowed, connect to the VLAN",
-        ));
-    }
-    let client = match backend.get_by_ip(&addr.unwrap().ip().to_string()) {
-        Some(client) => client,
-        None => {
-            warn!("No client found with the given IP");
-            return Ok(packets::attach_response(
-                false,
-                "No client found with the given IP, please register your device",
-            ));
-        }
+    let client = match find_client(addr, device, &mut backend) {
+        Ok(client) => client,
+        Err(e) => return Ok(packets::attach_response(false, &e.to_string())),
     };
     backend.counter.attached += 1;
-    let mounts = backend.mounts.clone();
     drop(backend);
 
-    let (tx, mut rx) = mpsc::channel(1);
-
-    tokio::task::spawn_blocking(move || {
-        let mut i = 5;
-        loop {
-            match client.attach_debugger(pid, mounts.clone()) {
-                Ok(_) => match tx.blocking_send(packets::attach_response(true, "")) {
-                    Ok(_) => break,
-                    Err(e) => {
-                        warn!("Unable to send response: {}", e);
-                        break;
-                    }
-                },
-                Err(e) => {
-                    if i == 0 {
-                        match tx.blocking_send(packets::attach_response(false, &e)) {
-                            Ok(_) => (),
-                            Err(e) => {
-                                warn!("Unable to send response: {}", e);
-                            }
-                        }
-                        break;
-                    }
-                    i -= 1;
-                }
-            };
-        }
-    });
+    let (job, rx) = tasks::attach(client, pid);
+
+    if query.run_async {
+        return Ok(packets::job_response(true, "", &job));
+    }
 
-    match timeout(std::time::Duration::from_secs(60), rx.recv()).await {
+    match timeout(std::time::Duration::from_secs(60), rx).await {
         Ok(x) => match x {
-            Some(x) => Ok(x),
-            None => Ok(packets::attach_response(false, "Timeout")),
+            Ok(Ok(_)) => Ok(packets::attach_response(true, "")),
+            Ok(Err(e)) => Ok(packets::attach_response(false, &e.to_string())),
+            Err(_) => Ok(packets::attach_response(false, "Timeout")),
         },
         Err(_) => {
             warn!("Unable to receive response");
@@ -667,189 +999,716 @@ async fn attach_debugger(
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/shortcuts/unregister/",
+    tag = "v1",
+    security((), ("device_token" = [])),
+    params(UdidQuery),
+    responses(
+        (
+            status = 200,
+            description = "Whether the calling device was removed",
+            body = MessagePacket,
+            content_type = "text/plain"
+        )
+    )
+)]
 async fn shortcuts_unregister(
     addr: Option<SocketAddr>,
+    device: DeviceId,
     backend: Arc<Mutex<Backend>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Device has sent request unregister");
     let mut backend = backend.lock().await;
-    if addr.is_none() {
-        warn!("No address provided");
-        return Ok(packets::launch_response(false, "Unable to get IP address"));
-    }
-    if !backend.check_ip(addr.unwrap().ip()) {
-        warn!("Address not allowed");
-        return Ok(packets::unregister_response(
-            false,
-            "Address not allowed, connect to the VLAN",
-        ));
-    }
-    match backend.unregister_client(addr.unwrap().ip().to_string()) {
+    let client = match find_client(addr, device, &mut backend) {
+        Ok(client) => client,
+        Err(e) => return Ok(packets::unregister_response(false, &e.to_string())),
+    };
+    match backend.unregister_client(&client.udid) {
         Ok(_) => Ok(packets::unregister_response(true, "")),
-        Err(_) => Ok(packets::unregister_response(
-            false,
-            "Device not found in database",
-        )),
+        Err(e) => Ok(packets::unregister_response(false, &e.to_string())),
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/token/",
+    tag = "v1",
+    security((), ("device_token" = [])),
+    responses(
+        (
+            status = 200,
+            description = "A new token for the calling device, the old one stops working",
+            body = TokenPacket,
+            content_type = "text/plain"
+        )
+    )
+)]
+async fn rotate_token(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<impl Reply, Rejection> {
+    info!("Device has sent request for a new token");
+    let mut backend = backend.lock().await;
+    let client = match find_client(addr, device, &mut backend) {
+        Ok(client) => client,
+        Err(e) => return Ok(packets::token_response(false, &e.to_string(), "")),
+    };
+    match backend.issue_token(&client.udid) {
+        Ok(token) => Ok(packets::token_response(true, "", &token)),
+        Err(e) => Ok(packets::token_response(false, &e.to_string(), "")),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/netmuxd/",
+    tag = "v1",
+    security((), ("device_token" = [])),
+    responses(
+        (
+            status = 200,
+            description = "`ok`, or why the device couldn't be added to netmuxd",
+            body = String,
+            content_type = "text/plain"
+        )
+    )
+)]
 async fn netmuxd_connect(
     addr: Option<SocketAddr>,
+    device: DeviceId,
     backend: Arc<Mutex<Backend>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Device has sent request to connect to netmuxd");
-    let addr = match addr {
-        Some(addr) => addr,
-        None => {
-            warn!("No address provided");
-            return Ok("Unable to get IP address");
-        }
-    };
     let mut backend = backend.lock().await;
-    if !backend.check_ip(addr.ip()) {
-        warn!("Address not allowed");
-        return Ok("Address not allowed, connect to the VLAN");
-    }
-    let client = match backend.get_by_ip(&addr.ip().to_string()) {
-        Some(client) => client,
-        None => {
-            warn!("No client found with the given IP");
-            return Ok("No client found with the given IP, please register your device");
-        }
+    let client = match find_client(addr, device, &mut backend) {
+        Ok(client) => client,
+        Err(e) => return Ok(e.to_string()),
     };
     let udid = client.udid.clone();
     let netmuxd_address = backend.netmuxd_address.clone();
 
     if netmuxd_address.is_none() {
         warn!("No netmuxd address provided");
-        return Ok("No netmuxd address provided");
+        return Ok("No netmuxd address provided".to_string());
     }
     let netmuxd_address = netmuxd_address.unwrap();
 
     backend.counter.netmuxd += 1;
+    let metrics = backend.shared.metrics.clone();
 
     drop(backend);
 
+    match add_to_netmuxd(client.ip.clone(), udid, netmuxd_address).await {
+        Ok(_) => {
+            metrics.request(Request::Netmuxd, Outcome::Success);
+            client.mark_seen();
+            Ok("ok".to_string())
+        }
+        Err(e) => {
+            metrics.request(Request::Netmuxd, Outcome::Failure);
+            Ok(e)
+        }
+    }
+}
+
+/// Asks netmuxd to start muxing a network device, returning why it couldn't.
+async fn add_to_netmuxd(ip: String, udid: String, netmuxd_address: String) -> Result<(), String> {
     // Determine if the muxer already contains the client
     if rusty_libimobiledevice::idevice::get_device(udid.clone()).is_ok() {
         info!("Device already connected to netmuxd");
-        return Ok("ok");
+        return Ok(());
     }
 
     // Send the packet to netmuxd
-    let packet: Vec<u8> = match netmuxd::add_device_packet(addr.ip().to_string(), udid) {
+    let packet: Vec<u8> = match netmuxd::add_device_packet(ip, udid) {
+        Ok(packet) => packet.into(),
+        Err(_) => {
+            warn!("Unable to build netmuxd packet");
+            return Err("Unable to build netmuxd packet".to_string());
+        }
+    };
+
+    send_to_netmuxd(packet, netmuxd_address, false).await
+}
+
+/// Tells netmuxd a device moved to a new IP, replacing the address it was muxed at.
+/// Both requests have to be answered, otherwise netmuxd would keep the old address.
+async fn move_in_netmuxd(ip: String, udid: String, netmuxd_address: String) -> Result<(), String> {
+    let packet: Vec<u8> = match netmuxd::remove_device_packet(udid.clone()) {
         Ok(packet) => packet.into(),
         Err(_) => {
             warn!("Unable to build netmuxd packet");
-            return Ok("Unable to build netmuxd packet");
+            return Err("Unable to build netmuxd packet".to_string());
         }
     };
+    if let Err(e) = send_to_netmuxd(packet, netmuxd_address.clone(), true).await {
+        return Err(format!("netmuxd didn't remove the old address: {}", e));
+    }
+
+    let packet: Vec<u8> = match netmuxd::add_device_packet(ip, udid) {
+        Ok(packet) => packet.into(),
+        Err(_) => {
+            warn!("Unable to build netmuxd packet");
+            return Err("Unable to build netmuxd packet".to_string());
+        }
+    };
+    if let Err(e) = send_to_netmuxd(packet, netmuxd_address, true).await {
+        return Err(format!("netmuxd didn't add the new address: {}", e));
+    }
+    Ok(())
+}
+
+/// How long to wait for netmuxd to answer a request.
+const NETMUXD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
 
+/// Sends a packet to netmuxd over TCP or a Unix socket, depending on the address.
+/// If `answered`, waits for netmuxd's answer and checks the request succeeded.
+async fn send_to_netmuxd(
+    packet: Vec<u8>,
+    netmuxd_address: String,
+    answered: bool,
+) -> Result<(), String> {
     // Determine if the address is TCP or Unix
     match netmuxd_address.parse::<std::net::SocketAddr>() {
-        Ok(addr) => {
-            let stream = match tokio::net::TcpStream::connect(addr).await {
-                Ok(stream) => stream,
-                Err(e) => {
-                    warn!("Unable to connect to netmuxd: {}", e);
-                    return Ok("Unable to connect to netmuxd");
-                }
-            };
-            // Send the packet
-            let mut stream = tokio::io::BufWriter::new(stream);
-            match stream.write_all(&packet).await {
-                Ok(_) => (),
-                Err(e) => {
-                    warn!("Unable to send packet to netmuxd: {}", e);
-                    return Ok("Unable to send packet to netmuxd");
-                }
-            };
+        Ok(addr) => match tokio::net::TcpStream::connect(addr).await {
+            Ok(stream) => exchange_with_netmuxd(stream, packet, answered).await,
+            Err(e) => {
+                warn!("Unable to connect to netmuxd: {}", e);
+                Err("Unable to connect to netmuxd".to_string())
+            }
+        },
+        Err(_) => match tokio::net::UnixStream::connect(netmuxd_address).await {
+            Ok(stream) => exchange_with_netmuxd(stream, packet, answered).await,
+            Err(e) => {
+                warn!("Unable to connect to netmuxd: {}", e);
+                Err("Unable to connect to netmuxd".to_string())
+            }
+        },
+    }
+}
 
-            match stream.flush().await {
-                Ok(_) => (),
-                Err(e) => {
-                    warn!("Unable to flush packet to netmuxd: {}", e);
-                    return Ok("Unable to flush packet to netmuxd");
-                }
-            };
+async fn exchange_with_netmuxd<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    packet: Vec<u8>,
+    answered: bool,
+) -> Result<(), String> {
+    // Send the packet
+    let mut stream = tokio::io::BufWriter::new(stream);
+    match stream.write_all(&packet).await {
+        Ok(_) => (),
+        Err(e) => {
+            warn!("Unable to send packet to netmuxd: {}", e);
+            return Err("Unable to send packet to netmuxd".to_string());
         }
-        Err(_) => {
-            let stream = match tokio::net::UnixStream::connect(netmuxd_address).await {
-                Ok(stream) => stream,
-                Err(e) => {
-                    warn!("Unable to connect to netmuxd: {}", e);
-                    return Ok("Unable to connect to netmuxd");
-                }
-            };
-            // Send the packet
-            let mut stream = tokio::io::BufWriter::new(stream);
-            match stream.write_all(&packet).await {
-                Ok(_) => (),
-                Err(e) => {
-                    warn!("Unable to send packet to netmuxd: {}", e);
-                    return Ok("Unable to send packet to netmuxd");
-                }
-            };
+    };
 
-            match stream.flush().await {
-                Ok(_) => (),
-                Err(e) => {
-                    warn!("Unable to flush packet to netmuxd: {}", e);
-                    return Ok("Unable to flush packet to netmuxd");
-                }
-            };
+    match stream.flush().await {
+        Ok(_) => (),
+        Err(e) => {
+            warn!("Unable to flush packet to netmuxd: {}", e);
+            return Err("Unable to flush packet to netmuxd".to_string());
         }
     };
+    if !answered {
+        return Ok(());
+    }
 
-    Ok("ok")
+    let answer = timeout(NETMUXD_TIMEOUT, async {
+        let mut header = [0; netmuxd::HEADER_LEN];
+        stream.read_exact(&mut header).await?;
+        let mut body = vec![0; netmuxd::body_len(&header)];
+        stream.read_exact(&mut body).await?;
+        Ok::<_, std::io::Error>(body)
+    })
+    .await;
+    match answer {
+        Ok(Ok(body)) => netmuxd::check_result(&body),
+        Ok(Err(e)) => {
+            warn!("netmuxd closed the connection without answering: {}", e);
+            Err("netmuxd didn't answer, it may be too old for this request".to_string())
+        }
+        Err(_) => {
+            warn!("netmuxd didn't answer within {:?}", NETMUXD_TIMEOUT);
+            Err("netmuxd didn't answer in time".to_string())
+        }
+    }
 }
 
-async fn install_app(
+#[utoipa::path(
+    post,
+    path = "/install/app/",
+    tag = "v1",
+    security((), ("device_token" = [])),
+    params(
+        ("x-content-sha256" = String, Header, description = "The IPA's hex SHA-256"),
+        AsyncQuery
+    ),
+    request_body(
+        content = String,
+        content_type = "application/octet-stream",
+        description = "The IPA"
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Whether the app installed, or a JobPacket if async was set",
+            body = MessagePacket,
+            content_type = "text/plain"
+        )
+    )
+)]
+async fn install_app<S, B>(
     addr: Option<SocketAddr>,
+    device: DeviceId,
+    sha256: Option<String>,
+    query: AsyncQuery,
+    body: S,
     backend: Arc<Mutex<Backend>>,
-    ipa: bytes::Bytes,
-) -> Result<impl Reply, Rejection> {
+) -> Result<impl Reply, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send,
+    B: Buf + Send,
+{
     info!("Device has sent request to install app");
-    let addr = match addr {
-        Some(addr) => addr,
-        None => {
-            warn!("No address provided");
-            return Ok(packets::install_response(false, "Unable to get IP address"));
+    match receive_ipa(addr, device, sha256, body, &backend).await {
+        Ok((client, path)) => Ok(run_install(client, path, query.run_async).await),
+        Err(e) => Ok(packets::install_response(false, &e.to_string())),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/install/session/",
+    tag = "v1",
+    security((), ("device_token" = [])),
+    params(NewSessionQuery),
+    responses(
+        (
+            status = 200,
+            description = "A new resumable upload",
+            body = UploadSessionPacket,
+            content_type = "text/plain"
+        )
+    )
+)]
+async fn install_session_create(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    query: NewSessionQuery,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<impl Reply, Rejection> {
+    info!("Device has requested an upload session");
+    match create_session(addr, device, query, &backend).await {
+        Ok(session) => Ok(packets::upload_session_response(true, "", &session.id, 0)),
+        Err(e) => Ok(packets::upload_session_response(
+            false,
+            &e.to_string(),
+            "",
+            0,
+        )),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/install/session/{id}/",
+    tag = "v1",
+    security((), ("device_token" = [])),
+    params(("id" = String, Path, description = "The upload session ID")),
+    responses(
+        (
+            status = 200,
+            description = "How much of the upload has arrived",
+            body = UploadSessionPacket,
+            content_type = "text/plain"
+        )
+    )
+)]
+async fn install_session_status(
+    id: String,
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<impl Reply, Rejection> {
+    match session_status(&id, addr, device, &backend).await {
+        Ok(session) => Ok(packets::upload_session_response(
+            true,
+            "",
+            &id,
+            session.received,
+        )),
+        Err(e) => Ok(packets::upload_session_response(
+            false,
+            &e.to_string(),
+            &id,
+            0,
+        )),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/install/session/{id}/",
+    tag = "v1",
+    security((), ("device_token" = [])),
+    params(
+        ("id" = String, Path, description = "The upload session ID"),
+        ("upload-offset" = u64, Header, description = "Where this chunk starts")
+    ),
+    request_body(
+        content = String,
+        content_type = "application/octet-stream",
+        description = "The next chunk of the IPA"
+    ),
+    responses(
+        (
+            status = 200,
+            description = "The new upload offset",
+            body = UploadSessionPacket,
+            content_type = "text/plain"
+        )
+    )
+)]
+async fn install_session_upload<S, B>(
+    id: String,
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    offset: u64,
+    body: S,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<impl Reply, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send,
+    B: Buf + Send,
+{
+    match upload_chunk(&id, addr, device, offset, body, &backend).await {
+        (received, Ok(_)) => Ok(packets::upload_session_response(true, "", &id, received)),
+        (received, Err(e)) => Ok(packets::upload_session_response(
+            false,
+            &e.to_string(),
+            &id,
+            received,
+        )),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/install/session/{id}/finish/",
+    tag = "v1",
+    security((), ("device_token" = [])),
+    params(("id" = String, Path, description = "The upload session ID"), AsyncQuery),
+    responses(
+        (
+            status = 200,
+            description = "Whether the app installed, or a JobPacket if async was set",
+            body = MessagePacket,
+            content_type = "text/plain"
+        )
+    )
+)]
+async fn install_session_finish(
+    id: String,
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    query: AsyncQuery,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<impl Reply, Rejection> {
+    info!("Device has finished upload session {}", id);
+    match finish_session(&id, addr, device, &backend).await {
+        Ok((client, path)) => Ok(run_install(client, path, query.run_async).await),
+        Err(e) => Ok(packets::install_response(false, &e.to_string())),
+    }
+}
+
+/// Streams an IPA for the calling device to disk, handing it back once it matches its hash.
+pub(crate) async fn receive_ipa<S, B>(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    sha256: Option<String>,
+    body: S,
+    backend: &Arc<Mutex<Backend>>,
+) -> Result<(Client, PathBuf), Error>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send,
+    B: Buf + Send,
+{
+    // Every install is checked against a hash, so a truncated upload is never installed
+    let expected = match sha256 {
+        Some(sha256) => uploads::parse_sha256(&sha256)?,
+        None => return Err(Error::HashRequired),
+    };
+    let mut lock = backend.lock().await;
+    let client = find_client(addr, device, &mut lock)?;
+    let path = lock.uploads.temp_path(&client.udid)?;
+    let max_size = lock.uploads.max_size;
+    drop(lock);
+
+    let hash = match uploads::write_stream(&path, 0, max_size, body).await {
+        Ok(_) => uploads::hash_file(&path).await,
+        Err(e) => Err(e),
+    };
+    match hash {
+        Ok(hash) if hash == expected => Ok((client, path)),
+        Ok(_) => {
+            warn!("Uploaded IPA hash did not match");
+            let _ = tokio::fs::remove_file(&path).await;
+            Err(Error::HashMismatch)
         }
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&path).await;
+            Err(e)
+        }
+    }
+}
+
+/// Starts a resumable upload for the calling device.
+pub(crate) async fn create_session(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    query: NewSessionQuery,
+    backend: &Arc<Mutex<Backend>>,
+) -> Result<UploadSession, Error> {
+    let mut lock = backend.lock().await;
+    let client = find_client(addr, device, &mut lock)?;
+    lock.uploads
+        .new_session(client.udid, query.size, query.sha256)
+}
+
+/// Looks up one of the calling device's upload sessions.
+pub(crate) async fn session_status(
+    id: &str,
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    backend: &Arc<Mutex<Backend>>,
+) -> Result<UploadSession, Error> {
+    let mut lock = backend.lock().await;
+    let client = find_client(addr, device, &mut lock)?;
+    match lock.uploads.get_session(id) {
+        Some(session) if session.udid == client.udid => Ok(session),
+        _ => Err(Error::UploadNotFound),
+    }
+}
+
+/// Appends a chunk to one of the calling device's upload sessions.
+/// Returns how much of the upload has arrived, even if the chunk failed part way.
+pub(crate) async fn upload_chunk<S, B>(
+    id: &str,
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    offset: u64,
+    body: S,
+    backend: &Arc<Mutex<Backend>>,
+) -> (u64, Result<(), Error>)
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send,
+    B: Buf + Send,
+{
+    let mut lock = backend.lock().await;
+    let client = match find_client(addr, device, &mut lock) {
+        Ok(client) => client,
+        Err(e) => return (0, Err(e)),
     };
-    let mut backend = backend.lock().await;
-    if !backend.check_ip(addr.ip()) {
-        warn!("Address not allowed");
-        return Ok(packets::install_response(
-            false,
-            "Address not allowed, connect to the VLAN",
-        ));
+    let session = match lock.uploads.claim_session(id, &client.udid) {
+        Ok(session) => session,
+        Err(e) => return (0, Err(e)),
+    };
+    drop(lock);
+
+    // The client has to resume from exactly where we left off
+    if offset != session.received {
+        backend
+            .lock()
+            .await
+            .uploads
+            .release_session(id, session.received);
+        return (session.received, Err(Error::UploadOffset));
     }
-    let client = match backend.get_by_ip(&addr.ip().to_string()) {
-        Some(client) => client,
-        None => {
-            warn!("No client found with the given IP");
-            return Ok(packets::install_response(
+
+    let res = uploads::write_stream(&session.path, session.received, session.size, body).await;
+
+    // Whatever made it to disk counts, so the client can resume after a dropped connection
+    let received = match tokio::fs::metadata(&session.path).await {
+        Ok(m) => m.len(),
+        Err(_) => session.received,
+    };
+    backend.lock().await.uploads.release_session(id, received);
+    (received, res.map(|_| ()))
+}
+
+/// Checks a finished upload session against its hash, handing back the IPA to install.
+pub(crate) async fn finish_session(
+    id: &str,
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    backend: &Arc<Mutex<Backend>>,
+) -> Result<(Client, PathBuf), Error> {
+    let mut lock = backend.lock().await;
+    let client = find_client(addr, device, &mut lock)?;
+    let session = lock.uploads.claim_session(id, &client.udid)?;
+    if session.received != session.size {
+        lock.uploads.release_session(id, session.received);
+        return Err(Error::UploadIncomplete);
+    }
+    drop(lock);
+
+    let hash = uploads::hash_file(&session.path).await;
+    let mut lock = backend.lock().await;
+    match hash {
+        Ok(hash) if hash == session.sha256 => {
+            lock.uploads.remove_session(id);
+            Ok((client, session.path))
+        }
+        Ok(_) => {
+            warn!("Upload session {} hash did not match", id);
+            lock.uploads.remove_session(id);
+            let _ = tokio::fs::remove_file(&session.path).await;
+            Err(Error::HashMismatch)
+        }
+        Err(e) => {
+            lock.uploads.release_session(id, session.received);
+            Err(e)
+        }
+    }
+}
+
+/// Installs an IPA from disk, then removes the file.
+/// If `run_async` is set this returns the job ID instead of waiting for the install.
+async fn run_install(client: Client, path: PathBuf, run_async: bool) -> String {
+    let (job, rx) = tasks::install(client, path);
+
+    if run_async {
+        return packets::job_response(true, "", &job);
+    }
+    match rx.await {
+        Ok(Ok(_)) => packets::install_response(true, ""),
+        Ok(Err(e)) => packets::install_response(false, &e.to_string()),
+        Err(_) => packets::install_response(false, "The install task stopped unexpectedly"),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}/",
+    tag = "v1",
+    params(("id" = String, Path, description = "The job ID")),
+    responses(
+        (
+            status = 200,
+            description = "The job's current state",
+            body = JobStatusPacket,
+            content_type = "text/plain"
+        )
+    )
+)]
+async fn get_job(id: String, backend: Arc<Mutex<Backend>>) -> Result<impl Reply, Rejection> {
+    let jobs = backend.lock().await.shared.jobs.clone();
+    let job = jobs.lock().unwrap().get(&id);
+    match job {
+        Some(job) => Ok(packets::job_status_response(true, "", Some(job))),
+        None => Ok(packets::job_status_response(false, "Job not found", None)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/",
+    tag = "v1",
+    security((), ("device_token" = [])),
+    params(JobListQuery),
+    responses(
+        (
+            status = 200,
+            description = "Jobs for the calling device, newest first",
+            body = JobListPacket,
+            content_type = "text/plain"
+        )
+    )
+)]
+async fn list_jobs(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    query: JobListQuery,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<impl Reply, Rejection> {
+    let mut lock = backend.lock().await;
+    let client = match find_client(addr, device, &mut lock) {
+        Ok(client) => client,
+        Err(e) => return Ok(packets::job_list_response(false, &e.to_string(), vec![])),
+    };
+    // Devices can only see their own jobs
+    if let Some(udid) = query.udid {
+        if udid != client.udid {
+            return Ok(packets::job_list_response(
                 false,
-                "No client found with the given IP, please register your device",
+                "You can only list jobs for your own device",
+                vec![],
             ));
         }
-    };
-
-    let (tx, mut rx) = mpsc::channel(1);
+    }
+    let jobs = lock.shared.jobs.lock().unwrap().by_udid(&client.udid);
+    Ok(packets::job_list_response(true, "", jobs))
+}
 
-    tokio::task::spawn_blocking(move || {
-        match client.install_app(ipa.to_vec()) {
-            Ok(_) => {
-                tx.blocking_send(packets::install_response(true, ""))
-                    .unwrap();
-            }
-            Err(e) => {
-                tx.blocking_send(packets::install_response(false, &e))
-                    .unwrap();
-            }
-        };
-    });
+#[utoipa::path(
+    get,
+    path = "/progress/{udid}/",
+    tag = "v1",
+    security((), ("device_token" = [])),
+    params(("udid" = String, Path, description = "The device to follow, the caller's own")),
+    responses(
+        (
+            status = 200,
+            description = "Server-sent `progress` events for the device's disk image",
+            body = ProgressEvent,
+            content_type = "text/event-stream"
+        ),
+        (
+            status = 403,
+            description = "The caller isn't that device",
+            body = MessagePacket,
+            content_type = "text/plain"
+        )
+    )
+)]
+async fn progress_stream(
+    udid: String,
+    addr: Option<SocketAddr>,
+    mut device: DeviceId,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    // Devices sharing an IP are told apart by the UDID being followed
+    device.udid = device.udid.or_else(|| Some(udid.clone()));
+    let mut lock = backend.lock().await;
+    let message = match find_client(addr, device, &mut lock) {
+        Ok(client) if client.udid == udid => None,
+        Ok(_) => Some("You can only follow your own device".to_string()),
+        Err(e) => Some(e.to_string()),
+    };
+    if let Some(message) = message {
+        return Ok(warp::reply::with_status(
+            packets::progress_response(false, &message),
+            StatusCode::FORBIDDEN,
+        )
+        .into_response());
+    }
+    let progress = lock.shared.progress.clone();
+    drop(lock);
+    let (latest, rx) = progress.lock().unwrap().subscribe(&udid);
+    Ok(
+        warp::sse::reply(warp::sse::keep_alive().stream(progress::event_stream(latest, rx)))
+            .into_response(),
+    )
+}
 
-    Ok(rx.recv().await.unwrap())
+/// Looks up the registered client making a request.
+fn find_client(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    backend: &mut Backend,
+) -> Result<Client, Error> {
+    backend.identify(addr, &device)
 }
@@ -0,0 +1,54 @@
+// jkcoxson
+
+use log::{info, warn};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{backend::Backend, pairing_store::PairingStore};
+
+/// Saves when devices were last seen, expires stale devices and abandoned uploads,
+/// and rolls up old usage events, forever.
+pub async fn run(backend: Arc<Mutex<Backend>>, interval: Duration) {
+    // The first run waits a full interval, so a restart never expires devices straight away
+    let mut interval = tokio::time::interval_at(Instant::now() + interval, interval);
+    loop {
+        interval.tick().await;
+        let mut backend = backend.lock().await;
+        backend.uploads.expire_sessions();
+        if let Ok(mut usage) = backend.shared.usage.lock() {
+            usage.compact();
+        }
+        if let Err(e) = backend.save_seen() {
+            warn!("Unable to save when devices were last seen: {}", e);
+        }
+        match backend.expire_clients(false) {
+            Ok(expired) => {
+                for client in expired {
+                    info!(
+                        "Expired {}, it hasn't been seen in {} days",
+                        client.udid, backend.retention_days
+                    );
+                }
+            }
+            Err(e) => warn!("Unable to expire devices: {}", e),
+        }
+    }
+}
+
+/// Drops expired pair codes every minute, they only live for a few.
+pub async fn sweep_pair_codes(backend: Arc<Mutex<Backend>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        backend.lock().await.pair_codes.sweep();
+    }
+}
+
+/// Removes decrypted pairing record copies once they have been left long enough.
+pub async fn sweep_pairing_copies(records: Arc<PairingStore>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        records.sweep_copies();
+    }
+}
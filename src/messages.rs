@@ -20,7 +20,23 @@ This can take up to 5 minutes, keep your device powered on and connected.
 Run the shortcut again to check the mounting progress in a bit.
 If you receive this message multiple times, please restart your device."#;
 
+pub const MOUNTING_DEFERRED: &str = r#"JitStreamer is currently mounting the developer disk image.
+This can take up to 5 minutes, keep your device powered on and connected.
+Your app will launch automatically as soon as mounting finishes."#;
+
 pub const DETACH: &str = r#"Unable to send the detach command to the device.
 This error can be ignored if the app still launched successfully.
 (Please note that all apps installed with TrollStore are INCOMPATIBLE with JitStreamer. Please contact your app's developer to add support for ptrace instead.)
 Otherwise, please restart your device."#;
+
+pub const START_AFC: &str = r#"Unable to start AFC. This is a connection error to the device.
+Try restarting your device. If you still have this problem, unregister and re-register.
+Error:"#;
+
+pub const UPLOAD_IPA: &str = r#"Unable to upload the app to the device.
+Make sure your device has enough free storage and try again.
+Error:"#;
+
+pub const INSTALL_APP: &str = r#"The device refused to install the app.
+Make sure the app is signed for your device and that it supports your iOS version.
+Error:"#;
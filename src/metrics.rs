@@ -0,0 +1,204 @@
+// jkcoxson
+
+use log::warn;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+
+/// Device operations can take anywhere from milliseconds to a multi-minute DMG download.
+const DURATION_BUCKETS: &[f64] = &[
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+/// A request a device makes, counted in the census.
+#[derive(Clone, Copy, Debug)]
+pub enum Request {
+    Launch,
+    Fetch,
+    Attach,
+    Netmuxd,
+}
+
+impl Request {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Request::Launch => "launch",
+            Request::Fetch => "fetch",
+            Request::Attach => "attach",
+            Request::Netmuxd => "netmuxd",
+        }
+    }
+}
+
+/// A step of talking to a device that is worth timing.
+#[derive(Clone, Copy, Debug)]
+pub enum Operation {
+    Connect,
+    InstproxyLookup,
+    DebugserverLaunch,
+    DdiDownload,
+    Mount,
+}
+
+impl Operation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Connect => "connect",
+            Operation::InstproxyLookup => "instproxy_lookup",
+            Operation::DebugserverLaunch => "debugserver_launch",
+            Operation::DdiDownload => "ddi_download",
+            Operation::Mount => "mount",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    Failure,
+    /// The request is waiting on the developer disk image to mount, which isn't a failure
+    Mounting,
+}
+
+impl Outcome {
+    pub fn of<T>(res: &Result<T, Error>) -> Outcome {
+        match res {
+            Ok(_) => Outcome::Success,
+            Err(Error::Mounting) | Err(Error::MountingDeferred) => Outcome::Mounting,
+            Err(_) => Outcome::Failure,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+            Outcome::Mounting => "mounting",
+        }
+    }
+}
+
+/// Prometheus metrics for the whole server.
+/// Unlike the census counters these are labelled by outcome and never reset by an admin.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    operations: HistogramVec,
+    clients: IntGauge,
+    heartbeats: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "jitstreamer_requests_total",
+                "Requests made by devices, by kind and outcome",
+            ),
+            &["kind", "outcome"],
+        )
+        .unwrap();
+        let operations = HistogramVec::new(
+            HistogramOpts::new(
+                "jitstreamer_operation_duration_seconds",
+                "How long each device operation took, by operation and outcome",
+            )
+            .buckets(DURATION_BUCKETS.to_vec()),
+            &["operation", "outcome"],
+        )
+        .unwrap();
+        let clients = IntGauge::new("jitstreamer_clients", "Registered devices").unwrap();
+        let heartbeats = IntGauge::new(
+            "jitstreamer_heartbeats",
+            "Devices with a heartbeat currently running",
+        )
+        .unwrap();
+        let start_time = IntGauge::new(
+            "jitstreamer_start_time_seconds",
+            "When the server started, in seconds since the epoch",
+        )
+        .unwrap();
+        start_time.set(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64,
+        );
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(operations.clone())).unwrap();
+        registry.register(Box::new(clients.clone())).unwrap();
+        registry.register(Box::new(heartbeats.clone())).unwrap();
+        registry.register(Box::new(start_time)).unwrap();
+
+        Self {
+            registry,
+            requests,
+            operations,
+            clients,
+            heartbeats,
+        }
+    }
+
+    pub fn request(&self, kind: Request, outcome: Outcome) {
+        self.requests
+            .with_label_values(&[kind.as_str(), outcome.as_str()])
+            .inc();
+    }
+
+    /// Starts timing an operation. It is recorded as a failure unless `succeed` is called.
+    pub fn time(&self, operation: Operation) -> Timer {
+        Timer {
+            metrics: self,
+            operation,
+            start: Instant::now(),
+            outcome: Outcome::Failure,
+        }
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    /// The gauges are read at scrape time, so they are passed in here.
+    pub fn render(&self, clients: usize, heartbeats: usize) -> String {
+        self.clients.set(clients as i64);
+        self.heartbeats.set(heartbeats as i64);
+
+        let mut buf = vec![];
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            warn!("Unable to encode metrics: {}", e);
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Records how long an operation took when dropped.
+pub struct Timer<'a> {
+    metrics: &'a Metrics,
+    operation: Operation,
+    start: Instant,
+    outcome: Outcome,
+}
+
+impl Timer<'_> {
+    pub fn succeed(mut self) {
+        self.outcome = Outcome::Success;
+    }
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.metrics
+            .operations
+            .with_label_values(&[self.operation.as_str(), self.outcome.as_str()])
+            .observe(self.start.elapsed().as_secs_f64());
+    }
+}
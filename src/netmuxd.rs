@@ -1,6 +1,13 @@
 // jkcoxson
 
+use plist::Value;
 use plist_plus::{error::PlistError, Plist};
+use std::io::Cursor;
+
+/// The size, version, message and tag that start every packet.
+pub const HEADER_LEN: usize = 16;
+/// Replies are a small plist, anything longer is cut off here.
+const MAX_REPLY_LEN: usize = 64 * 1024;
 
 #[derive(Debug)]
 pub struct RawPacket {
@@ -15,7 +22,7 @@ impl RawPacket {
     pub fn new(plist: Plist, version: u32, message: u32, tag: u32) -> RawPacket {
         let plist_bytes = plist.to_string();
         let plist_bytes = plist_bytes.as_bytes();
-        let size = plist_bytes.len() as u32 + 16;
+        let size = (plist_bytes.len() + HEADER_LEN) as u32;
         RawPacket {
             size,
             version,
@@ -48,3 +55,40 @@ pub fn add_device_packet(ip: String, udid: String) -> Result<RawPacket, PlistErr
 
     Ok(RawPacket::new(plist, 1, 69, 69))
 }
+
+/// Asks netmuxd to stop muxing a network device, so it can be added again at a new IP.
+/// netmuxd versions without RemoveDevice close the connection without answering.
+pub fn remove_device_packet(udid: String) -> Result<RawPacket, PlistError> {
+    let mut plist = Plist::new_dict();
+    plist.dict_set_item("MessageType", "RemoveDevice".into())?;
+    plist.dict_set_item("DeviceID", udid.into())?;
+
+    Ok(RawPacket::new(plist, 1, 69, 69))
+}
+
+/// How long the plist after a reply's header is.
+pub fn body_len(header: &[u8; HEADER_LEN]) -> usize {
+    let size = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    size.saturating_sub(HEADER_LEN).min(MAX_REPLY_LEN)
+}
+
+/// Checks the answer to a request. Like usbmuxd, netmuxd answers with a Result message
+/// whose Number is 0 if the request succeeded.
+pub fn check_result(body: &[u8]) -> Result<(), String> {
+    let reply = match Value::from_reader(Cursor::new(body)) {
+        Ok(reply) => reply,
+        Err(e) => return Err(format!("netmuxd's answer isn't a plist ({})", e)),
+    };
+    let number = reply
+        .as_dictionary()
+        .and_then(|dict| dict.get("Number"))
+        .and_then(|number| number.as_unsigned_integer());
+    match number {
+        Some(0) => Ok(()),
+        Some(number) => Err(format!(
+            "netmuxd refused the request with result {}",
+            number
+        )),
+        None => Err("netmuxd's answer has no result".to_string()),
+    }
+}
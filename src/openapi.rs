@@ -0,0 +1,278 @@
+// jkcoxson
+
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+
+use crate::{
+    admin::{
+        AdminClient, AdminClientResponse, AdminClientsResponse, ClearedCodesResponse,
+        ExpiredClient, ExpiryResponse, UsageExportResponse,
+    },
+    backend::{PreviousIp, TOKEN_HEADER},
+    error::ErrorCode,
+    integrity::{IntegrityReport, InvalidRecord},
+    jobs::{Job, JobKind, JobState},
+    packets::{
+        CensusPacket, JobListPacket, JobPacket, JobStatusPacket, ListAppsPacket, MessagePacket,
+        PotentialPairPacket, StatusPacket, TokenPacket, UploadSessionPacket, Version,
+    },
+    pairing_record::PairingFormat,
+    progress::{Phase, ProgressEvent},
+    usage::{EventKind, ExportFormat, UsageEvent, UsageStats, UsageSummary},
+    v2::{
+        ApiError, AppsResponse, CensusResponse, ErrorResponse, JobResponse, JobsResponse,
+        PairCodeResponse, RegistrationResponse, StatusResponse, TokenResponse, UnregisterResponse,
+        UploadSessionResponse,
+    },
+};
+
+/// The OpenAPI document for every route the server exposes.
+/// v1 routes answer with JSON sent as text/plain, and expect a trailing slash.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "JitStreamer",
+        description = "Enables JIT on iOS devices over the network"
+    ),
+    paths(
+        crate::status,
+        crate::upload_file,
+        crate::potential_pair,
+        crate::potential_follow_up,
+        crate::version_route,
+        crate::census,
+        crate::prometheus_metrics,
+        crate::list_apps,
+        crate::shortcuts_run,
+        crate::shortcuts_unregister,
+        crate::rotate_token,
+        crate::attach_debugger,
+        crate::netmuxd_connect,
+        crate::install_app,
+        crate::install_session_create,
+        crate::install_session_status,
+        crate::install_session_upload,
+        crate::install_session_finish,
+        crate::get_job,
+        crate::list_jobs,
+        crate::progress_stream,
+        crate::openapi_json,
+        crate::v2::status,
+        crate::v2::list_apps,
+        crate::v2::launch,
+        crate::v2::attach,
+        crate::v2::unregister,
+        crate::v2::rotate_token,
+        crate::v2::register,
+        crate::v2::potential_pair,
+        crate::v2::potential_follow_up,
+        crate::v2::install_app,
+        crate::v2::create_session,
+        crate::v2::session_status,
+        crate::v2::upload_chunk,
+        crate::v2::finish_session,
+        crate::v2::get_job,
+        crate::v2::list_jobs,
+        crate::v2::census,
+        crate::v2::version,
+        crate::v2::export_pairing_file,
+        crate::admin::list_clients,
+        crate::admin::get_client,
+        crate::admin::unregister,
+        crate::admin::list_jobs,
+        crate::admin::clear_pair_codes,
+        crate::admin::reset_counter,
+        crate::admin::export_usage,
+        crate::admin::preview_expiry,
+        crate::admin::run_expiry,
+        crate::admin::check_integrity,
+        crate::admin::repair_integrity,
+    ),
+    components(schemas(
+        StatusPacket,
+        MessagePacket,
+        TokenPacket,
+        PotentialPairPacket,
+        ListAppsPacket,
+        CensusPacket,
+        UploadSessionPacket,
+        JobPacket,
+        JobStatusPacket,
+        JobListPacket,
+        Version,
+        Job,
+        JobKind,
+        JobState,
+        Phase,
+        ProgressEvent,
+        PairingFormat,
+        ErrorCode,
+        ApiError,
+        ErrorResponse,
+        StatusResponse,
+        AppsResponse,
+        JobResponse,
+        JobsResponse,
+        UnregisterResponse,
+        TokenResponse,
+        PairCodeResponse,
+        RegistrationResponse,
+        UploadSessionResponse,
+        CensusResponse,
+        AdminClient,
+        PreviousIp,
+        AdminClientsResponse,
+        AdminClientResponse,
+        ClearedCodesResponse,
+        UsageExportResponse,
+        ExpiredClient,
+        ExpiryResponse,
+        IntegrityReport,
+        InvalidRecord,
+        UsageEvent,
+        EventKind,
+        ExportFormat,
+        UsageStats,
+        UsageSummary,
+    )),
+    modifiers(&SecurityAddon),
+    tags(
+        (name = "v1", description = "The original API used by the shortcuts"),
+        (name = "v2", description = "Typed API with HTTP status codes and structured errors"),
+        (name = "admin", description = "Managing the server, needs the admin token from the config")
+    )
+)]
+pub struct ApiDoc;
+
+/// Registers the device and admin tokens that the `security` attributes refer to.
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "device_token",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(TOKEN_HEADER))),
+            );
+            components.add_security_scheme(
+                "admin_token",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Routes that are deliberately left out of the document.
+    const UNDOCUMENTED: &[&str] = &[];
+
+    /// Pulls every method and path served by a route table out of its source.
+    /// Path parameters become `{}` so they can be compared with the document.
+    fn routes_in(source: &str) -> Vec<(String, String)> {
+        let mut routes = vec![];
+        for (start, _) in source.match_indices("warp::path") {
+            let rest = &source[start + "warp::path".len()..];
+            let (is_macro, rest) = match rest.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, rest),
+            };
+            let (args, rest) = match rest.strip_prefix('(') {
+                Some(args) => args.split_at(args.find(')').unwrap()),
+                None => continue,
+            };
+            let segments: Vec<&str> = if is_macro {
+                args.split('/').map(|s| s.trim()).collect()
+            } else {
+                vec![args.trim()]
+            };
+            let path: String = segments
+                .iter()
+                .map(|s| match s.strip_prefix('"') {
+                    Some(literal) => format!("/{}", literal.trim_end_matches('"')),
+                    None => "/{}".to_string(),
+                })
+                .collect();
+
+            // The method filter belongs to the same route, so it comes before the statement ends
+            let route = &rest[..rest.find(';').unwrap_or(rest.len())];
+            let method = ["get", "post", "put", "delete"]
+                .iter()
+                .find(|m| route.contains(&format!("warp::{}()", m)))
+                .unwrap_or_else(|| panic!("no method filter on the route for {}", path));
+            routes.push((method.to_string(), path));
+        }
+        routes
+    }
+
+    /// The methods the document describes for a path.
+    fn methods_of(item: &utoipa::openapi::PathItem) -> Vec<&'static str> {
+        let operations = [
+            ("get", &item.get),
+            ("post", &item.post),
+            ("put", &item.put),
+            ("delete", &item.delete),
+        ];
+        operations
+            .iter()
+            .filter(|(_, operation)| operation.is_some())
+            .map(|(method, _)| *method)
+            .collect()
+    }
+
+    fn normalize(path: &str) -> String {
+        let mut out = String::new();
+        let mut in_param = false;
+        for c in path.trim_end_matches('/').chars() {
+            match c {
+                '{' => {
+                    in_param = true;
+                    out.push_str("{}");
+                }
+                '}' => in_param = false,
+                _ if in_param => {}
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn every_route_is_documented() {
+        let doc = ApiDoc::openapi();
+        let mut documented = vec![];
+        for (path, item) in &doc.paths.paths {
+            for method in methods_of(item) {
+                documented.push((method.to_string(), normalize(path)));
+            }
+        }
+
+        let mut routes = routes_in(include_str!("main.rs"));
+        routes.extend(routes_in(include_str!("v2.rs")));
+        routes.extend(routes_in(include_str!("admin.rs")));
+        assert!(!routes.is_empty(), "no routes found in the route tables");
+
+        let missing: Vec<&(String, String)> = routes
+            .iter()
+            .filter(|(_, path)| !UNDOCUMENTED.contains(&path.as_str()))
+            .filter(|r| !documented.contains(r))
+            .collect();
+        assert!(
+            missing.is_empty(),
+            "routes missing from the OpenAPI document: {:?}",
+            missing
+        );
+
+        let extra: Vec<&(String, String)> =
+            documented.iter().filter(|d| !routes.contains(d)).collect();
+        assert!(
+            extra.is_empty(),
+            "documented routes the server doesn't serve: {:?}",
+            extra
+        );
+    }
+}
@@ -1,115 +1,267 @@
 // jkcoxson
 
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use serde::Serialize;
+use utoipa::ToSchema;
 
 use crate::backend::Counter;
+use crate::jobs::Job;
+use crate::usage::UsageSummary;
+
+/// The reply to `/status`.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusPacket {
+    pub valid_ip: bool,
+    pub registered: bool,
+    pub mounting: bool,
+    /// The bundle ID waiting for the mount to finish, if any
+    pub pending_launch: Option<String>,
+    pub mount_message: String,
+    pub mount_finished: bool,
+}
+
+/// The reply to routes that only report whether they worked.
+#[derive(Serialize, ToSchema)]
+pub struct MessagePacket {
+    pub success: bool,
+    pub message: String,
+}
+
+/// The reply to routes that hand out a device token.
+#[derive(Serialize, ToSchema)]
+pub struct TokenPacket {
+    pub success: bool,
+    pub message: String,
+    /// Send this in the x-jitstreamer-token header, it is only shown once
+    pub token: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PotentialPairPacket {
+    pub success: bool,
+    pub message: String,
+    /// The code to send to `/potential_follow_up`, 0 on failure
+    pub code: u16,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ListAppsPacket {
+    pub success: bool,
+    pub message: String,
+    /// Display names mapped to bundle IDs
+    pub list: BTreeMap<String, String>,
+    /// Apps known to need JIT, mapped the same way
+    #[serde(rename = "preferedList")] // TODO: fix this spelling in the next release
+    pub preferred_list: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CensusPacket {
+    pub launched: usize,
+    pub attached: usize,
+    pub fetched: usize,
+    pub netmuxd: usize,
+    /// Seconds since the server started
+    pub uptime: u64,
+    pub clients: usize,
+    pub version: String,
+    /// Usage history that survives restarts, unlike the counters above
+    pub usage: UsageSummary,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UploadSessionPacket {
+    pub success: bool,
+    pub message: String,
+    pub id: String,
+    /// How many bytes the server has, resume uploading from here
+    pub offset: u64,
+}
+
+/// The reply to a route started with `?async=true`.
+#[derive(Serialize, ToSchema)]
+pub struct JobPacket {
+    pub success: bool,
+    pub message: String,
+    /// The job ID to poll at `/jobs/{id}`
+    pub job: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct JobStatusPacket {
+    pub success: bool,
+    pub message: String,
+    pub job: Option<Job>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct JobListPacket {
+    pub success: bool,
+    pub message: String,
+    pub jobs: Vec<Job>,
+}
+
+fn message_packet(success: bool, message: &str) -> String {
+    serde_json::to_string(&MessagePacket {
+        success,
+        message: message.to_string(),
+    })
+    .unwrap()
+}
 
 pub fn status_packet(
     valid_ip: bool,
     registered: bool,
     mounting: bool,
     mount_message: &str,
+    pending_launch: Option<String>,
 ) -> String {
-    let mut packet: serde_json::Value = serde_json::Value::Object(serde_json::Map::new());
-    packet["validIp"] = serde_json::Value::Bool(valid_ip);
-    packet["registered"] = serde_json::Value::Bool(registered);
-    packet["mounting"] = serde_json::Value::Bool(mounting);
-    if mount_message.is_empty() {
-        packet["mountMessage"] = serde_json::Value::String(
+    let (mount_message, mount_finished) = if mount_message.is_empty() {
+        (
             "JitStreamer is still mounting your DMG, check back in a few minutes.".to_string(),
-        );
-        packet["mountFinished"] = serde_json::Value::Bool(false)
+            false,
+        )
     } else {
-        packet["mountMessage"] = serde_json::Value::String(mount_message.to_string());
-        packet["mountFinished"] = serde_json::Value::Bool(true)
-    }
-    serde_json::to_string(&packet).unwrap()
+        (mount_message.to_string(), true)
+    };
+    serde_json::to_string(&StatusPacket {
+        valid_ip,
+        registered,
+        mounting,
+        pending_launch,
+        mount_message,
+        mount_finished,
+    })
+    .unwrap()
 }
 
 pub fn upload_response(success: bool, message: &str) -> String {
-    let mut packet: serde_json::Value = serde_json::Value::Object(serde_json::Map::new());
-    packet["success"] = serde_json::Value::Bool(success);
-    packet["message"] = serde_json::Value::String(message.to_string());
-    serde_json::to_string(&packet).unwrap()
+    message_packet(success, message)
+}
+
+pub fn token_response(success: bool, message: &str, token: &str) -> String {
+    serde_json::to_string(&TokenPacket {
+        success,
+        message: message.to_string(),
+        token: token.to_string(),
+    })
+    .unwrap()
 }
 
 pub fn potential_pair_response(success: bool, message: &str, code: u16) -> String {
-    let mut packet: serde_json::Value = serde_json::Value::Object(serde_json::Map::new());
-    packet["success"] = serde_json::Value::Bool(success);
-    packet["message"] = serde_json::Value::String(message.to_string());
-    packet["code"] = serde_json::Value::Number(serde_json::Number::from(code));
-    serde_json::to_string(&packet).unwrap()
+    serde_json::to_string(&PotentialPairPacket {
+        success,
+        message: message.to_string(),
+        code,
+    })
+    .unwrap()
 }
 
 pub fn potential_follow_up_response(success: bool, message: &str) -> String {
-    let mut packet: serde_json::Value = serde_json::Value::Object(serde_json::Map::new());
-    packet["success"] = serde_json::Value::Bool(success);
-    packet["message"] = serde_json::Value::String(message.to_string());
-    serde_json::to_string(&packet).unwrap()
+    message_packet(success, message)
 }
 
 pub fn unregister_response(success: bool, message: &str) -> String {
-    let mut packet: serde_json::Value = serde_json::Value::Object(serde_json::Map::new());
-    packet["success"] = serde_json::Value::Bool(success);
-    packet["message"] = serde_json::Value::String(message.to_string());
-    serde_json::to_string(&packet).unwrap()
+    message_packet(success, message)
 }
 
 pub fn list_apps_response(
     success: bool,
     message: &str,
-    list: serde_json::Value,
-    preferred_list: serde_json::Value,
+    list: BTreeMap<String, String>,
+    preferred_list: BTreeMap<String, String>,
 ) -> String {
-    let mut packet: serde_json::Value = serde_json::Value::Object(serde_json::Map::new());
-    packet["success"] = serde_json::Value::Bool(success);
-    packet["message"] = serde_json::Value::String(message.to_string());
-    packet["list"] = list;
-    packet["preferedList"] = preferred_list; // TODO: fix this spelling in the next release
-    serde_json::to_string(&packet).unwrap()
+    serde_json::to_string(&ListAppsPacket {
+        success,
+        message: message.to_string(),
+        list,
+        preferred_list,
+    })
+    .unwrap()
 }
 
 pub fn launch_response(success: bool, message: &str) -> String {
-    let mut packet: serde_json::Value = serde_json::Value::Object(serde_json::Map::new());
-    packet["success"] = serde_json::Value::Bool(success);
-    packet["message"] = serde_json::Value::String(message.to_string());
-    serde_json::to_string(&packet).unwrap()
+    message_packet(success, message)
 }
 
 pub fn attach_response(sucess: bool, message: &str) -> String {
-    let mut packet: serde_json::Value = serde_json::Value::Object(serde_json::Map::new());
-    packet["success"] = serde_json::Value::Bool(sucess);
-    packet["message"] = serde_json::Value::String(message.to_string());
-    serde_json::to_string(&packet).unwrap()
-}
-
-pub fn census_response(counter: Counter, clients: usize, version: String) -> String {
-    let mut packet: serde_json::Value = serde_json::Value::Object(serde_json::Map::new());
-    packet["launched"] = serde_json::Value::Number(serde_json::Number::from(counter.launched));
-    packet["attached"] = serde_json::Value::Number(serde_json::Number::from(counter.attached));
-    packet["fetched"] = serde_json::Value::Number(serde_json::Number::from(counter.fetched));
-    packet["netmuxd"] = serde_json::Value::Number(serde_json::Number::from(counter.netmuxd));
+    message_packet(sucess, message)
+}
+
+pub fn census_response(
+    counter: Counter,
+    clients: usize,
+    version: String,
+    usage: UsageSummary,
+) -> String {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs();
-    packet["uptime"] =
-        serde_json::Value::Number(serde_json::Number::from(now - counter.uptime.as_secs()));
-    packet["clients"] = serde_json::Value::Number(serde_json::Number::from(clients));
-    packet["version"] = serde_json::Value::String(version);
-    serde_json::to_string(&packet).unwrap()
+    serde_json::to_string(&CensusPacket {
+        launched: counter.launched,
+        attached: counter.attached,
+        fetched: counter.fetched,
+        netmuxd: counter.netmuxd,
+        uptime: now - counter.uptime.as_secs(),
+        clients,
+        version,
+        usage,
+    })
+    .unwrap()
+}
+
+pub fn progress_response(success: bool, message: &str) -> String {
+    message_packet(success, message)
 }
 
 pub fn install_response(success: bool, message: &str) -> String {
-    let mut packet: serde_json::Value = serde_json::Value::Object(serde_json::Map::new());
-    packet["success"] = serde_json::Value::Bool(success);
-    packet["message"] = serde_json::Value::String(message.to_string());
-    serde_json::to_string(&packet).unwrap()
+    message_packet(success, message)
+}
+
+pub fn upload_session_response(success: bool, message: &str, id: &str, offset: u64) -> String {
+    serde_json::to_string(&UploadSessionPacket {
+        success,
+        message: message.to_string(),
+        id: id.to_string(),
+        offset,
+    })
+    .unwrap()
+}
+
+pub fn job_response(success: bool, message: &str, id: &str) -> String {
+    serde_json::to_string(&JobPacket {
+        success,
+        message: message.to_string(),
+        job: id.to_string(),
+    })
+    .unwrap()
+}
+
+pub fn job_status_response(success: bool, message: &str, job: Option<Job>) -> String {
+    serde_json::to_string(&JobStatusPacket {
+        success,
+        message: message.to_string(),
+        job,
+    })
+    .unwrap()
+}
+
+pub fn job_list_response(success: bool, message: &str, jobs: Vec<Job>) -> String {
+    serde_json::to_string(&JobListPacket {
+        success,
+        message: message.to_string(),
+        jobs,
+    })
+    .unwrap()
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct Version {
     pub version: String,
 }
@@ -0,0 +1,280 @@
+// jkcoxson
+
+use log::{info, warn};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config::Config, error::Error};
+
+/// The codes handed out by `/potential`, which let another machine pair a device.
+pub struct PairCodes {
+    codes: Vec<PairCode>,
+    /// Failed follow ups by the IP they came from
+    failures: HashMap<String, Failures>,
+    /// How long a code can be used for, in seconds
+    lifetime: u64,
+    /// Failed follow ups allowed per code, and per IP within a code's lifetime
+    max_attempts: usize,
+}
+
+#[derive(Debug)]
+pub struct PairCode {
+    /// The address of the device that asked for the code
+    pub ip: String,
+    pub code: u16,
+    /// Seconds since the epoch
+    pub expires: u64,
+    pub failures: usize,
+}
+
+struct Failures {
+    count: usize,
+    /// When the first of these failures happened, in seconds since the epoch
+    since: u64,
+}
+
+impl PairCodes {
+    pub fn new(config: &Config) -> PairCodes {
+        PairCodes {
+            codes: vec![],
+            failures: HashMap::new(),
+            lifetime: config.pairing.code_lifetime * 60,
+            max_attempts: config.pairing.max_attempts,
+        }
+    }
+
+    /// Issues a code for a device, different from every other live code.
+    pub fn issue(&mut self, ip: String) -> u16 {
+        self.sweep();
+        let mut rng = rand::thread_rng();
+        let code = loop {
+            let code: u16 = rng.gen_range(10000..65535);
+            if !self.codes.iter().any(|c| c.code == code) {
+                break code;
+            }
+        };
+        self.codes.push(PairCode {
+            ip,
+            code,
+            expires: now() + self.lifetime,
+            failures: 0,
+        });
+        code
+    }
+
+    /// Takes a code out for a follow up, so nothing else can use it at the same time.
+    /// Give it back with `release` if the follow up fails.
+    pub fn claim(&mut self, code: u16, source: &str) -> Result<PairCode, Error> {
+        let now = now();
+        if let Some(failures) = self.failures.get(source) {
+            if failures.count >= self.max_attempts && failures.since + self.lifetime > now {
+                warn!("{} is making too many pairing attempts", source);
+                return Err(Error::TooManyAttempts);
+            }
+        }
+        match self
+            .codes
+            .iter()
+            .position(|c| c.code == code && c.expires > now)
+        {
+            Some(i) => Ok(self.codes.remove(i)),
+            None => {
+                self.fail(source, now);
+                Err(Error::InvalidCode)
+            }
+        }
+    }
+
+    /// Puts a code back after a failed follow up, unless it has run out of attempts.
+    pub fn release(&mut self, mut code: PairCode, source: &str) {
+        let now = now();
+        self.fail(source, now);
+        code.failures += 1;
+        if code.failures >= self.max_attempts {
+            warn!(
+                "Pair code {} was dropped after too many failures",
+                code.code
+            );
+        } else if code.expires > now {
+            self.codes.push(code);
+        }
+    }
+
+    /// Counts a failed follow up against the IP it came from.
+    fn fail(&mut self, source: &str, now: u64) {
+        let lifetime = self.lifetime;
+        let failures = self.failures.entry(source.to_string()).or_insert(Failures {
+            count: 0,
+            since: now,
+        });
+        if failures.since + lifetime <= now {
+            *failures = Failures {
+                count: 0,
+                since: now,
+            };
+        }
+        failures.count += 1;
+    }
+
+    /// Drops every outstanding code, returning how many there were.
+    pub fn clear(&mut self) -> usize {
+        let count = self.codes.len();
+        self.codes.clear();
+        count
+    }
+
+    /// Drops expired codes and forgets failures older than a code's lifetime.
+    pub fn sweep(&mut self) {
+        let now = now();
+        let before = self.codes.len();
+        self.codes.retain(|c| c.expires > now);
+        let expired = before - self.codes.len();
+        if expired > 0 {
+            info!("{} pair codes expired", expired);
+        }
+        let lifetime = self.lifetime;
+        self.failures.retain(|_, f| f.since + lifetime > now);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: &str = "10.7.0.2";
+    const OTHER_IP: &str = "10.7.0.3";
+
+    fn codes(max_attempts: usize) -> PairCodes {
+        PairCodes {
+            codes: vec![],
+            failures: HashMap::new(),
+            lifetime: 10 * 60,
+            max_attempts,
+        }
+    }
+
+    #[test]
+    fn a_code_can_only_be_claimed_once() {
+        let mut codes = codes(5);
+        let code = codes.issue(IP.to_string());
+
+        let claimed = codes.claim(code, OTHER_IP).unwrap();
+        assert_eq!(claimed.ip, IP);
+        assert!(matches!(
+            codes.claim(code, OTHER_IP),
+            Err(Error::InvalidCode)
+        ));
+    }
+
+    #[test]
+    fn an_expired_code_is_rejected_and_swept() {
+        let mut codes = codes(5);
+        let code = codes.issue(IP.to_string());
+        codes.codes[0].expires = now();
+
+        assert!(matches!(
+            codes.claim(code, OTHER_IP),
+            Err(Error::InvalidCode)
+        ));
+        codes.sweep();
+        assert!(codes.codes.is_empty());
+    }
+
+    #[test]
+    fn a_released_code_can_be_claimed_again() {
+        let mut codes = codes(5);
+        let code = codes.issue(IP.to_string());
+
+        let claimed = codes.claim(code, OTHER_IP).unwrap();
+        codes.release(claimed, OTHER_IP);
+        let claimed = codes.claim(code, OTHER_IP).unwrap();
+        assert_eq!(claimed.failures, 1);
+    }
+
+    #[test]
+    fn a_code_is_dropped_after_too_many_failures() {
+        let mut codes = codes(3);
+        let code = codes.issue(IP.to_string());
+
+        // From different IPs, so only the code's own count runs out
+        for source in ["10.7.0.10", "10.7.0.11", "10.7.0.12"] {
+            let claimed = codes.claim(code, source).unwrap();
+            codes.release(claimed, source);
+        }
+        assert!(codes.codes.is_empty());
+        assert!(matches!(
+            codes.claim(code, OTHER_IP),
+            Err(Error::InvalidCode)
+        ));
+    }
+
+    #[test]
+    fn a_code_released_after_it_expired_is_dropped() {
+        let mut codes = codes(5);
+        let code = codes.issue(IP.to_string());
+
+        let mut claimed = codes.claim(code, OTHER_IP).unwrap();
+        claimed.expires = now();
+        codes.release(claimed, OTHER_IP);
+        assert!(codes.codes.is_empty());
+    }
+
+    #[test]
+    fn an_ip_is_turned_away_after_too_many_failures() {
+        let mut codes = codes(3);
+        let code = codes.issue(IP.to_string());
+        let wrong = if code == 10000 { 10001 } else { 10000 };
+
+        for _ in 0..3 {
+            assert!(matches!(
+                codes.claim(wrong, OTHER_IP),
+                Err(Error::InvalidCode)
+            ));
+        }
+        // Even the right code, until the window is over
+        assert!(matches!(
+            codes.claim(code, OTHER_IP),
+            Err(Error::TooManyAttempts)
+        ));
+        // Other IPs aren't affected
+        assert!(codes.claim(code, IP).is_ok());
+    }
+
+    #[test]
+    fn the_failure_window_resets_after_a_code_lifetime() {
+        let mut codes = codes(3);
+        let code = codes.issue(IP.to_string());
+        let wrong = if code == 10000 { 10001 } else { 10000 };
+        for _ in 0..3 {
+            let _ = codes.claim(wrong, OTHER_IP);
+        }
+
+        let lifetime = codes.lifetime;
+        codes.failures.get_mut(OTHER_IP).unwrap().since = now() - lifetime;
+        // The next failure starts counting again from one
+        let _ = codes.claim(wrong, OTHER_IP);
+        assert_eq!(codes.failures[OTHER_IP].count, 1);
+        assert!(codes.claim(code, OTHER_IP).is_ok());
+    }
+
+    #[test]
+    fn sweeping_forgets_old_failures() {
+        let mut codes = codes(3);
+        let _ = codes.claim(10000, OTHER_IP);
+        let lifetime = codes.lifetime;
+        codes.failures.get_mut(OTHER_IP).unwrap().since = now() - lifetime;
+
+        codes.sweep();
+        assert!(codes.failures.is_empty());
+    }
+}
@@ -0,0 +1,619 @@
+// jkcoxson
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use log::{info, warn};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    config::Config,
+    error::Error,
+    pairing_record::udid_from_filename,
+    storage::{self, write_atomic, write_private},
+};
+
+/// Starts every encrypted record, followed by a version byte.
+const MAGIC: &[u8] = b"JSPR";
+const VERSION: u8 = 1;
+const FINGERPRINT_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 1 + FINGERPRINT_LEN + NONCE_LEN;
+
+/// Where pairing records live.
+/// Without a key they are plain plists in plist_storage, like lockdown keeps them.
+/// With one they are encrypted in the record path, and plist_storage only gets decrypted
+/// copies for the muxer to read while a device is being used.
+pub struct PairingStore {
+    plist_storage: String,
+    encryption: Option<Encryption>,
+    /// Devices with a new record on trial, see begin_trial
+    trials: Mutex<HashSet<String>>,
+}
+
+struct Encryption {
+    record_path: String,
+    /// The key records are encrypted with, then any other key records may still be under
+    keys: Vec<[u8; 32]>,
+    /// How long a decrypted copy is left in plist_storage
+    copy_lifetime: Duration,
+}
+
+impl PairingStore {
+    pub fn new(config: &Config) -> Result<PairingStore, Error> {
+        let keys = load_keys(config)?;
+        let encryption = match keys.is_empty() {
+            true => None,
+            false => {
+                std::fs::create_dir_all(&config.encryption.record_path)
+                    .map_err(Error::io("Unable to create the pairing record directory"))?;
+                Some(Encryption {
+                    record_path: config.encryption.record_path.clone(),
+                    keys,
+                    copy_lifetime: Duration::from_secs(config.encryption.copy_lifetime * 60),
+                })
+            }
+        };
+        Ok(PairingStore {
+            plist_storage: config.paths.plist_storage.clone(),
+            encryption,
+            trials: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Where the muxer reads a device's pairing record from.
+    fn plain_path(&self, udid: &str) -> PathBuf {
+        Path::new(&self.plist_storage).join(format!("{}.plist", udid))
+    }
+
+    /// Saves a device's pairing record.
+    /// When encrypting, a decrypted copy is left for the muxer so the device can be tested.
+    /// Records in plist_storage can only be read by the user JitStreamer runs as.
+    pub fn write(&self, udid: &str, plist: &[u8]) -> Result<(), Error> {
+        if let Some(encryption) = &self.encryption {
+            let sealed = encryption.seal(udid, plist)?;
+            write_atomic(encryption.path(udid), &sealed).map_err(Error::PairingFile)?;
+        }
+        write_private(self.plain_path(udid), plist).map_err(Error::PairingFile)
+    }
+
+    /// Where the record a trial replaced is kept until the trial is over.
+    fn previous_path(&self, udid: &str) -> PathBuf {
+        Path::new(&self.plist_storage).join(format!("{}.plist.previous", udid))
+    }
+
+    /// Puts a new pairing record where the muxer reads it, so lockdown can be tested with it
+    /// before it is saved. The record it replaces is moved aside, and is put back by
+    /// rollback_trial unless commit_trial saves the new one.
+    pub fn begin_trial(&self, udid: &str, plist: &[u8]) -> Result<(), Error> {
+        match self.trials.lock() {
+            Ok(mut trials) => {
+                if !trials.insert(udid.to_string()) {
+                    return Err(Error::PairingInProgress);
+                }
+            }
+            Err(_) => return Err(Error::PairingInProgress),
+        }
+        // Staged beside the live record first, so a failed write leaves it alone
+        let path = self.plain_path(udid);
+        let staged = Path::new(&self.plist_storage).join(format!("{}.plist.staged", udid));
+        if let Err(e) = write_private(&staged, plist) {
+            self.end_trial(udid);
+            return Err(Error::PairingFile(e));
+        }
+        match std::fs::rename(&path, self.previous_path(udid)) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                let _ = std::fs::remove_file(&staged);
+                self.end_trial(udid);
+                return Err(Error::PairingFile(e));
+            }
+        }
+        if let Err(e) = std::fs::rename(&staged, &path) {
+            let _ = std::fs::remove_file(&staged);
+            self.rollback_trial(udid)?;
+            return Err(Error::PairingFile(e));
+        }
+        Ok(())
+    }
+
+    /// Saves the record on trial, the one it replaced is thrown away.
+    pub fn commit_trial(&self, udid: &str, plist: &[u8]) -> Result<(), Error> {
+        let res = self.write(udid, plist);
+        if res.is_ok() {
+            remove_if_exists(&self.previous_path(udid))?;
+        }
+        self.end_trial(udid);
+        res
+    }
+
+    /// Puts back the record a trial replaced, or removes the new one if there wasn't one.
+    pub fn rollback_trial(&self, udid: &str) -> Result<(), Error> {
+        let res = match std::fs::rename(self.previous_path(udid), self.plain_path(udid)) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => remove_if_exists(&self.plain_path(udid)),
+            Err(e) => Err(Error::Io {
+                context: "Unable to restore the previous pairing file",
+                cause: e,
+            }),
+        };
+        self.end_trial(udid);
+        res
+    }
+
+    fn end_trial(&self, udid: &str) {
+        if let Ok(mut trials) = self.trials.lock() {
+            trials.remove(udid);
+        }
+    }
+
+    /// Reads a device's pairing record into memory.
+    pub fn read(&self, udid: &str) -> Result<Vec<u8>, Error> {
+        match &self.encryption {
+            Some(encryption) => {
+                let sealed = std::fs::read(encryption.path(udid))
+                    .map_err(Error::io("Unable to read pairing file"))?;
+                encryption.open(udid, &sealed)
+            }
+            None => std::fs::read(self.plain_path(udid))
+                .map_err(Error::io("Unable to read pairing file")),
+        }
+    }
+
+    pub fn remove(&self, udid: &str) -> Result<(), Error> {
+        if let Some(encryption) = &self.encryption {
+            remove_if_exists(&encryption.path(udid))?;
+        }
+        remove_if_exists(&self.plain_path(udid))
+    }
+
+    /// The UDIDs of every stored pairing record.
+    /// Without a key, files in plist_storage not named after a UDID are left out.
+    pub fn list(&self) -> Result<Vec<String>, Error> {
+        let (dir, suffix) = match &self.encryption {
+            Some(encryption) => (encryption.record_path.as_str(), ".plist.enc"),
+            None => (self.plist_storage.as_str(), ".plist"),
+        };
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => {
+                return Err(Error::Io {
+                    context: "Unable to list pairing files",
+                    cause: e,
+                })
+            }
+        };
+        Ok(entries
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let udid = name.strip_suffix(suffix)?;
+                udid_from_filename(&format!("{}.plist", udid)).map(str::to_string)
+            })
+            .collect())
+    }
+
+    /// Whether a record no device owns can be removed. Only records JitStreamer encrypted
+    /// qualify, plist_storage may hold usbmuxd's own, and only once they have been left
+    /// alone for `grace`, so a registration still being saved isn't raced.
+    pub fn is_removable(&self, udid: &str, grace: Duration) -> bool {
+        let encryption = match &self.encryption {
+            Some(encryption) => encryption,
+            None => return false,
+        };
+        if self
+            .trials
+            .lock()
+            .map_or(true, |trials| trials.contains(udid))
+        {
+            return false;
+        }
+        let age = std::fs::metadata(encryption.path(udid))
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        age.is_some_and(|age| age >= grace)
+    }
+
+    /// Makes sure the muxer can read a device's pairing record, before talking to the device.
+    pub fn materialize(&self, udid: &str) -> Result<(), Error> {
+        if self.encryption.is_none() {
+            return Ok(());
+        }
+        let plist = self.read(udid)?;
+        write_private(self.plain_path(udid), &plist).map_err(Error::PairingFile)
+    }
+
+    /// Removes decrypted copies that have been left longer than their lifetime.
+    /// Only records JitStreamer has an encrypted copy of are touched.
+    pub fn sweep_copies(&self) {
+        let encryption = match &self.encryption {
+            Some(encryption) => encryption,
+            None => return,
+        };
+        let entries = match std::fs::read_dir(&self.plist_storage) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Unable to read {}: {}", self.plist_storage, e);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let udid = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) => match name.strip_suffix(".plist") {
+                    Some(udid) => udid.to_string(),
+                    None => continue,
+                },
+                None => continue,
+            };
+            if !encryption.path(&udid).exists() {
+                continue;
+            }
+            let age = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+            if age.is_some_and(|age| age >= encryption.copy_lifetime) {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    warn!(
+                        "Unable to remove the decrypted copy {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    /// Encrypts plain pairing records left from before a key was set, then removes them.
+    pub fn import_plaintext(&self, udids: &[String]) -> Result<(), Error> {
+        let encryption = match &self.encryption {
+            Some(encryption) => encryption,
+            None => return Ok(()),
+        };
+        for udid in udids {
+            if encryption.path(udid).exists() {
+                continue;
+            }
+            let plist = match std::fs::read(self.plain_path(udid)) {
+                Ok(plist) => plist,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(Error::Io {
+                        context: "Unable to read pairing file",
+                        cause: e,
+                    })
+                }
+            };
+            let sealed = encryption.seal(udid, &plist)?;
+            write_atomic(encryption.path(udid), &sealed).map_err(Error::PairingFile)?;
+            remove_if_exists(&self.plain_path(udid))?;
+            info!("Encrypted the pairing record for {}", udid);
+        }
+        Ok(())
+    }
+
+    /// Encrypts every record under a new key and replaces the key file, returning how many.
+    /// The new key is written beside the old one first, so an interrupted rotation can be
+    /// run again without losing any records.
+    /// A running server only reads its keys at startup, so it has to be stopped first.
+    pub fn rotate_key(config: &Config) -> Result<usize, Error> {
+        let _lock = storage::lock_configured(config)?;
+        let key_file = match &config.encryption.key_file {
+            Some(key_file) => key_file,
+            None => {
+                return Err(Error::PairingKey(
+                    "rotating the key needs key_file set in [encryption]".to_string(),
+                ))
+            }
+        };
+        let store = PairingStore::new(config)?;
+        let encryption = match &store.encryption {
+            Some(encryption) => encryption,
+            None => return Err(Error::PairingKey("no key is configured".to_string())),
+        };
+
+        // Finish an interrupted rotation with the key it started, some records already use it
+        let pending = format!("{}.new", key_file);
+        let new_key = match std::fs::read_to_string(&pending) {
+            Ok(key) => parse_key(&key)?,
+            Err(_) => {
+                let key: [u8; 32] = rand::thread_rng().gen();
+                write_key(&pending, &key)?;
+                key
+            }
+        };
+        let rotated = Encryption {
+            record_path: encryption.record_path.clone(),
+            keys: vec![new_key],
+            copy_lifetime: encryption.copy_lifetime,
+        };
+
+        let entries = std::fs::read_dir(&encryption.record_path)
+            .map_err(Error::io("Unable to read the pairing record directory"))?;
+        let mut count = 0;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let udid = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) => match name.strip_suffix(".plist.enc") {
+                    Some(udid) => udid.to_string(),
+                    None => continue,
+                },
+                None => continue,
+            };
+            let sealed = std::fs::read(&path).map_err(Error::io("Unable to read pairing file"))?;
+            let plist = encryption.open(&udid, &sealed)?;
+            write_atomic(&path, &rotated.seal(&udid, &plist)?).map_err(Error::PairingFile)?;
+            count += 1;
+        }
+
+        std::fs::rename(&pending, key_file).map_err(Error::io("Unable to replace the key file"))?;
+        Ok(count)
+    }
+}
+
+impl Encryption {
+    fn path(&self, udid: &str) -> PathBuf {
+        Path::new(&self.record_path).join(format!("{}.plist.enc", udid))
+    }
+
+    /// Encrypts a record under the current key. The UDID is authenticated with it,
+    /// so a record can't be passed off as another device's.
+    fn seal(&self, udid: &str, plist: &[u8]) -> Result<Vec<u8>, Error> {
+        let key = &self.keys[0];
+        let nonce: [u8; NONCE_LEN] = rand::thread_rng().gen();
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+        let payload = Payload {
+            msg: plist,
+            aad: udid.as_bytes(),
+        };
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| Error::PairingDecrypt(udid.to_string()))?;
+
+        let mut sealed = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.push(VERSION);
+        sealed.extend_from_slice(&fingerprint(key));
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn open(&self, udid: &str, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        let failed = || Error::PairingDecrypt(udid.to_string());
+        if sealed.len() < HEADER_LEN
+            || &sealed[..MAGIC.len()] != MAGIC
+            || sealed[MAGIC.len()] != VERSION
+        {
+            return Err(failed());
+        }
+        let rest = &sealed[MAGIC.len() + 1..];
+        let (key_id, rest) = rest.split_at(FINGERPRINT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let key = match self.keys.iter().find(|k| fingerprint(k) == key_id) {
+            Some(key) => key,
+            None => return Err(failed()),
+        };
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+        let payload = Payload {
+            msg: ciphertext,
+            aad: udid.as_bytes(),
+        };
+        cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| failed())
+    }
+}
+
+/// Identifies which key a record was encrypted with, without giving the key away.
+fn fingerprint(key: &[u8; 32]) -> [u8; FINGERPRINT_LEN] {
+    let digest = Sha256::digest(key);
+    let mut out = [0; FINGERPRINT_LEN];
+    out.copy_from_slice(&digest[..FINGERPRINT_LEN]);
+    out
+}
+
+/// Reads the configured key, creating the key file if it doesn't exist yet.
+/// A key left by an interrupted rotation is loaded too, so its records can still be read.
+fn load_keys(config: &Config) -> Result<Vec<[u8; 32]>, Error> {
+    if let Some(key) = &config.encryption.key {
+        return Ok(vec![parse_key(key)?]);
+    }
+    let key_file = match &config.encryption.key_file {
+        Some(key_file) => key_file,
+        None => return Ok(vec![]),
+    };
+    let key = match std::fs::read_to_string(key_file) {
+        Ok(key) => parse_key(&key)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let key: [u8; 32] = rand::thread_rng().gen();
+            write_key(key_file, &key)?;
+            println!(
+                "Created a new pairing record key in {}, back it up",
+                key_file
+            );
+            key
+        }
+        Err(e) => {
+            return Err(Error::Io {
+                context: "Unable to read the key file",
+                cause: e,
+            })
+        }
+    };
+    let mut keys = vec![key];
+    let pending = format!("{}.new", key_file);
+    if let Ok(key) = std::fs::read_to_string(&pending) {
+        warn!("A key rotation was interrupted, run it again to finish it");
+        keys.push(parse_key(&key)?);
+    }
+    Ok(keys)
+}
+
+fn parse_key(key: &str) -> Result<[u8; 32], Error> {
+    let bytes = hex::decode(key.trim())
+        .map_err(|_| Error::PairingKey("the key must be 64 hex characters".to_string()))?;
+    bytes
+        .try_into()
+        .map_err(|_| Error::PairingKey("the key must be 64 hex characters".to_string()))
+}
+
+/// Writes a key file only the owner can read.
+fn write_key(path: &str, key: &[u8; 32]) -> Result<(), Error> {
+    write_private(path, hex::encode(key).as_bytes())
+        .map_err(Error::io("Unable to write the key file"))
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), Error> {
+    match std::fs::remove_file(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Error::Io {
+            context: "Unable to remove pairing file",
+            cause: e,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UDID: &str = "00008030-001A2B3C4D5E6F70";
+    const OTHER_UDID: &str = "00008101-000A1B2C3D4E5F60";
+    const PLIST: &[u8] = b"<plist><dict><key>HostID</key><string>host</string></dict></plist>";
+
+    fn encryption(keys: Vec<[u8; 32]>) -> Encryption {
+        Encryption {
+            record_path: String::new(),
+            keys,
+            copy_lifetime: Duration::from_secs(60),
+        }
+    }
+
+    /// A config with a key file, keeping everything in a fresh directory.
+    fn config(dir: &Path) -> Config {
+        let mut config: Config = toml::from_str(crate::config::DEFAULT).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        config.paths.plist_storage = path("lockdown");
+        config.paths.database_path = path("database.json");
+        config.encryption.key_file = Some(path("pairing.key"));
+        config.encryption.record_path = path("records");
+        std::fs::create_dir_all(&config.paths.plist_storage).unwrap();
+        config
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "jitstreamer-test-{:016x}",
+            rand::thread_rng().gen::<u64>()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn a_sealed_record_opens_again() {
+        let encryption = encryption(vec![rand::thread_rng().gen()]);
+        let sealed = encryption.seal(UDID, PLIST).unwrap();
+
+        assert_eq!(&sealed[..MAGIC.len()], MAGIC);
+        assert_eq!(sealed[MAGIC.len()], VERSION);
+        assert_eq!(encryption.open(UDID, &sealed).unwrap(), PLIST);
+    }
+
+    #[test]
+    fn a_record_only_opens_for_its_own_udid() {
+        let encryption = encryption(vec![rand::thread_rng().gen()]);
+        let sealed = encryption.seal(UDID, PLIST).unwrap();
+
+        assert!(matches!(
+            encryption.open(OTHER_UDID, &sealed),
+            Err(Error::PairingDecrypt(_))
+        ));
+    }
+
+    #[test]
+    fn a_record_doesnt_open_under_another_key_or_once_changed() {
+        let sealed = encryption(vec![rand::thread_rng().gen()])
+            .seal(UDID, PLIST)
+            .unwrap();
+        let other = encryption(vec![rand::thread_rng().gen()]);
+        assert!(other.open(UDID, &sealed).is_err());
+
+        let encryption = encryption(vec![rand::thread_rng().gen()]);
+        let mut sealed = encryption.seal(UDID, PLIST).unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(encryption.open(UDID, &sealed).is_err());
+        assert!(encryption.open(UDID, &sealed[..HEADER_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn records_open_after_an_interrupted_rotation() {
+        let dir = temp_dir();
+        let config = config(&dir);
+        let store = PairingStore::new(&config).unwrap();
+        store.write(UDID, PLIST).unwrap();
+        store.write(OTHER_UDID, PLIST).unwrap();
+
+        // The rotation wrote its new key and re-encrypted one record before it stopped
+        let key_file = config.encryption.key_file.clone().unwrap();
+        let new_key: [u8; 32] = rand::thread_rng().gen();
+        write_key(&format!("{}.new", key_file), &new_key).unwrap();
+        let rotated = encryption(vec![new_key]);
+        let path = store.encryption.as_ref().unwrap().path(UDID);
+        write_atomic(&path, &rotated.seal(UDID, PLIST).unwrap()).unwrap();
+
+        // Both keys are loaded, so records under either still open
+        let store = PairingStore::new(&config).unwrap();
+        assert_eq!(store.read(UDID).unwrap(), PLIST);
+        assert_eq!(store.read(OTHER_UDID).unwrap(), PLIST);
+
+        // Running the rotation again finishes it with the same key
+        assert_eq!(PairingStore::rotate_key(&config).unwrap(), 2);
+        assert!(!Path::new(&format!("{}.new", key_file)).exists());
+        let store = PairingStore::new(&config).unwrap();
+        assert_eq!(store.encryption.as_ref().unwrap().keys, vec![new_key]);
+        assert_eq!(store.read(UDID).unwrap(), PLIST);
+        assert_eq!(store.read(OTHER_UDID).unwrap(), PLIST);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn plaintext_copies_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir();
+        let config = config(&dir);
+        let store = PairingStore::new(&config).unwrap();
+        store.write(UDID, PLIST).unwrap();
+        store.materialize(UDID).unwrap();
+
+        for path in [
+            store.plain_path(UDID),
+            PathBuf::from(config.encryption.key_file.unwrap()),
+        ] {
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "{}", path.display());
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
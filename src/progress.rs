@@ -0,0 +1,138 @@
+// jkcoxson
+
+use futures::{Stream, StreamExt};
+use log::warn;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+use utoipa::ToSchema;
+
+/// How many events a slow listener can fall behind before it starts skipping.
+const CHANNEL_CAPACITY: usize = 64;
+
+#[derive(Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Download,
+    Unzip,
+    Upload,
+    Mount,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct ProgressEvent {
+    pub udid: String,
+    pub phase: Phase,
+    /// How many bytes of the current phase are complete. Only the download and unzip
+    /// phases count bytes, the upload and mount are reported once as they start
+    pub bytes: u64,
+    /// The total bytes in the current phase, if known
+    pub total: Option<u64>,
+    pub message: String,
+}
+
+/// Broadcasts DDI download and mount progress to anyone listening for a device.
+#[derive(Default)]
+pub struct Progress {
+    senders: HashMap<String, Sender<ProgressEvent>>,
+    /// The last event of every mount still going
+    latest: HashMap<String, ProgressEvent>,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self {
+            senders: HashMap::new(),
+            latest: HashMap::new(),
+        }
+    }
+
+    /// Subscribes to a device's progress, returning the last event of a mount still going
+    /// so a new listener doesn't have to wait for the next update to draw something.
+    pub fn subscribe(&mut self, udid: &str) -> (Option<ProgressEvent>, Receiver<ProgressEvent>) {
+        // Drop the channels of devices everyone stopped listening to
+        self.senders.retain(|_, sender| sender.receiver_count() > 0);
+        let sender = self
+            .senders
+            .entry(udid.to_string())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0);
+        (self.latest.get(udid).cloned(), sender.subscribe())
+    }
+
+    pub fn send(&mut self, event: ProgressEvent) {
+        // Nobody listening is not an error, the channel is only kept while someone is
+        if let Some(sender) = self.senders.get(&event.udid) {
+            if sender.send(event.clone()).is_err() {
+                self.senders.remove(&event.udid);
+            }
+        }
+        match event.phase {
+            Phase::Done | Phase::Failed => {
+                self.latest.remove(&event.udid);
+            }
+            _ => {
+                self.latest.insert(event.udid.clone(), event);
+            }
+        }
+    }
+}
+
+/// Reports progress for a device from a blocking thread.
+pub fn report(
+    progress: &Arc<Mutex<Progress>>,
+    udid: &str,
+    phase: Phase,
+    bytes: u64,
+    total: Option<u64>,
+    message: &str,
+) {
+    match progress.lock() {
+        Ok(mut progress) => progress.send(ProgressEvent {
+            udid: udid.to_string(),
+            phase,
+            bytes,
+            total,
+            message: message.to_string(),
+        }),
+        Err(_) => warn!("Progress mutex poisoned"),
+    }
+}
+
+/// Turns a subscription into a stream of server-sent events.
+pub fn event_stream(
+    latest: Option<ProgressEvent>,
+    rx: Receiver<ProgressEvent>,
+) -> impl Stream<Item = Result<warp::sse::Event, Infallible>> {
+    let updates = futures::stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some((event, rx)),
+                // Skip whatever we missed, the next event has the current totals anyway
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    futures::stream::iter(latest)
+        .chain(updates)
+        .map(|event| Ok(sse_event(&event)))
+}
+
+fn sse_event(event: &ProgressEvent) -> warp::sse::Event {
+    match warp::sse::Event::default()
+        .event("progress")
+        .json_data(event)
+    {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("Unable to serialize progress event: {}", e);
+            warp::sse::Event::default().event("progress")
+        }
+    }
+}
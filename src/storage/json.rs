@@ -0,0 +1,128 @@
+// jkcoxson
+
+use log::{info, warn};
+use std::{
+    fs::File,
+    io::ErrorKind,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{lock, temp_path, write_atomic, Storage};
+use crate::{backend::DeserializedClient, error::Error};
+
+/// Keeps every client in a single pretty printed JSON array.
+pub struct JsonStorage {
+    path: String,
+    _lock: File,
+}
+
+impl JsonStorage {
+    pub fn open(path: &str) -> Result<JsonStorage, Error> {
+        Ok(JsonStorage {
+            path: path.to_string(),
+            _lock: lock(path)?,
+        })
+    }
+}
+
+/// Reads a JSON database, `None` if it doesn't exist.
+/// Parse errors are returned separately so the caller can decide whether to recover.
+#[allow(clippy::type_complexity)]
+fn read_clients(
+    path: &Path,
+) -> Result<Option<Result<Vec<DeserializedClient>, serde_json::Error>>, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(serde_json::from_str(&contents))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::Io {
+            context: "Unable to read the database",
+            cause: e,
+        }),
+    }
+}
+
+impl JsonStorage {
+    /// Reads the clients as they were saved, recovering from an interrupted save.
+    fn read(&mut self) -> Result<Vec<DeserializedClient>, Error> {
+        let saved = read_clients(Path::new(&self.path))?;
+        let interrupted = temp_path(&self.path);
+        if !interrupted.exists() {
+            return match saved {
+                Some(Ok(clients)) => Ok(clients),
+                Some(Err(cause)) => Err(Error::DatabaseDamaged {
+                    path: self.path.clone(),
+                    cause,
+                }),
+                None => {
+                    println!("Failed to open database file, using an empty database");
+                    Ok(vec![])
+                }
+            };
+        }
+
+        // A save was interrupted. The database is only replaced once the new copy is complete,
+        // so the leftover copy is only used when the database itself can't be read.
+        match (saved, read_clients(&interrupted)?) {
+            (Some(Ok(clients)), _) => {
+                warn!(
+                    "Removing {}, left by an interrupted save",
+                    interrupted.display()
+                );
+                let _ = std::fs::remove_file(&interrupted);
+                Ok(clients)
+            }
+            (_, Some(Ok(clients))) => {
+                warn!("Recovered the database from {}", interrupted.display());
+                std::fs::rename(&interrupted, &self.path)
+                    .map_err(Error::io("Unable to recover the database"))?;
+                Ok(clients)
+            }
+            (Some(Err(cause)), _) => Err(Error::DatabaseDamaged {
+                path: self.path.clone(),
+                cause,
+            }),
+            (None, _) => {
+                warn!(
+                    "Removing {}, left by an interrupted save",
+                    interrupted.display()
+                );
+                let _ = std::fs::remove_file(&interrupted);
+                println!("Failed to open database file, using an empty database");
+                Ok(vec![])
+            }
+        }
+    }
+}
+
+impl Storage for JsonStorage {
+    fn load(&mut self) -> Result<Vec<DeserializedClient>, Error> {
+        let mut clients = self.read()?;
+        // Clients saved before last_seen was tracked start counting now, otherwise every
+        // device registered longer ago than the retention period would expire on upgrade
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut backfilled = 0;
+        for client in clients.iter_mut().filter(|c| !c.seen_tracked) {
+            client.last_seen = client.last_seen.max(now);
+            client.seen_tracked = true;
+            backfilled += 1;
+        }
+        if backfilled > 0 {
+            self.save(&clients)?;
+            info!(
+                "Started tracking when {} devices were last seen",
+                backfilled
+            );
+        }
+        Ok(clients)
+    }
+
+    fn save(&mut self, clients: &[DeserializedClient]) -> Result<(), Error> {
+        let contents = serde_json::to_string_pretty(clients)?;
+        write_atomic(&self.path, contents.as_bytes())
+            .map_err(Error::io("Unable to save the database"))
+    }
+}
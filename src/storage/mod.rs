@@ -0,0 +1,128 @@
+// jkcoxson
+
+use fs2::FileExt;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    backend::DeserializedClient,
+    config::{Config, StorageBackend},
+    error::Error,
+};
+
+mod json;
+mod sqlite;
+
+pub use json::JsonStorage;
+pub use sqlite::SqliteStorage;
+
+/// Somewhere registered clients are kept between restarts.
+pub trait Storage: Send {
+    /// Reads every registered client.
+    fn load(&mut self) -> Result<Vec<DeserializedClient>, Error>;
+    /// Replaces everything stored with these clients.
+    fn save(&mut self, clients: &[DeserializedClient]) -> Result<(), Error>;
+}
+
+/// Opens the storage backend chosen in the config.
+pub fn open(config: &Config) -> Result<Box<dyn Storage>, Error> {
+    Ok(match config.storage.backend {
+        StorageBackend::Json => Box::new(JsonStorage::open(&config.paths.database_path)?),
+        StorageBackend::Sqlite => Box::new(SqliteStorage::open(
+            &config.storage.sqlite_path,
+            &config.paths.database_path,
+        )?),
+    })
+}
+
+/// Takes the lock of the storage backend chosen in the config without opening it,
+/// for commands that can't run while a server is using the same data.
+pub fn lock_configured(config: &Config) -> Result<File, Error> {
+    lock(match config.storage.backend {
+        StorageBackend::Json => &config.paths.database_path,
+        StorageBackend::Sqlite => &config.storage.sqlite_path,
+    })
+}
+
+/// Takes an advisory lock beside a database, so a second JitStreamer can't use it at the same time.
+/// The lock is held until the returned file is dropped.
+pub fn lock(path: &str) -> Result<File, Error> {
+    let lock_path = format!("{}.lock", path);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(Error::io("Unable to create the database lock"))?;
+    match file.try_lock_exclusive() {
+        Ok(_) => Ok(file),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+            Err(Error::DatabaseLocked(lock_path))
+        }
+        Err(e) => Err(Error::Io {
+            context: "Unable to lock the database",
+            cause: e,
+        }),
+    }
+}
+
+/// Where `write_atomic` puts the new contents of a file before they replace it.
+pub fn temp_path(path: impl AsRef<Path>) -> PathBuf {
+    let mut name = path.as_ref().as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Replaces a file without ever leaving it half written.
+/// The contents are written and synced beside it, then renamed over the old file.
+pub fn write_atomic(path: impl AsRef<Path>, contents: &[u8]) -> std::io::Result<()> {
+    replace(path.as_ref(), contents, None)
+}
+
+/// Like write_atomic, but only the owner can read the file.
+/// For keys and pairing records, which hold the key that unlocks a device.
+pub fn write_private(path: impl AsRef<Path>, contents: &[u8]) -> std::io::Result<()> {
+    replace(path.as_ref(), contents, Some(0o600))
+}
+
+fn replace(path: &Path, contents: &[u8], mode: Option<u32>) -> std::io::Result<()> {
+    let tmp = temp_path(path);
+    let res = (|| {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(mode);
+        }
+        let mut file = options.open(&tmp)?;
+        // The mode only applies to new files, a temporary file left by a crash keeps its own
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        // Sync the directory too, otherwise the rename itself can be lost in a crash
+        #[cfg(unix)]
+        {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    })();
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    res
+}
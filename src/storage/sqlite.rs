@@ -0,0 +1,177 @@
+// jkcoxson
+
+use log::{info, warn};
+use rusqlite::{params, Connection};
+use std::fs::File;
+
+use super::{lock, JsonStorage, Storage};
+use crate::{
+    backend::{DeserializedClient, PreviousIp},
+    error::Error,
+};
+
+/// Each migration moves the schema up one version, tracked in `PRAGMA user_version`.
+/// Only ever append to this list, installs that already ran a migration won't run it again.
+const MIGRATIONS: &[&str] = &[
+    // 1: the fields database.json started out with
+    "CREATE TABLE clients (
+        udid TEXT PRIMARY KEY NOT NULL,
+        ip TEXT NOT NULL,
+        last_seen INTEGER NOT NULL
+    );",
+    // 2: per-device tokens
+    "ALTER TABLE clients ADD COLUMN token_hash TEXT;",
+    // 3: last_seen was only set at registration before devices could expire,
+    // so every device starts counting from the upgrade
+    "UPDATE clients SET last_seen = MAX(last_seen, CAST(strftime('%s', 'now') AS INTEGER));",
+    // 4: the IPs devices were registered from before they moved
+    "CREATE TABLE ip_history (
+        udid TEXT NOT NULL,
+        ip TEXT NOT NULL,
+        moved_at INTEGER NOT NULL
+    );",
+];
+
+/// Keeps clients in a SQLite database, so a crash mid-save can't lose them.
+pub struct SqliteStorage {
+    conn: Connection,
+    _lock: File,
+}
+
+impl SqliteStorage {
+    /// Opens the database, migrating it to the latest schema.
+    /// An empty database imports any clients in the old JSON database. The JSON database is
+    /// renamed once it has been imported, so an import that failed is tried again next time.
+    pub fn open(path: &str, json_path: &str) -> Result<SqliteStorage, Error> {
+        let lock = lock(path)?;
+        let mut conn = Connection::open(path)?;
+        migrate(&mut conn)?;
+        let mut storage = SqliteStorage { conn, _lock: lock };
+        if std::path::Path::new(json_path).exists() {
+            if storage.load()?.is_empty() {
+                storage.import(json_path)?;
+            } else {
+                warn!(
+                    "Not importing {}, the SQLite database already has clients. \
+                     Move it aside if it was already imported",
+                    json_path
+                );
+            }
+        }
+        Ok(storage)
+    }
+
+    /// Copies the clients out of a JSON database, then renames it so it isn't used by mistake.
+    fn import(&mut self, json_path: &str) -> Result<(), Error> {
+        // A device can only be in the table once, the JSON database may have it more than
+        // once, so only the registration seen most recently is imported
+        let mut clients: Vec<DeserializedClient> = vec![];
+        for client in JsonStorage::open(json_path)?.load()? {
+            match clients.iter_mut().find(|c| c.udid == client.udid) {
+                Some(kept) => {
+                    warn!(
+                        "{} is registered more than once in {}, \
+                         importing the registration seen most recently",
+                        client.udid, json_path
+                    );
+                    if client.last_seen > kept.last_seen {
+                        *kept = client;
+                    }
+                }
+                None => clients.push(client),
+            }
+        }
+        self.save(&clients)?;
+        let imported = format!("{}.imported", json_path);
+        std::fs::rename(json_path, &imported)
+            .map_err(Error::io("Unable to rename the imported database"))?;
+        info!(
+            "Imported {} clients from {}, the old file is now {}",
+            clients.len(),
+            json_path,
+            imported
+        );
+        Ok(())
+    }
+}
+
+/// Runs any migrations the database hasn't seen yet.
+fn migrate(conn: &mut Connection) -> Result<(), Error> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let version = version as usize;
+    if version > MIGRATIONS.len() {
+        return Err(Error::DatabaseVersion(version));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", (i + 1) as i64)?;
+        tx.commit()?;
+        info!("Migrated the database to version {}", i + 1);
+    }
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn load(&mut self) -> Result<Vec<DeserializedClient>, Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT ip, udid, last_seen, token_hash FROM clients")?;
+        let mut clients = stmt
+            .query_map([], |row| {
+                Ok(DeserializedClient {
+                    ip: row.get(0)?,
+                    udid: row.get(1)?,
+                    last_seen: row.get::<_, i64>(2)? as u64,
+                    token_hash: row.get(3)?,
+                    ip_history: vec![],
+                    seen_tracked: true,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT udid, ip, moved_at FROM ip_history ORDER BY rowid")?;
+        let history = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    PreviousIp {
+                        ip: row.get(1)?,
+                        moved_at: row.get::<_, i64>(2)? as u64,
+                    },
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (udid, previous) in history {
+            if let Some(client) = clients.iter_mut().find(|c| c.udid == udid) {
+                client.ip_history.push(previous);
+            }
+        }
+        Ok(clients)
+    }
+
+    fn save(&mut self, clients: &[DeserializedClient]) -> Result<(), Error> {
+        // Everything is replaced in one transaction, so readers never see a partial save
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM clients", [])?;
+        tx.execute("DELETE FROM ip_history", [])?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO clients (ip, udid, last_seen, token_hash)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            let mut history =
+                tx.prepare("INSERT INTO ip_history (udid, ip, moved_at) VALUES (?1, ?2, ?3)")?;
+            for c in clients {
+                stmt.execute(params![c.ip, c.udid, c.last_seen as i64, c.token_hash])?;
+                for previous in &c.ip_history {
+                    history.execute(params![c.udid, previous.ip, previous.moved_at as i64])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+// jkcoxson
+
+use log::warn;
+use std::{collections::BTreeMap, path::PathBuf};
+use tokio::sync::oneshot;
+
+use crate::{
+    backend::Backend,
+    client::Client,
+    error::Error,
+    jobs::JobKind,
+    metrics::{Outcome, Request},
+    usage::EventKind,
+};
+
+/// Resolves once a device operation running on a blocking thread finishes.
+pub type TaskResult = oneshot::Receiver<Result<(), Error>>;
+
+/// A device's installed apps, keyed by display name.
+pub struct AppList {
+    pub apps: BTreeMap<String, String>,
+    pub preferred_apps: BTreeMap<String, String>,
+    /// How many apps were listed, for the census
+    pub count: usize,
+}
+
+/// Fetches and trims the list of apps installed on a device.
+pub async fn get_apps(client: Client) -> Result<AppList, Error> {
+    let recorder = client.clone();
+    let res = tokio::task::spawn_blocking(move || client.get_apps()).await;
+    let outcome = match &res {
+        Ok(res) => Outcome::of(res),
+        Err(_) => Outcome::Failure,
+    };
+    recorder.shared.metrics.request(Request::Fetch, outcome);
+    recorder.record_usage(EventKind::Fetch, None, outcome == Outcome::Success);
+    let v = match res {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
+            warn!("Unable to get apps: {:?}", e);
+            return Err(e);
+        }
+        Err(e) => {
+            warn!("App lookup task failed: {}", e);
+            return Err(Error::Task(e));
+        }
+    };
+
+    // Trim the list of apps
+    let mut list = AppList {
+        apps: BTreeMap::new(),
+        preferred_apps: BTreeMap::new(),
+        count: 0,
+    };
+    for i in v {
+        let i = i.plist;
+        let name = i
+            .clone()
+            .dict_get_item("CFBundleDisplayName")
+            .unwrap()
+            .get_string_val()
+            .unwrap();
+        let bundle_id = i
+            .clone()
+            .dict_get_item("CFBundleIdentifier")
+            .unwrap()
+            .get_string_val()
+            .unwrap();
+        if bundle_id.contains("com.apple") {
+            continue;
+        }
+        if Backend::preferred_app(&name) {
+            list.preferred_apps.insert(name, bundle_id);
+        } else {
+            list.apps.insert(name, bundle_id);
+        }
+        list.count += 1;
+    }
+    Ok(list)
+}
+
+/// Launches an app on a blocking thread, tracked as a job.
+/// Returns the job ID and a receiver for the outcome.
+pub fn launch(client: Client, app: String, defer: bool) -> (String, TaskResult) {
+    let jobs = client.shared.jobs.clone();
+    let job = jobs
+        .lock()
+        .unwrap()
+        .create(client.udid.clone(), JobKind::Launch, app.clone());
+
+    let (tx, rx) = oneshot::channel();
+    let job_id = job.clone();
+    tokio::task::spawn_blocking(move || {
+        jobs.lock().unwrap().start(&job_id);
+        let res = client.debug_app(app.clone(), defer.then_some(job_id.as_str()));
+        let outcome = Outcome::of(&res);
+        client.shared.metrics.request(Request::Launch, outcome);
+        // Launches waiting on a mount are recorded when they actually run
+        if outcome != Outcome::Mounting {
+            client.record_usage(EventKind::Launch, Some(app), res.is_ok());
+        }
+        // A deferred launch keeps its job, which is finished once the launch really runs
+        if !matches!(res, Err(Error::MountingDeferred)) {
+            jobs.lock().unwrap().finish(&job_id, &res);
+        }
+        // Nobody is listening if the job was started asynchronously
+        let _ = tx.send(res);
+    });
+    (job, rx)
+}
+
+/// Attaches the debugger to a process on a blocking thread, retrying a few times.
+pub fn attach(client: Client, pid: u16) -> (String, TaskResult) {
+    let jobs = client.shared.jobs.clone();
+    let job = jobs
+        .lock()
+        .unwrap()
+        .create(client.udid.clone(), JobKind::Attach, pid.to_string());
+
+    let (tx, rx) = oneshot::channel();
+    let job_id = job.clone();
+    tokio::task::spawn_blocking(move || {
+        jobs.lock().unwrap().start(&job_id);
+        let mut i = 5;
+        let res = loop {
+            match client.attach_debugger(pid) {
+                Ok(_) => break Ok(()),
+                Err(e) => {
+                    if i == 0 {
+                        break Err(e);
+                    }
+                    i -= 1;
+                }
+            }
+        };
+        client
+            .shared
+            .metrics
+            .request(Request::Attach, Outcome::of(&res));
+        client.record_usage(EventKind::Attach, None, res.is_ok());
+        jobs.lock().unwrap().finish(&job_id, &res);
+        let _ = tx.send(res);
+    });
+    (job, rx)
+}
+
+/// Installs an IPA from disk on a blocking thread, then removes the file.
+pub fn install(client: Client, path: PathBuf) -> (String, TaskResult) {
+    let jobs = client.shared.jobs.clone();
+    let job = jobs.lock().unwrap().create(
+        client.udid.clone(),
+        JobKind::Install,
+        path.to_string_lossy(),
+    );
+
+    let (tx, rx) = oneshot::channel();
+    let job_id = job.clone();
+    tokio::task::spawn_blocking(move || {
+        jobs.lock().unwrap().start(&job_id);
+        let res = client.install_app(&path);
+        client.record_usage(EventKind::Install, None, res.is_ok());
+        jobs.lock().unwrap().finish(&job_id, &res);
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("Unable to remove uploaded IPA {}: {}", path.display(), e);
+        }
+        let _ = tx.send(res);
+    });
+    (job, rx)
+}
@@ -0,0 +1,252 @@
+// jkcoxson
+
+use bytes::Buf;
+use futures::{Stream, TryStreamExt};
+use log::{info, warn};
+use rand::Rng;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use utoipa::IntoParams;
+
+use crate::{config::Config, error::Error};
+
+/// Tracks IPAs that are being streamed to disk before they are installed.
+pub struct Uploads {
+    pub upload_path: String,
+    /// The maximum IPA size in bytes
+    pub max_size: u64,
+    /// How long an unfinished session is kept, in seconds
+    session_lifetime: u64,
+    sessions: HashMap<String, UploadSession>,
+}
+
+#[derive(Clone, Debug)]
+pub struct UploadSession {
+    pub id: String,
+    /// The UDID of the device that created the session
+    pub udid: String,
+    pub path: PathBuf,
+    /// The size the client promised to send
+    pub size: u64,
+    /// The lowercase hex SHA-256 the file must match before it is installed
+    pub sha256: String,
+    /// How many bytes have been written so far
+    pub received: u64,
+    /// Set while a chunk is being written so two requests can't interleave
+    pub busy: bool,
+    pub created: u64,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct NewSessionQuery {
+    /// The size of the IPA in bytes
+    pub size: u64,
+    /// The hex SHA-256 of the IPA
+    pub sha256: String,
+}
+
+impl Uploads {
+    pub fn new(config: &Config) -> Uploads {
+        Uploads {
+            upload_path: config.install.upload_path.clone(),
+            max_size: config.install.max_ipa_size * 1024 * 1024,
+            session_lifetime: config.install.session_lifetime * 60 * 60,
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Generates a fresh path in the upload directory for an incoming IPA.
+    pub fn temp_path(&self, udid: &str) -> Result<PathBuf, Error> {
+        std::fs::create_dir_all(&self.upload_path)
+            .map_err(Error::io("Unable to create upload directory"))?;
+        let mut rng = rand::thread_rng();
+        let suffix: u64 = rng.gen();
+        Ok(Path::new(&self.upload_path).join(format!("{}-{:016x}.ipa", udid, suffix)))
+    }
+
+    pub fn new_session(
+        &mut self,
+        udid: String,
+        size: u64,
+        sha256: String,
+    ) -> Result<UploadSession, Error> {
+        self.expire_sessions();
+        if size > self.max_size {
+            return Err(Error::UploadTooLarge(self.max_size / 1024 / 1024));
+        }
+        let sha256 = parse_sha256(&sha256)?;
+
+        let path = self.temp_path(&udid)?;
+        let mut rng = rand::thread_rng();
+        let id = format!("{:032x}", rng.gen::<u128>());
+        let session = UploadSession {
+            id: id.clone(),
+            udid,
+            path,
+            size,
+            sha256,
+            received: 0,
+            busy: false,
+            created: now(),
+        };
+        info!("Created upload session {}", id);
+        self.sessions.insert(id, session.clone());
+        Ok(session)
+    }
+
+    pub fn get_session(&self, id: &str) -> Option<UploadSession> {
+        self.sessions.get(id).cloned()
+    }
+
+    /// Marks a session as busy so a chunk can be written to it.
+    /// Fails if the session doesn't exist, belongs to another device, or is already busy.
+    pub fn claim_session(&mut self, id: &str, udid: &str) -> Result<UploadSession, Error> {
+        let session = match self.sessions.get_mut(id) {
+            Some(s) if s.udid == udid => s,
+            _ => return Err(Error::UploadNotFound),
+        };
+        if session.busy {
+            return Err(Error::UploadBusy);
+        }
+        session.busy = true;
+        Ok(session.clone())
+    }
+
+    /// Releases a session claimed with `claim_session`, recording the new offset.
+    pub fn release_session(&mut self, id: &str, received: u64) {
+        if let Some(session) = self.sessions.get_mut(id) {
+            session.busy = false;
+            session.received = received;
+        }
+    }
+
+    pub fn remove_session(&mut self, id: &str) -> Option<UploadSession> {
+        self.sessions.remove(id)
+    }
+
+    /// Drops sessions that have outlived the configured lifetime, along with their files.
+    pub fn expire_sessions(&mut self) {
+        let cutoff = now().saturating_sub(self.session_lifetime);
+        self.sessions.retain(|id, session| {
+            if session.created >= cutoff || session.busy {
+                return true;
+            }
+            info!("Upload session {} expired", id);
+            let _ = std::fs::remove_file(&session.path);
+            false
+        });
+    }
+}
+
+impl Default for Uploads {
+    fn default() -> Self {
+        Uploads {
+            upload_path: "uploads".to_string(),
+            max_size: 4096 * 1024 * 1024,
+            session_lifetime: 24 * 60 * 60,
+            sessions: HashMap::new(),
+        }
+    }
+}
+
+/// Checks a hex SHA-256 sent by a client, returning it in lowercase.
+pub fn parse_sha256(sha256: &str) -> Result<String, Error> {
+    let sha256 = sha256.to_lowercase();
+    match sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => Ok(sha256),
+        false => Err(Error::InvalidHash),
+    }
+}
+
+/// Appends a request body to a file on disk without buffering it in memory.
+/// Returns the total size of the file after writing, or an error if it would grow past `max_size`.
+pub async fn write_stream<S, B>(
+    path: &Path,
+    offset: u64,
+    max_size: u64,
+    body: S,
+) -> Result<u64, Error>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    let mut file = match tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+    {
+        Ok(f) => f,
+        Err(e) => {
+            warn!("Unable to open {}: {}", path.display(), e);
+            return Err(Error::Io {
+                context: "Unable to create upload file",
+                cause: e,
+            });
+        }
+    };
+    let mut written = offset;
+    let mut body = Box::pin(body);
+    loop {
+        let mut buf = match body.try_next().await {
+            Ok(Some(buf)) => buf,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Error receiving upload: {}", e);
+                return Err(Error::UploadInterrupted);
+            }
+        };
+        while buf.has_remaining() {
+            let chunk = buf.chunk();
+            written += chunk.len() as u64;
+            if written > max_size {
+                return Err(Error::UploadTooLarge(max_size / 1024 / 1024));
+            }
+            file.write_all(chunk)
+                .await
+                .map_err(Error::io("Unable to write upload to disk"))?;
+            let n = chunk.len();
+            buf.advance(n);
+        }
+    }
+    file.flush()
+        .await
+        .map_err(Error::io("Unable to write upload to disk"))?;
+    Ok(written)
+}
+
+/// Computes the lowercase hex SHA-256 of a file on disk.
+pub async fn hash_file(path: &Path) -> Result<String, Error> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(Error::io("Unable to read upload"))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1024 * 1024];
+    loop {
+        match file.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(e) => {
+                return Err(Error::Io {
+                    context: "Unable to read upload",
+                    cause: e,
+                })
+            }
+        }
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,391 @@
+// jkcoxson
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::storage::write_atomic;
+
+const DAY: u64 = 24 * 60 * 60;
+const WEEK: u64 = 7 * DAY;
+/// How many days and weeks the census breaks usage down into.
+const DAILY_PERIODS: u64 = 7;
+const WEEKLY_PERIODS: u64 = 4;
+/// The census needs every event from the last four weeks.
+pub const MIN_RETENTION_DAYS: u64 = WEEKLY_PERIODS * 7;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Launch,
+    Attach,
+    Fetch,
+    Install,
+    Mount,
+}
+
+impl EventKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Launch => "launch",
+            EventKind::Attach => "attach",
+            EventKind::Fetch => "fetch",
+            EventKind::Install => "install",
+            EventKind::Mount => "mount",
+        }
+    }
+}
+
+/// Something a device did, kept in the usage history until it is rolled up.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct UsageEvent {
+    /// Seconds since the epoch
+    pub timestamp: u64,
+    pub kind: EventKind,
+    pub udid: String,
+    /// The app launched, when there was one
+    pub bundle_id: Option<String>,
+    pub success: bool,
+}
+
+/// Usage over a period of time. Failed attempts are counted as well as successful ones.
+#[derive(Serialize, Deserialize, ToSchema, Default, Debug)]
+pub struct UsageStats {
+    /// Seconds since the epoch, inclusive
+    pub start: u64,
+    /// Seconds since the epoch, exclusive
+    pub end: u64,
+    pub launches: usize,
+    pub attaches: usize,
+    pub fetches: usize,
+    pub installs: usize,
+    pub mounts: usize,
+    pub failures: usize,
+    /// How many different devices did anything
+    pub devices: usize,
+}
+
+/// The persistent part of the census.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct UsageSummary {
+    pub totals: UsageStats,
+    /// The last seven days in UTC, oldest first, ending with today
+    pub daily: Vec<UsageStats>,
+    /// The last four weeks starting on Monday in UTC, oldest first, ending with this week
+    pub weekly: Vec<UsageStats>,
+}
+
+#[derive(Deserialize, ToSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    /// `json` or `csv`, defaults to json
+    #[serde(default)]
+    pub format: ExportFormat,
+    /// Only events at or after this time, in seconds since the epoch
+    pub since: Option<u64>,
+    /// Only events before this time, in seconds since the epoch
+    pub until: Option<u64>,
+}
+
+/// Events too old to keep, added up. Saved beside the usage history.
+#[derive(Serialize, Deserialize, Default)]
+struct Rollup {
+    /// `end` is where the kept events start, older events in the history are already counted
+    stats: UsageStats,
+    /// Every device in the totals, so a device isn't counted again by the kept events
+    devices: BTreeSet<String>,
+}
+
+impl Rollup {
+    fn add(&mut self, events: &[UsageEvent], end: u64) {
+        if self.stats.start == 0 {
+            self.stats.start = events.first().map_or(end, |e| e.timestamp);
+        }
+        for event in events {
+            self.stats.count(event);
+            self.devices.insert(event.udid.clone());
+        }
+        self.stats.end = end;
+        self.stats.devices = self.devices.len();
+    }
+}
+
+impl UsageStats {
+    fn count(&mut self, event: &UsageEvent) {
+        match event.kind {
+            EventKind::Launch => self.launches += 1,
+            EventKind::Attach => self.attaches += 1,
+            EventKind::Fetch => self.fetches += 1,
+            EventKind::Install => self.installs += 1,
+            EventKind::Mount => self.mounts += 1,
+        }
+        if !event.success {
+            self.failures += 1;
+        }
+    }
+}
+
+/// The usage history, appended to a JSON lines file as events happen.
+/// Events older than the retention period are rolled up into totals, see `compact`.
+#[derive(Default)]
+pub struct Usage {
+    path: String,
+    events: Vec<UsageEvent>,
+    rollup: Rollup,
+    /// How long events are kept in seconds, 0 keeps them forever
+    retention: u64,
+}
+
+fn rollup_path(path: &str) -> String {
+    format!("{}.rollup.json", path)
+}
+
+impl Usage {
+    /// Reads the usage history, skipping any lines that can't be parsed.
+    pub fn load(path: &str, retention_days: u64) -> Usage {
+        let rollup: Rollup = match std::fs::read_to_string(rollup_path(path)) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(rollup) => rollup,
+                Err(e) => {
+                    warn!(
+                        "Unable to parse the usage totals, starting them again: {}",
+                        e
+                    );
+                    Rollup::default()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Rollup::default(),
+            Err(e) => {
+                warn!("Unable to read the usage totals: {}", e);
+                Rollup::default()
+            }
+        };
+        let mut events = vec![];
+        match std::fs::File::open(path) {
+            Ok(file) => {
+                for (i, line) in BufReader::new(file).lines().enumerate() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(e) => {
+                            warn!("Unable to read usage history: {}", e);
+                            break;
+                        }
+                    };
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<UsageEvent>(&line) {
+                        // Left by a compaction that didn't finish, they are in the totals
+                        Ok(event) if event.timestamp < rollup.stats.end => {}
+                        Ok(event) => events.push(event),
+                        Err(e) => warn!("Skipping line {} of {}: {}", i + 1, path, e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Unable to open usage history {}: {}", path, e),
+        }
+        let mut usage = Usage {
+            path: path.to_string(),
+            events,
+            rollup,
+            retention: retention_days * DAY,
+        };
+        usage.compact();
+        usage
+    }
+
+    /// Rolls events older than the retention period into the totals, and rewrites the history
+    /// without them. The totals are saved first and remember where they end, so if the
+    /// rewrite doesn't happen the old events are skipped when the history is loaded.
+    pub fn compact(&mut self) {
+        if self.retention == 0 {
+            return;
+        }
+        let now = now();
+        let cutoff = now.saturating_sub(self.retention);
+        let cutoff = cutoff - cutoff % DAY;
+        if !self.events.iter().any(|e| e.timestamp < cutoff) {
+            return;
+        }
+        let (old, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.events)
+            .into_iter()
+            .partition(|e| e.timestamp < cutoff);
+        self.events = kept;
+        self.rollup.add(&old, cutoff);
+
+        let rollup = serde_json::to_string(&self.rollup).unwrap();
+        if let Err(e) = write_atomic(rollup_path(&self.path), rollup.as_bytes()) {
+            warn!("Unable to save the usage totals: {}", e);
+            return;
+        }
+        let mut history = String::new();
+        for event in &self.events {
+            history.push_str(&serde_json::to_string(event).unwrap());
+            history.push('\n');
+        }
+        if let Err(e) = write_atomic(&self.path, history.as_bytes()) {
+            warn!("Unable to rewrite the usage history: {}", e);
+            return;
+        }
+        info!("Rolled {} usage events into the totals", old.len());
+    }
+
+    pub fn record(
+        &mut self,
+        kind: EventKind,
+        udid: &str,
+        bundle_id: Option<String>,
+        success: bool,
+    ) {
+        let event = UsageEvent {
+            timestamp: now(),
+            kind,
+            udid: udid.to_string(),
+            bundle_id,
+            success,
+        };
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", serde_json::to_string(&event).unwrap()));
+        if let Err(e) = res {
+            warn!("Unable to save usage event: {}", e);
+        }
+        self.events.push(event);
+    }
+
+    /// Events in `[since, until)`, oldest first.
+    pub fn between(&self, since: Option<u64>, until: Option<u64>) -> Vec<UsageEvent> {
+        self.events
+            .iter()
+            .filter(|e| since.is_none_or(|s| e.timestamp >= s))
+            .filter(|e| until.is_none_or(|u| e.timestamp < u))
+            .cloned()
+            .collect()
+    }
+
+    pub fn summary(&self) -> UsageSummary {
+        let now = now();
+        let today = now - now % DAY;
+        // The epoch was a Thursday, so weeks are shifted to start on Monday
+        let this_week = today - (today / DAY + 3) % 7 * DAY;
+
+        let mut totals = self.stats(
+            self.events.first().map(|e| e.timestamp).unwrap_or(now),
+            now + 1,
+        );
+        let rolled = &self.rollup.stats;
+        if rolled.end != 0 {
+            totals.start = rolled.start;
+            totals.launches += rolled.launches;
+            totals.attaches += rolled.attaches;
+            totals.fetches += rolled.fetches;
+            totals.installs += rolled.installs;
+            totals.mounts += rolled.mounts;
+            totals.failures += rolled.failures;
+            totals.devices = self
+                .rollup
+                .devices
+                .iter()
+                .map(String::as_str)
+                .chain(self.events.iter().map(|e| e.udid.as_str()))
+                .collect::<HashSet<_>>()
+                .len();
+        }
+        let daily = (0..DAILY_PERIODS)
+            .rev()
+            .map(|i| today - i * DAY)
+            .map(|start| self.stats(start, start + DAY))
+            .collect();
+        let weekly = (0..WEEKLY_PERIODS)
+            .rev()
+            .map(|i| this_week - i * WEEK)
+            .map(|start| self.stats(start, start + WEEK))
+            .collect();
+        UsageSummary {
+            totals,
+            daily,
+            weekly,
+        }
+    }
+
+    fn stats(&self, start: u64, end: u64) -> UsageStats {
+        let mut stats = UsageStats {
+            start,
+            end,
+            ..Default::default()
+        };
+        let mut devices = HashSet::new();
+        for event in self
+            .events
+            .iter()
+            .filter(|e| e.timestamp >= start && e.timestamp < end)
+        {
+            stats.count(event);
+            devices.insert(event.udid.as_str());
+        }
+        stats.devices = devices.len();
+        stats
+    }
+}
+
+/// Summarises the usage history for the census.
+/// Callers take the history out of the backend first, so the backend isn't held meanwhile.
+pub fn summarize(usage: &Arc<Mutex<Usage>>) -> UsageSummary {
+    match usage.lock() {
+        Ok(usage) => usage.summary(),
+        Err(_) => {
+            warn!("Mutex poisoned!!");
+            Usage::default().summary()
+        }
+    }
+}
+
+/// Renders events as CSV with a header row.
+pub fn to_csv(events: &[UsageEvent]) -> String {
+    let mut csv = String::from("timestamp,kind,udid,bundle_id,success\n");
+    for e in events {
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            e.timestamp,
+            e.kind.as_str(),
+            csv_field(&e.udid),
+            csv_field(e.bundle_id.as_deref().unwrap_or_default()),
+            e.success
+        ));
+    }
+    csv
+}
+
+/// Quotes a field if it could break the row apart.
+fn csv_field(s: &str) -> String {
+    if s.contains(|c| matches!(c, ',' | '"' | '\n' | '\r')) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,1196 @@
+// jkcoxson
+
+use bytes::Buf;
+use futures::Stream;
+use log::{info, warn};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::Mutex, time::timeout};
+use utoipa::ToSchema;
+use warp::{
+    filters::BoxedFilter,
+    http::StatusCode,
+    reply::{self, Response},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    backend::{Backend, DeviceId, Registration, UdidQuery},
+    client::Client,
+    error::{Error, ErrorCode},
+    jobs::{AsyncQuery, Job, JobListQuery, LaunchQuery},
+    packets::Version,
+    pairing_record::{self, PairingFileQuery},
+    tasks::{self, TaskResult},
+    uploads::{NewSessionQuery, UploadSession},
+    usage::{self, UsageSummary},
+    SHORTCUT_VERSION,
+};
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(error: Error) -> Self {
+        Self::new(error.code(), error.to_string())
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: ApiError,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusResponse {
+    pub valid_ip: bool,
+    pub registered: bool,
+    pub mounting: bool,
+    /// Why the last mount failed, only reported once
+    pub mount_error: Option<String>,
+    pub pending_launch: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AppsResponse {
+    pub apps: BTreeMap<String, String>,
+    pub preferred_apps: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct JobResponse {
+    pub job: Job,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct JobsResponse {
+    pub jobs: Vec<Job>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UnregisterResponse {
+    pub udid: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TokenResponse {
+    /// Send this in the x-jitstreamer-token header, it is only shown once
+    pub token: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PairCodeResponse {
+    pub code: u16,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationResponse {
+    pub udid: String,
+    /// Send this in the x-jitstreamer-token header, it is only shown once
+    pub token: String,
+    /// The IP the device was registered from before, if it moved
+    pub moved_from: Option<String>,
+}
+
+impl From<Registration> for RegistrationResponse {
+    fn from(registration: Registration) -> Self {
+        Self {
+            udid: registration.udid,
+            token: registration.token,
+            moved_from: registration.moved_from,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UploadSessionResponse {
+    pub id: String,
+    /// The size of the IPA in bytes
+    pub size: u64,
+    /// How many bytes the server has, resume uploading from here
+    pub received: u64,
+}
+
+impl From<UploadSession> for UploadSessionResponse {
+    fn from(session: UploadSession) -> Self {
+        Self {
+            id: session.id,
+            size: session.size,
+            received: session.received,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CensusResponse {
+    pub launched: usize,
+    pub attached: usize,
+    pub fetched: usize,
+    pub netmuxd: usize,
+    /// Seconds since the server started
+    pub uptime: u64,
+    pub clients: usize,
+    pub version: String,
+    /// Usage history that survives restarts, unlike the counters above
+    pub usage: UsageSummary,
+}
+
+pub(crate) type ApiResult<T> = Result<(StatusCode, T), ApiError>;
+
+pub(crate) fn reply<T: Serialize>(res: ApiResult<T>) -> Result<Response, Rejection> {
+    Ok(match res {
+        Ok((status, body)) => reply::with_status(reply::json(&body), status).into_response(),
+        Err(error) => {
+            let status = error.code.status();
+            reply::with_status(reply::json(&ErrorResponse { error }), status).into_response()
+        }
+    })
+}
+
+/// Builds every route under `/v2`.
+pub fn routes(backend: Arc<Mutex<Backend>>) -> BoxedFilter<(Response,)> {
+    let status_backend = backend.clone();
+    let status_route = warp::path!("v2" / "status")
+        .and(warp::get())
+        .and(warp::filters::addr::remote())
+        .and(crate::device_id())
+        .and_then(move |addr, device| status(addr, device, status_backend.clone()));
+
+    let apps_backend = backend.clone();
+    let apps_route = warp::path!("v2" / "apps")
+        .and(warp::get())
+        .and(warp::filters::addr::remote())
+        .and(crate::device_id())
+        .and_then(move |addr, device| list_apps(addr, device, apps_backend.clone()));
+
+    let launch_backend = backend.clone();
+    let launch_route = warp::path!("v2" / "launch" / String)
+        .and(warp::post())
+        .and(warp::filters::addr::remote())
+        .and(crate::device_id())
+        .and(warp::query::<LaunchQuery>())
+        .and_then(move |app, addr, device, query| {
+            launch(app, addr, device, query, launch_backend.clone())
+        });
+
+    let attach_backend = backend.clone();
+    let attach_route = warp::path!("v2" / "attach" / u16)
+        .and(warp::post())
+        .and(warp::filters::addr::remote())
+        .and(crate::device_id())
+        .and(warp::query::<AsyncQuery>())
+        .and_then(move |pid, addr, device, query| {
+            attach(pid, addr, device, query, attach_backend.clone())
+        });
+
+    let unregister_backend = backend.clone();
+    let unregister_route = warp::path!("v2" / "unregister")
+        .and(warp::post())
+        .and(warp::filters::addr::remote())
+        .and(crate::device_id())
+        .and_then(move |addr, device| unregister(addr, device, unregister_backend.clone()));
+
+    let token_backend = backend.clone();
+    let token_route = warp::path!("v2" / "token")
+        .and(warp::post())
+        .and(warp::filters::addr::remote())
+        .and(crate::device_id())
+        .and_then(move |addr, device| rotate_token(addr, device, token_backend.clone()));
+
+    let register_backend = backend.clone();
+    let register_route = warp::path!("v2" / "register")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 1024 * 10))
+        .and(warp::body::bytes())
+        .and(warp::query::<UdidQuery>())
+        .and(warp::filters::addr::remote())
+        .and_then(move |body, query, addr| register(body, query, addr, register_backend.clone()));
+
+    let potential_backend = backend.clone();
+    let potential_route = warp::path!("v2" / "potential")
+        .and(warp::get())
+        .and(warp::filters::addr::remote())
+        .and_then(move |addr| potential_pair(addr, potential_backend.clone()));
+
+    let follow_up_backend = backend.clone();
+    let follow_up_route = warp::path!("v2" / "potential" / u16)
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 1024 * 10))
+        .and(warp::body::bytes())
+        .and(warp::query::<UdidQuery>())
+        .and(warp::filters::addr::remote())
+        .and_then(move |code, body, query, addr| {
+            potential_follow_up(code, body, query, addr, follow_up_backend.clone())
+        });
+
+    let install_backend = backend.clone();
+    let install_route = warp::path!("v2" / "install" / "app")
+        .and(warp::post())
+        .and(warp::filters::addr::remote())
+        .and(crate::device_id())
+        .and(warp::header::optional::<String>("x-content-sha256"))
+        .and(warp::query::<AsyncQuery>())
+        .and(warp::body::stream())
+        .and_then(move |addr, device, sha256, query, body| {
+            install_app(addr, device, sha256, query, body, install_backend.clone())
+        });
+
+    let session_create_backend = backend.clone();
+    let session_create_route = warp::path!("v2" / "install" / "session")
+        .and(warp::post())
+        .and(warp::filters::addr::remote())
+        .and(crate::device_id())
+        .and(warp::query::<NewSessionQuery>())
+        .and_then(move |addr, device, query| {
+            create_session(addr, device, query, session_create_backend.clone())
+        });
+
+    let session_status_backend = backend.clone();
+    let session_status_route = warp::path!("v2" / "install" / "session" / String)
+        .and(warp::get())
+        .and(warp::filters::addr::remote())
+        .and(crate::device_id())
+        .and_then(move |id, addr, device| {
+            session_status(id, addr, device, session_status_backend.clone())
+        });
+
+    let session_upload_backend = backend.clone();
+    let session_upload_route = warp::path!("v2" / "install" / "session" / String)
+        .and(warp::put())
+        .and(warp::filters::addr::remote())
+        .and(crate::device_id())
+        .and(warp::header::<u64>("upload-offset"))
+        .and(warp::body::stream())
+        .and_then(move |id, addr, device, offset, body| {
+            upload_chunk(
+                id,
+                addr,
+                device,
+                offset,
+                body,
+                session_upload_backend.clone(),
+            )
+        });
+
+    let session_finish_backend = backend.clone();
+    let session_finish_route = warp::path!("v2" / "install" / "session" / String / "finish")
+        .and(warp::post())
+        .and(warp::filters::addr::remote())
+        .and(crate::device_id())
+        .and(warp::query::<AsyncQuery>())
+        .and_then(move |id, addr, device, query| {
+            finish_session(id, addr, device, query, session_finish_backend.clone())
+        });
+
+    let job_backend = backend.clone();
+    let job_route = warp::path!("v2" / "jobs" / String)
+        .and(warp::get())
+        .and_then(move |id| get_job(id, job_backend.clone()));
+
+    let jobs_backend = backend.clone();
+    let jobs_route = warp::path!("v2" / "jobs")
+        .and(warp::get())
+        .and(warp::filters::addr::remote())
+        .and(crate::device_id())
+        .and(warp::query::<JobListQuery>())
+        .and_then(move |addr, device, query| list_jobs(addr, device, query, jobs_backend.clone()));
+
+    let pairing_file_backend = backend.clone();
+    let pairing_file_route = warp::path!("v2" / "pairing_file")
+        .and(warp::get())
+        .and(warp::filters::addr::remote())
+        .and(crate::device_id())
+        .and(warp::query::<PairingFileQuery>())
+        .and_then(move |addr, device, query| {
+            export_pairing_file(addr, device, query, pairing_file_backend.clone())
+        });
+
+    let census_route = warp::path!("v2" / "census")
+        .and(warp::get())
+        .and_then(move || census(backend.clone()));
+
+    let version_route = warp::path!("v2" / "version")
+        .and(warp::get())
+        .and_then(version);
+
+    status_route
+        .or(apps_route)
+        .unify()
+        .or(launch_route)
+        .unify()
+        .or(attach_route)
+        .unify()
+        .or(unregister_route)
+        .unify()
+        .or(token_route)
+        .unify()
+        .or(register_route)
+        .unify()
+        .or(potential_route)
+        .unify()
+        .or(follow_up_route)
+        .unify()
+        .or(install_route)
+        .unify()
+        .or(session_create_route)
+        .unify()
+        .or(session_status_route)
+        .unify()
+        .or(session_upload_route)
+        .unify()
+        .or(session_finish_route)
+        .unify()
+        .or(job_route)
+        .unify()
+        .or(jobs_route)
+        .unify()
+        .or(census_route)
+        .unify()
+        .or(version_route)
+        .unify()
+        .or(pairing_file_route)
+        .unify()
+        .boxed()
+}
+
+/// Looks up the registered client making a request.
+fn find_client(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    backend: &mut Backend,
+) -> Result<Client, ApiError> {
+    backend.identify(addr, &device).map_err(ApiError::from)
+}
+
+/// Waits for a device operation, or hands back the queued job if the caller asked for async.
+/// The job is also handed back while it is still running, when a launch was deferred until
+/// the mount finishes or the device didn't answer within the limit, so it can be polled.
+async fn finish_task(
+    backend: &Arc<Mutex<Backend>>,
+    job: String,
+    rx: TaskResult,
+    run_async: bool,
+    limit: Option<std::time::Duration>,
+) -> ApiResult<JobResponse> {
+    let jobs = backend.lock().await.shared.jobs.clone();
+    let snapshot = |jobs: &Arc<std::sync::Mutex<crate::jobs::Jobs>>| {
+        jobs.lock()
+            .ok()
+            .and_then(|j| j.get(&job))
+            .ok_or_else(|| ApiError::new(ErrorCode::Internal, "Job disappeared"))
+    };
+    let accepted = || -> ApiResult<JobResponse> {
+        Ok((
+            StatusCode::ACCEPTED,
+            JobResponse {
+                job: snapshot(&jobs)?,
+            },
+        ))
+    };
+    if run_async {
+        return accepted();
+    }
+
+    let res = match limit {
+        Some(limit) => match timeout(limit, rx).await {
+            Ok(res) => res,
+            Err(_) => return accepted(),
+        },
+        None => rx.await,
+    };
+    match res {
+        Ok(Ok(_)) => Ok((
+            StatusCode::OK,
+            JobResponse {
+                job: snapshot(&jobs)?,
+            },
+        )),
+        Ok(Err(Error::MountingDeferred)) => accepted(),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(ApiError::new(
+            ErrorCode::Internal,
+            "The task stopped unexpectedly",
+        )),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v2/status",
+    operation_id = "v2_status",
+    tag = "v2",
+    security((), ("device_token" = [])),
+    params(UdidQuery),
+    responses(
+        (
+            status = 200,
+            description = "Registration and mount state of the calling device",
+            body = StatusResponse
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse),
+        (status = "5XX", description = "The device operation failed", body = ErrorResponse)
+    )
+)]
+async fn status(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    let mut lock = backend.lock().await;
+    let client = match find_client(addr, device, &mut lock) {
+        Ok(client) => client,
+        Err(e) if matches!(e.code, ErrorCode::NoAddress | ErrorCode::AmbiguousDevice) => {
+            return reply::<StatusResponse>(Err(e))
+        }
+        Err(e) => {
+            return reply(Ok((
+                StatusCode::OK,
+                StatusResponse {
+                    valid_ip: e.code != ErrorCode::IpNotAllowed,
+                    registered: false,
+                    mounting: false,
+                    mount_error: None,
+                    pending_launch: None,
+                },
+            )))
+        }
+    };
+    let status = match lock.shared.jobs.lock() {
+        Ok(mut jobs) => jobs.mount_status(&client.udid),
+        Err(_) => {
+            warn!("Mutex poisoned!!");
+            return reply::<StatusResponse>(Err(ApiError::new(
+                ErrorCode::Internal,
+                "Job state is unavailable",
+            )));
+        }
+    };
+    reply(Ok((
+        StatusCode::OK,
+        StatusResponse {
+            valid_ip: true,
+            registered: true,
+            mounting: status.mounting,
+            mount_error: if status.message.is_empty() {
+                None
+            } else {
+                Some(status.message)
+            },
+            pending_launch: status.pending_launch,
+        },
+    )))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v2/apps",
+    operation_id = "v2_list_apps",
+    tag = "v2",
+    security((), ("device_token" = [])),
+    params(UdidQuery),
+    responses(
+        (status = 200, description = "Apps installed on the calling device", body = AppsResponse),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse),
+        (status = "5XX", description = "The device operation failed", body = ErrorResponse)
+    )
+)]
+async fn list_apps(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    info!("Device list requested");
+    let mut lock = backend.lock().await;
+    let client = match find_client(addr, device, &mut lock) {
+        Ok(client) => client,
+        Err(e) => return reply::<AppsResponse>(Err(e)),
+    };
+    drop(lock);
+
+    let list = match tasks::get_apps(client).await {
+        Ok(list) => list,
+        Err(e) => return reply::<AppsResponse>(Err(e.into())),
+    };
+    backend.lock().await.counter.fetched += list.count;
+
+    reply(Ok((
+        StatusCode::OK,
+        AppsResponse {
+            apps: list.apps,
+            preferred_apps: list.preferred_apps,
+        },
+    )))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v2/launch/{bundle_id}",
+    operation_id = "v2_launch",
+    tag = "v2",
+    security((), ("device_token" = [])),
+    params(
+        ("bundle_id" = String, Path, description = "The bundle ID to launch"),
+        LaunchQuery,
+        UdidQuery
+    ),
+    responses(
+        (status = 200, description = "The finished launch job", body = JobResponse),
+        (
+            status = 202,
+            description = "The queued job, when async is set or the launch was deferred \
+                           until the developer disk image is mounted",
+            body = JobResponse
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse),
+        (status = "5XX", description = "The device operation failed", body = ErrorResponse)
+    )
+)]
+async fn launch(
+    app: String,
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    query: LaunchQuery,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    info!("Device has sent request to launch {}", app);
+    let mut lock = backend.lock().await;
+    let client = match find_client(addr, device, &mut lock) {
+        Ok(client) => client,
+        Err(e) => return reply::<JobResponse>(Err(e)),
+    };
+    lock.counter.launched += 1;
+    drop(lock);
+
+    let (job, rx) = tasks::launch(client, app, query.defer);
+    reply(finish_task(&backend, job, rx, query.run_async, None).await)
+}
+
+#[utoipa::path(
+    post,
+    path = "/v2/attach/{pid}",
+    operation_id = "v2_attach",
+    tag = "v2",
+    security((), ("device_token" = [])),
+    params(
+        ("pid" = u16, Path, description = "The process to attach to"),
+        AsyncQuery,
+        UdidQuery
+    ),
+    responses(
+        (status = 200, description = "The finished attach job", body = JobResponse),
+        (
+            status = 202,
+            description = "The queued job, when async is set or the device didn't answer in \
+                           time, poll it to see how the attach went",
+            body = JobResponse
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse),
+        (status = "5XX", description = "The device operation failed", body = ErrorResponse)
+    )
+)]
+async fn attach(
+    pid: u16,
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    query: AsyncQuery,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    info!("Device has sent request to attach to process {}", pid);
+    let mut lock = backend.lock().await;
+    let client = match find_client(addr, device, &mut lock) {
+        Ok(client) => client,
+        Err(e) => return reply::<JobResponse>(Err(e)),
+    };
+    lock.counter.attached += 1;
+    drop(lock);
+
+    let (job, rx) = tasks::attach(client, pid);
+    reply(
+        finish_task(
+            &backend,
+            job,
+            rx,
+            query.run_async,
+            Some(std::time::Duration::from_secs(60)),
+        )
+        .await,
+    )
+}
+
+#[utoipa::path(
+    post,
+    path = "/v2/unregister",
+    operation_id = "v2_unregister",
+    tag = "v2",
+    security((), ("device_token" = [])),
+    params(UdidQuery),
+    responses(
+        (status = 200, description = "The device that was removed", body = UnregisterResponse),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn unregister(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    info!("Device has sent request unregister");
+    let mut lock = backend.lock().await;
+    let client = match find_client(addr, device, &mut lock) {
+        Ok(client) => client,
+        Err(e) => return reply::<UnregisterResponse>(Err(e)),
+    };
+    match lock.unregister_client(&client.udid) {
+        Ok(_) => reply(Ok((
+            StatusCode::OK,
+            UnregisterResponse { udid: client.udid },
+        ))),
+        Err(e) => reply::<UnregisterResponse>(Err(e.into())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v2/pairing_file",
+    operation_id = "v2_export_pairing_file",
+    tag = "v2",
+    security(("device_token" = [])),
+    params(PairingFileQuery, UdidQuery),
+    responses(
+        (
+            status = 200,
+            description = "The device's pairing record, sent as an attachment",
+            content_type = "application/x-plist"
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse),
+        (status = "5XX", description = "The record could not be read", body = ErrorResponse)
+    )
+)]
+async fn export_pairing_file(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    query: PairingFileQuery,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    // The record is as good as the device's keys, sharing an IP isn't enough to get it
+    if device.token.is_none() {
+        return reply::<()>(Err(Error::TokenRequired.into()));
+    }
+    let mut lock = backend.lock().await;
+    let client = match find_client(addr, device, &mut lock) {
+        Ok(client) => client,
+        Err(e) => return reply::<()>(Err(e)),
+    };
+    let record = match lock.read_pairing_file(&client.udid) {
+        Ok(record) => record,
+        Err(e) => return reply::<()>(Err(e.into())),
+    };
+    let record = match pairing_record::export(&record, query.format) {
+        Ok(record) => record,
+        Err(e) => return reply::<()>(Err(Error::from(e).into())),
+    };
+    Ok(reply::with_header(
+        reply::with_header(record, "content-type", query.format.content_type()),
+        "content-disposition",
+        format!(
+            "attachment; filename=\"{}.{}\"",
+            client.udid,
+            query.format.extension()
+        ),
+    )
+    .into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/v2/token",
+    operation_id = "v2_rotate_token",
+    tag = "v2",
+    security((), ("device_token" = [])),
+    responses(
+        (
+            status = 200,
+            description = "A new token for the calling device, the old one stops working",
+            body = TokenResponse
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse),
+        (status = "5XX", description = "The token could not be saved", body = ErrorResponse)
+    )
+)]
+async fn rotate_token(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    info!("Device has sent request for a new token");
+    let mut lock = backend.lock().await;
+    let client = match find_client(addr, device, &mut lock) {
+        Ok(client) => client,
+        Err(e) => return reply::<TokenResponse>(Err(e)),
+    };
+    match lock.issue_token(&client.udid) {
+        Ok(token) => reply(Ok((StatusCode::OK, TokenResponse { token }))),
+        Err(e) => reply::<TokenResponse>(Err(e.into())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v2/potential",
+    operation_id = "v2_potential_pair",
+    tag = "v2",
+    responses(
+        (
+            status = 200,
+            description = "A code to pair this IP from another machine",
+            body = PairCodeResponse
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn potential_pair(
+    addr: Option<SocketAddr>,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    let mut lock = backend.lock().await;
+    let addr = match addr {
+        Some(addr) => addr,
+        None => {
+            return reply::<PairCodeResponse>(Err(ApiError::new(
+                ErrorCode::NoAddress,
+                "No address provided",
+            )))
+        }
+    };
+    if !lock.check_ip(addr.ip()) {
+        return reply::<PairCodeResponse>(Err(ApiError::new(
+            ErrorCode::IpNotAllowed,
+            "Invalid IP, join from the VLAN",
+        )));
+    }
+    let code = lock.pair_codes.issue(addr.to_string());
+    info!("A potential pair code was generated: {}", code);
+    reply(Ok((StatusCode::OK, PairCodeResponse { code })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v2/register",
+    operation_id = "v2_register",
+    tag = "v2",
+    params((
+        "udid" = Option<String>,
+        Query,
+        description = "The device's UDID, for pairing files that don't include it"
+    )),
+    request_body(
+        content = String,
+        content_type = "application/xml",
+        description = "The device's pairing file as an XML or binary plist"
+    ),
+    responses(
+        (
+            status = 200,
+            description = "The registered device and its token. A device that was already \
+                           registered gets a new token, and the old one stops working",
+            body = RegistrationResponse
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse),
+        (status = "5XX", description = "The device could not be reached", body = ErrorResponse)
+    )
+)]
+async fn register(
+    body: bytes::Bytes,
+    query: UdidQuery,
+    addr: Option<SocketAddr>,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    let ip = match addr {
+        Some(addr) => addr.ip().to_string(),
+        None => return reply::<RegistrationResponse>(Err(Error::NoAddress.into())),
+    };
+    match crate::register_device(&body, query.udid.as_deref(), ip, &backend).await {
+        Ok(registration) => reply(Ok((
+            StatusCode::OK,
+            RegistrationResponse::from(registration),
+        ))),
+        Err(e) => reply::<RegistrationResponse>(Err(e.into())),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v2/potential/{code}",
+    operation_id = "v2_potential_follow_up",
+    tag = "v2",
+    params(
+        ("code" = u16, Path, description = "The code returned by GET /v2/potential"),
+        (
+            "udid" = Option<String>,
+            Query,
+            description = "The device's UDID, for pairing files that don't include it"
+        )
+    ),
+    request_body(
+        content = String,
+        content_type = "application/xml",
+        description = "The device's pairing file as an XML or binary plist"
+    ),
+    responses(
+        (
+            status = 200,
+            description = "The registered device and its token",
+            body = RegistrationResponse
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse),
+        (status = "5XX", description = "The device could not be reached", body = ErrorResponse)
+    )
+)]
+async fn potential_follow_up(
+    code: u16,
+    body: bytes::Bytes,
+    query: UdidQuery,
+    addr: Option<SocketAddr>,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    let source = match addr {
+        Some(addr) => addr.ip().to_string(),
+        None => return reply::<RegistrationResponse>(Err(Error::NoAddress.into())),
+    };
+    // The code is taken while the pairing file is checked, so it can only be used once
+    let claimed = match backend.lock().await.pair_codes.claim(code, &source) {
+        Ok(claimed) => claimed,
+        Err(e) => return reply::<RegistrationResponse>(Err(e.into())),
+    };
+    let ip = claimed.ip.split(':').next().unwrap().to_string();
+
+    match crate::register_device(&body, query.udid.as_deref(), ip, &backend).await {
+        Ok(registration) => reply(Ok((
+            StatusCode::OK,
+            RegistrationResponse::from(registration),
+        ))),
+        Err(e) => {
+            backend.lock().await.pair_codes.release(claimed, &source);
+            reply::<RegistrationResponse>(Err(e.into()))
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v2/install/app",
+    operation_id = "v2_install_app",
+    tag = "v2",
+    security((), ("device_token" = [])),
+    params(
+        ("x-content-sha256" = String, Header, description = "The IPA's hex SHA-256"),
+        AsyncQuery,
+        UdidQuery
+    ),
+    request_body(
+        content = String,
+        content_type = "application/octet-stream",
+        description = "The IPA"
+    ),
+    responses(
+        (status = 200, description = "The finished install job", body = JobResponse),
+        (status = 202, description = "The queued job, when async is set", body = JobResponse),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse),
+        (status = "5XX", description = "The device operation failed", body = ErrorResponse)
+    )
+)]
+async fn install_app<S, B>(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    sha256: Option<String>,
+    query: AsyncQuery,
+    body: S,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send,
+    B: Buf + Send,
+{
+    info!("Device has sent request to install app");
+    let (client, path) = match crate::receive_ipa(addr, device, sha256, body, &backend).await {
+        Ok(upload) => upload,
+        Err(e) => return reply::<JobResponse>(Err(e.into())),
+    };
+    let (job, rx) = tasks::install(client, path);
+    reply(finish_task(&backend, job, rx, query.run_async, None).await)
+}
+
+#[utoipa::path(
+    post,
+    path = "/v2/install/session",
+    operation_id = "v2_create_upload_session",
+    tag = "v2",
+    security((), ("device_token" = [])),
+    params(NewSessionQuery, UdidQuery),
+    responses(
+        (status = 201, description = "A new resumable upload", body = UploadSessionResponse),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn create_session(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    query: NewSessionQuery,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    info!("Device has requested an upload session");
+    match crate::create_session(addr, device, query, &backend).await {
+        Ok(session) => reply(Ok((
+            StatusCode::CREATED,
+            UploadSessionResponse::from(session),
+        ))),
+        Err(e) => reply::<UploadSessionResponse>(Err(e.into())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v2/install/session/{id}",
+    operation_id = "v2_upload_session_status",
+    tag = "v2",
+    security((), ("device_token" = [])),
+    params(("id" = String, Path, description = "The upload session ID"), UdidQuery),
+    responses(
+        (
+            status = 200,
+            description = "How much of the upload has arrived",
+            body = UploadSessionResponse
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn session_status(
+    id: String,
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    match crate::session_status(&id, addr, device, &backend).await {
+        Ok(session) => reply(Ok((StatusCode::OK, UploadSessionResponse::from(session)))),
+        Err(e) => reply::<UploadSessionResponse>(Err(e.into())),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/v2/install/session/{id}",
+    operation_id = "v2_upload_chunk",
+    tag = "v2",
+    security((), ("device_token" = [])),
+    params(
+        ("id" = String, Path, description = "The upload session ID"),
+        ("upload-offset" = u64, Header, description = "Where this chunk starts"),
+        UdidQuery
+    ),
+    request_body(
+        content = String,
+        content_type = "application/octet-stream",
+        description = "The next chunk of the IPA"
+    ),
+    responses(
+        (
+            status = 200,
+            description = "How much of the upload has arrived",
+            body = UploadSessionResponse
+        ),
+        (
+            status = "4XX",
+            description = "The chunk was rejected, check the session for where to resume",
+            body = ErrorResponse
+        ),
+        (status = "5XX", description = "The chunk could not be saved", body = ErrorResponse)
+    )
+)]
+async fn upload_chunk<S, B>(
+    id: String,
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    offset: u64,
+    body: S,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send,
+    B: Buf + Send,
+{
+    if let (_, Err(e)) = crate::upload_chunk(&id, addr, device, offset, body, &backend).await {
+        return reply::<UploadSessionResponse>(Err(e.into()));
+    }
+    match backend.lock().await.uploads.get_session(&id) {
+        Some(session) => reply(Ok((StatusCode::OK, UploadSessionResponse::from(session)))),
+        None => reply::<UploadSessionResponse>(Err(Error::UploadNotFound.into())),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v2/install/session/{id}/finish",
+    operation_id = "v2_finish_upload_session",
+    tag = "v2",
+    security((), ("device_token" = [])),
+    params(
+        ("id" = String, Path, description = "The upload session ID"),
+        AsyncQuery,
+        UdidQuery
+    ),
+    responses(
+        (status = 200, description = "The finished install job", body = JobResponse),
+        (status = 202, description = "The queued job, when async is set", body = JobResponse),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse),
+        (status = "5XX", description = "The device operation failed", body = ErrorResponse)
+    )
+)]
+async fn finish_session(
+    id: String,
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    query: AsyncQuery,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    info!("Device has finished upload session {}", id);
+    let (client, path) = match crate::finish_session(&id, addr, device, &backend).await {
+        Ok(upload) => upload,
+        Err(e) => return reply::<JobResponse>(Err(e.into())),
+    };
+    let (job, rx) = tasks::install(client, path);
+    reply(finish_task(&backend, job, rx, query.run_async, None).await)
+}
+
+#[utoipa::path(
+    get,
+    path = "/v2/jobs/{id}",
+    operation_id = "v2_get_job",
+    tag = "v2",
+    params(("id" = String, Path, description = "The job ID")),
+    responses(
+        (status = 200, description = "The job's current state", body = JobResponse),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn get_job(id: String, backend: Arc<Mutex<Backend>>) -> Result<Response, Rejection> {
+    let jobs = backend.lock().await.shared.jobs.clone();
+    let job = jobs.lock().ok().and_then(|j| j.get(&id));
+    match job {
+        Some(job) => reply(Ok((StatusCode::OK, JobResponse { job }))),
+        None => reply::<JobResponse>(Err(ApiError::new(ErrorCode::JobNotFound, "Job not found"))),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v2/jobs",
+    operation_id = "v2_list_jobs",
+    tag = "v2",
+    security((), ("device_token" = [])),
+    params(JobListQuery),
+    responses(
+        (
+            status = 200,
+            description = "Jobs for the calling device, newest first",
+            body = JobsResponse
+        ),
+        (status = "4XX", description = "The request was rejected", body = ErrorResponse)
+    )
+)]
+async fn list_jobs(
+    addr: Option<SocketAddr>,
+    device: DeviceId,
+    query: JobListQuery,
+    backend: Arc<Mutex<Backend>>,
+) -> Result<Response, Rejection> {
+    let mut lock = backend.lock().await;
+    let client = match find_client(addr, device, &mut lock) {
+        Ok(client) => client,
+        Err(e) => return reply::<JobsResponse>(Err(e)),
+    };
+    if let Some(udid) = query.udid {
+        if udid != client.udid {
+            return reply::<JobsResponse>(Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "You can only list jobs for your own device",
+            )));
+        }
+    }
+    let jobs = match lock.shared.jobs.lock() {
+        Ok(jobs) => jobs.by_udid(&client.udid),
+        Err(_) => vec![],
+    };
+    reply(Ok((StatusCode::OK, JobsResponse { jobs })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v2/census",
+    operation_id = "v2_census",
+    tag = "v2",
+    responses(
+        (status = 200, description = "Usage counters since startup", body = CensusResponse)
+    )
+)]
+async fn census(backend: Arc<Mutex<Backend>>) -> Result<Response, Rejection> {
+    let lock = backend.lock().await;
+    let counter = lock.counter.clone();
+    let clients = lock.deserialized_clients.len();
+    let usage = lock.shared.usage.clone();
+    drop(lock);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    reply(Ok((
+        StatusCode::OK,
+        CensusResponse {
+            launched: counter.launched,
+            attached: counter.attached,
+            fetched: counter.fetched,
+            netmuxd: counter.netmuxd,
+            uptime: now - counter.uptime.as_secs(),
+            clients,
+            version: SHORTCUT_VERSION.to_string(),
+            usage: usage::summarize(&usage),
+        },
+    )))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v2/version",
+    operation_id = "v2_version",
+    tag = "v2",
+    responses(
+        (status = 200, description = "The shortcut version this server expects", body = Version)
+    )
+)]
+async fn version() -> Result<Response, Rejection> {
+    reply(Ok((
+        StatusCode::OK,
+        Version {
+            version: SHORTCUT_VERSION.to_string(),
+        },
+    )))
+}